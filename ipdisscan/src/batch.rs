@@ -0,0 +1,233 @@
+use crate::beacons::{insert_beacon, BeaconAnswer, BeaconAnswers};
+use crate::conf::{BatchConfig, OutputFormat};
+use color_eyre::eyre::Report;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use ipdisserver::answers::{BeaconInfos, KEY_SEPARATOR};
+use ipdisserver::net::{format_scoped_ip, interface_addrs, InterfaceAddr};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::time::Instant;
+use tracing::{debug, instrument, trace};
+
+const ADDR_COLUMN: &str = "addr";
+
+/// Collect beacon answers until the timeout expires, writing them to `out` in the configured
+/// format. NDJSON records are written as soon as a new beacon answers.
+#[instrument(skip(out))]
+pub fn run<W>(
+    channel_receiving_end: Receiver<BeaconAnswer>,
+    conf: &BatchConfig,
+    out: &mut W,
+) -> Result<BeaconAnswers, Report>
+where
+    W: Write,
+{
+    let deadline = Instant::now() + conf.timeout;
+    let local = interface_addrs().unwrap_or_default();
+    let mut beacons = BeaconAnswers::new();
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let beacon = match channel_receiving_end.recv_timeout(timeout) {
+            Ok(b) => b,
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        trace!(?beacon, "Beacon answer collected.");
+        if !beacons.contains_key(&beacon.key()) && conf.format == OutputFormat::Ndjson {
+            writeln!(out, "{}", to_record(&beacon, &local))?;
+            out.flush()?;
+        }
+        insert_beacon(&mut beacons, beacon);
+    }
+    debug!(found = beacons.len(), "Scan completed.");
+    let mut sorted: Vec<&BeaconAnswer> = beacons.values().collect();
+    sorted.sort_by_key(|b| b.addr);
+    match conf.format {
+        OutputFormat::Json => {
+            let records: Vec<Value> = sorted.iter().map(|b| to_record(b, &local)).collect();
+            writeln!(out, "{}", serde_json::to_string_pretty(&records)?)?;
+        }
+        OutputFormat::Ndjson => (),
+        OutputFormat::Csv => write_csv(&sorted, &conf.columns, out)?,
+    };
+    Ok(beacons)
+}
+
+/// `local` interfaces are used to rank the addresses the beacon is reachable at.
+fn to_record(beacon: &BeaconAnswer, local: &[InterfaceAddr]) -> Value {
+    let rtt_ms = beacon.rtt.map(|rtt| rtt.as_micros() as f64 / 1000.0);
+    let addrs: Vec<String> = beacon
+        .seen_addrs
+        .iter()
+        .map(|(addr, _)| format_scoped_ip(addr))
+        .collect();
+    json!({
+        ADDR_COLUMN: beacon.host(),
+        "addrs": addrs,
+        "interface": beacon.interface,
+        "reachable_at": beacon.reachable_at(local),
+        "rtt_ms": rtt_ms,
+        "answer": beacon.infos()
+    })
+}
+
+fn write_csv<W>(beacons: &[&BeaconAnswer], columns: &[String], out: &mut W) -> Result<(), Report>
+where
+    W: Write,
+{
+    let infos: Vec<BeaconInfos> = beacons.iter().map(|b| b.infos()).collect();
+    let columns: Vec<String> = match columns.is_empty() {
+        false => columns.to_vec(),
+        true => {
+            let mut keys = BTreeSet::new();
+            for info in &infos {
+                collect_columns(info, "", &mut keys);
+            }
+            std::iter::once(ADDR_COLUMN.to_string())
+                .chain(keys)
+                .collect()
+        }
+    };
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(&columns)?;
+    for (beacon, info) in beacons.iter().zip(infos.iter()) {
+        let record = columns.iter().map(|column| match column.as_str() {
+            ADDR_COLUMN => beacon.host(),
+            key => format_csv_value(lookup(info, key)),
+        });
+        writer.write_record(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Nested objects are flattened in dotted columns, e.g. `net.eth0.ipv4`.
+fn collect_columns(info: &BeaconInfos, prefix: &str, columns: &mut BTreeSet<String>) {
+    for (key, value) in info {
+        let column = format!("{}{}", prefix, key);
+        match value {
+            Value::Object(object) if !object.is_empty() => {
+                collect_columns(object, &format!("{}{}", column, KEY_SEPARATOR), columns)
+            }
+            _ => {
+                columns.insert(column);
+            }
+        }
+    }
+}
+
+/// The value of a column: the key itself, or the path of a nested value.
+fn lookup<'a>(info: &'a BeaconInfos, column: &str) -> Option<&'a Value> {
+    if let Some(value) = info.get(column) {
+        return Some(value);
+    }
+    let (key, rest) = column.split_once(KEY_SEPARATOR)?;
+    match info.get(key)? {
+        Value::Object(object) => lookup(object, rest),
+        _ => None,
+    }
+}
+
+/// Strings are written as they are, other values as JSON, missing values as empty fields.
+fn format_csv_value(value: Option<&Value>) -> String {
+    match value {
+        None => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::beacons::init_input_channel;
+    use ipdisserver::answers::Answer;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    fn beacon(last_byte: u8, payload: &str) -> BeaconAnswer {
+        BeaconAnswer::new(
+            SocketAddr::from((Ipv4Addr::new(192, 168, 0, last_byte), 1901)),
+            Answer::from(payload.to_string()),
+        )
+    }
+
+    fn run_with(format: OutputFormat, columns: Vec<String>) -> (BeaconAnswers, String) {
+        let (sender, receiver) = init_input_channel();
+        sender
+            .send(beacon(2, r#"{"hostname":"two","ip":["a","b"]}"#))
+            .unwrap();
+        sender.send(beacon(1, r#"{"hostname":"one"}"#)).unwrap();
+        sender.send(beacon(1, r#"{"hostname":"one"}"#)).unwrap();
+        let conf = BatchConfig {
+            timeout: Duration::from_millis(100),
+            format,
+            columns,
+            ..BatchConfig::default()
+        };
+        let mut out = Vec::new();
+        let beacons = run(receiver, &conf, &mut out).unwrap();
+        (beacons, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_batch_json() {
+        let (beacons, out) = run_with(OutputFormat::Json, Vec::new());
+        assert_eq!(beacons.len(), 2);
+        let records: Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(records[0]["addr"], "192.168.0.1");
+        assert_eq!(records[1]["answer"]["ip"][1], "b");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_batch_ndjson() {
+        let (_, out) = run_with(OutputFormat::Ndjson, Vec::new());
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2); // one record per host, in arrival order
+        assert_eq!(
+            lines[0],
+            r#"{"addr":"192.168.0.2","addrs":["192.168.0.2"],"answer":{"hostname":"two","ip":["a","b"]},"interface":null,"reachable_at":["192.168.0.2"],"rtt_ms":null}"#
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_batch_csv() {
+        let (_, out) = run_with(OutputFormat::Csv, Vec::new());
+        assert_eq!(
+            out,
+            "addr,hostname,ip\n192.168.0.1,one,\n192.168.0.2,two,\"[\"\"a\"\",\"\"b\"\"]\"\n"
+        );
+        let (_, out) = run_with(
+            OutputFormat::Csv,
+            vec!["hostname".to_string(), "addr".to_string()],
+        );
+        assert_eq!(out, "hostname,addr\none,192.168.0.1\ntwo,192.168.0.2\n");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_batch_csv_nested() {
+        let (sender, receiver) = init_input_channel();
+        sender
+            .send(beacon(
+                1,
+                r#"{"net":{"eth0":{"up":true}},"os.id":"debian"}"#,
+            ))
+            .unwrap();
+        let conf = BatchConfig {
+            timeout: Duration::from_millis(50),
+            format: OutputFormat::Csv,
+            ..BatchConfig::default()
+        };
+        let mut out = Vec::new();
+        run(receiver, &conf, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "addr,net.eth0.up,os.id\n192.168.0.1,true,debian\n"
+        );
+    }
+}
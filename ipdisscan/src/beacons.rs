@@ -1,34 +1,263 @@
+use crate::conf::ScannerConfig;
 use color_eyre::eyre::Report;
 use crossbeam::channel::{unbounded, Receiver, Sender};
-use ipdisserver::answers::Answer;
+use ipdisserver::addresses::ADDRESSES_KEY;
+use ipdisserver::answers::{Answer, BeaconInfos, FALLBACK_INFO_KEY};
+use ipdisserver::identity::DEVICE_ID_KEY;
+use ipdisserver::net::{format_scoped_ip, InterfaceAddr};
+use ipdisserver::server::RECEIVED_ON_KEY;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::fmt;
-use std::net::IpAddr;
-use tracing::{instrument, trace};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument, trace};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct BeaconAnswer {
-    pub addr: IpAddr,
+    /// Source of the answer, IPv6 addresses keep the scope (interface) they were received on.
+    pub addr: SocketAddr,
     pub payload: Answer,
+    pub authenticity: Authenticity,
+    pub encryption: Encryption,
+    /// Time between the request and the answer, unknown for legacy answers.
+    pub rtt: Option<Duration>,
+    /// Local interface the answer was received on, if known.
+    pub interface: Option<String>,
+    /// First answer from this device.
+    pub first_seen: Instant,
+    /// Last answer from this device.
+    pub last_seen: Instant,
+    pub state: BeaconState,
+    /// Addresses the device answered from, with the time of their last answer, in order of
+    /// appearance. `addr` is the address of the last answer.
+    pub seen_addrs: Vec<(SocketAddr, Instant)>,
+    /// Set if the device answers from a new address, while its previous address of the same
+    /// family stopped answering (e.g. after a DHCP renewal).
+    pub previous_addr: Option<SocketAddr>,
+}
+
+/// Beacons are identified by the device ID sent by the server, so that a device keeps its entry
+/// when its IP changes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum BeaconKey {
+    Device(String),
+    /// Servers not sending a device ID, or whose answer cannot be read or trusted, are identified
+    /// by address.
+    Addr(SocketAddr),
+}
+
+/// Result of the answer authentication, when a shared key is configured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Authenticity {
+    /// No shared key configured, answers are not checked.
+    NotChecked,
+    Verified,
+    /// The answer is not authenticated or its HMAC does not match the shared key.
+    Unverified,
+}
+
+/// Encryption state of the answer payload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encryption {
+    Plain,
+    Decrypted,
+    /// Encrypted, but not for the configured private key (if any).
+    NoKey,
+}
+
+/// Whether the beacon is still answering, according to the time since its last answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BeaconState {
+    Online,
+    /// Missed a few scans.
+    Stale,
+    /// Not answering for a long time, e.g. unplugged.
+    Lost,
+}
+
+impl fmt::Display for BeaconState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Online => "online",
+            Self::Stale => "stale",
+            Self::Lost => "lost",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Time since the last answer after which a beacon changes state. When sweeping, the sweep
+/// duration is added to the configured scan periods, swept hosts being sent a request once per
+/// sweep.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StateThresholds {
+    pub stale: Duration,
+    pub lost: Duration,
+    /// Lost beacons are removed after this long, if set.
+    pub forget: Option<Duration>,
+}
+
+impl From<&ScannerConfig> for StateThresholds {
+    fn from(conf: &ScannerConfig) -> Self {
+        let sweep = conf.sweep_duration();
+        let periods = |n: f64| Duration::from_secs_f64(n * conf.scan_period) + sweep;
+        Self {
+            stale: periods(conf.stale_after),
+            lost: periods(conf.lost_after),
+            forget: conf.forget_after.map(periods),
+        }
+    }
+}
+
+impl Default for StateThresholds {
+    fn default() -> Self {
+        Self::from(&ScannerConfig::default())
+    }
+}
+
+impl BeaconAnswer {
+    pub fn new(addr: SocketAddr, payload: Answer) -> Self {
+        let now = Instant::now();
+        Self {
+            addr,
+            payload,
+            authenticity: Authenticity::NotChecked,
+            encryption: Encryption::Plain,
+            rtt: None,
+            interface: None,
+            first_seen: now,
+            last_seen: now,
+            state: BeaconState::Online,
+            seen_addrs: vec![(addr, now)],
+            previous_addr: None,
+        }
+    }
+
+    /// The device ID, unless the answer is not authentic: anyone could claim it.
+    pub fn key(&self) -> BeaconKey {
+        if self.authenticity == Authenticity::Unverified {
+            return BeaconKey::Addr(self.addr);
+        }
+        match self.infos().get(DEVICE_ID_KEY) {
+            Some(Value::String(id)) if !id.is_empty() => BeaconKey::Device(id.clone()),
+            _ => BeaconKey::Addr(self.addr),
+        }
+    }
+
+    /// Time since the last answer.
+    pub fn age(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_seen)
+    }
+
+    fn update_state(&mut self, now: Instant, thresholds: &StateThresholds) {
+        let age = self.age(now);
+        self.state = match age {
+            a if a >= thresholds.lost => BeaconState::Lost,
+            a if a >= thresholds.stale => BeaconState::Stale,
+            _ => BeaconState::Online,
+        };
+        self.previous_addr = self
+            .seen_addrs
+            .iter()
+            .filter(|(addr, last_seen)| {
+                *addr != self.addr
+                    && addr.is_ipv4() == self.addr.is_ipv4()
+                    && now.saturating_duration_since(*last_seen) >= thresholds.stale
+            })
+            .max_by_key(|(_, last_seen)| *last_seen)
+            .map(|(addr, _)| *addr);
+    }
+
+    /// Informations contained in the answer, a placeholder if they cannot be decrypted.
+    pub fn infos(&self) -> BeaconInfos {
+        match self.encryption {
+            Encryption::NoKey => {
+                let mut info = BeaconInfos::new();
+                info.insert(FALLBACK_INFO_KEY.into(), "encrypted, no key".into());
+                info
+            }
+            _ => self.payload.infos(),
+        }
+    }
+
+    /// IP of the beacon, with the scope for IPv6 link-local addresses.
+    pub fn host(&self) -> String {
+        format_scoped_ip(&self.addr)
+    }
+
+    /// Addresses the device can be connected to (e.g. with SSH), best first: the address the
+    /// request was received on, the source of the answer, the server addresses in a subnet of a
+    /// `local` interface, then the other IPv4, IPv6 and IPv6 link-local addresses. Link-local
+    /// addresses are scoped to the interface the answer was received on, e.g. `fe80::1%eth0`.
+    pub fn reachable_at(&self, local: &[InterfaceAddr]) -> Vec<String> {
+        let infos = self.infos();
+        let received_on = infos
+            .get(RECEIVED_ON_KEY)
+            .and_then(|r| r.get("addr"))
+            .and_then(Value::as_str)
+            .and_then(|addr| addr.parse::<IpAddr>().ok());
+        let listed = infos
+            .get(ADDRESSES_KEY)
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|a| a.get("addr")?.as_str()?.parse::<IpAddr>().ok());
+        let mut candidates: Vec<IpAddr> = Vec::new();
+        for ip in received_on
+            .into_iter()
+            .chain([self.addr.ip()])
+            .chain(listed)
+        {
+            if !candidates.contains(&ip) {
+                candidates.push(ip);
+            }
+        }
+        let rank = |ip: &IpAddr| match ip {
+            ip if Some(*ip) == received_on => 0,
+            ip if *ip == self.addr.ip() => 1,
+            ip if local.iter().any(|i| i.contains(ip)) => 2,
+            IpAddr::V4(_) => 3,
+            IpAddr::V6(ip) if !is_link_local_v6(ip) => 4,
+            IpAddr::V6(_) => 5,
+        };
+        candidates.sort_by_key(rank); // stable, keeps the server order
+        candidates
+            .into_iter()
+            .map(|ip| match (ip, &self.interface) {
+                (IpAddr::V6(v6), Some(interface)) if is_link_local_v6(&v6) => {
+                    format!("{}%{}", v6, interface)
+                }
+                _ => ip.to_string(),
+            })
+            .collect()
+    }
 }
 
 impl fmt::Display for BeaconAnswer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}: {}", self.addr, self.payload)
+        write!(f, "{}: {}", self.host(), self.payload)
     }
 }
 
-type BeaconAnswers = HashMap<IpAddr, BeaconAnswer>;
+pub type BeaconAnswers = HashMap<BeaconKey, BeaconAnswer>;
+
+/// fe80::/10
+fn is_link_local_v6(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
 
 #[instrument]
 pub fn run(
     channel_receiving_end: Receiver<BeaconAnswer>,
     output_channel_send_end: Sender<Vec<BeaconAnswer>>,
+    thresholds: StateThresholds,
 ) -> Result<(), Report> {
     let mut servers = BeaconAnswers::new();
     trace!("Starting server answers update loop.");
     loop {
         servers = beacons_update(servers, channel_receiving_end.clone())?;
+        update_states(&mut servers, Instant::now(), &thresholds);
         output_channel_send_end.try_send(servers.values().map(|x| x.to_owned()).collect())?;
     }
 }
@@ -52,35 +281,76 @@ fn beacons_update(
             _ => return Ok(beacons),
         };
         trace!(?beacon, "Updating beacons.");
-        beacons.insert(beacon.addr, beacon);
+        insert_beacon(&mut beacons, beacon);
+    }
+}
+
+/// Insert the beacon, replacing the previous answer of the same device. The first seen time and
+/// the addresses of the previous answers are kept.
+pub fn insert_beacon(beacons: &mut BeaconAnswers, mut beacon: BeaconAnswer) {
+    let key = beacon.key();
+    if let Some(previous) = beacons.remove(&key) {
+        beacon.first_seen = previous.first_seen;
+        beacon.previous_addr = previous.previous_addr;
+        beacon.seen_addrs = previous.seen_addrs;
+        match beacon
+            .seen_addrs
+            .iter_mut()
+            .find(|(a, _)| *a == beacon.addr)
+        {
+            Some((_, last_seen)) => *last_seen = beacon.last_seen,
+            None => {
+                info!(?key, addr = %beacon.addr, "Device answering from a new address.");
+                beacon.seen_addrs.push((beacon.addr, beacon.last_seen));
+            }
+        }
+    }
+    beacons.insert(key, beacon);
+}
+
+/// Update the beacon states, removing the ones lost for longer than the forget threshold.
+fn update_states(beacons: &mut BeaconAnswers, now: Instant, thresholds: &StateThresholds) {
+    for beacon in beacons.values_mut() {
+        let (previous_state, previous_addr) = (beacon.state, beacon.previous_addr);
+        beacon.update_state(now, thresholds);
+        if beacon.state != previous_state {
+            debug!(addr = %beacon.addr, state = %beacon.state, "Beacon state changed.");
+        }
+        if beacon.previous_addr.is_some() && beacon.previous_addr != previous_addr {
+            info!(addr = %beacon.addr, previous_addr = ?beacon.previous_addr, "Device address changed.");
+        }
+    }
+    if let Some(forget) = thresholds.forget {
+        beacons.retain(|_, beacon| beacon.age(now) < forget);
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::net::Ipv4Addr;
+    use crate::conf::SweepConfig;
+    use std::net::{Ipv4Addr, SocketAddrV6};
 
     #[test]
     #[tracing_test::traced_test]
     fn test_beacons_update() {
         let (sender, receiver) = init_input_channel();
-        let answer1 = BeaconAnswer {
-            addr: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
-            payload: Answer::default(),
-        };
-        let answer1_new = BeaconAnswer {
-            addr: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
-            payload: Answer::default(),
-        };
-        let answer2 = BeaconAnswer {
-            addr: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
-            payload: Answer::default(),
-        };
-        let answer2_new = BeaconAnswer {
-            addr: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
-            payload: Answer::default(),
-        };
+        let answer1 = BeaconAnswer::new(
+            SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 1901)),
+            Answer::default(),
+        );
+        let answer1_new = BeaconAnswer::new(
+            SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 1901)),
+            Answer::default(),
+        );
+        let answer2 = BeaconAnswer::new(
+            SocketAddr::from((Ipv4Addr::new(192, 168, 0, 2), 1901)),
+            Answer::default(),
+        );
+        let answer2_new = BeaconAnswer::new(
+            SocketAddr::from((Ipv4Addr::new(192, 168, 0, 2), 1901)),
+            Answer::default(),
+        );
         sender.send(answer2.clone()).unwrap();
         sender.send(answer1.clone()).unwrap();
         sender.send(answer1_new.clone()).unwrap();
@@ -88,23 +358,177 @@ mod test {
         let mut beacons = BeaconAnswers::new();
         beacons = beacons_update(beacons, receiver).unwrap();
         assert_eq!(
-            beacons.get(&answer1.addr).unwrap().payload,
+            beacons.get(&answer1.key()).unwrap().payload,
             answer1_new.payload
         );
         assert_eq!(
-            beacons.get(&answer2.addr).unwrap().payload,
+            beacons.get(&answer2.key()).unwrap().payload,
             answer2_new.payload
         );
     }
 
     #[test]
     #[tracing_test::traced_test]
-    fn test_put_in_queue() {
+    fn test_beacons_update_scoped() {
         let (sender, receiver) = init_input_channel();
-        let an_answer = BeaconAnswer {
-            addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            payload: Answer::default(),
+        let link_local = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+        let answer_if1 = BeaconAnswer::new(
+            SocketAddr::V6(SocketAddrV6::new(link_local, 1901, 0, 1)),
+            Answer::default(),
+        );
+        let answer_if2 = BeaconAnswer::new(
+            SocketAddr::V6(SocketAddrV6::new(link_local, 1901, 0, 2)),
+            Answer::default(),
+        );
+        sender.send(answer_if1.clone()).unwrap();
+        sender.send(answer_if2.clone()).unwrap();
+        let beacons = beacons_update(BeaconAnswers::new(), receiver).unwrap();
+        assert_eq!(beacons.len(), 2);
+        assert_eq!(beacons.get(&answer_if2.key()).unwrap().host(), "fe80::1%2");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_beacon_states() {
+        let (sender, receiver) = init_input_channel();
+        let addr = SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 1901));
+        let first = BeaconAnswer::new(addr, Answer::default());
+        sender.send(first.clone()).unwrap();
+        sender
+            .send(BeaconAnswer::new(addr, Answer::default()))
+            .unwrap();
+        let mut beacons = beacons_update(BeaconAnswers::new(), receiver).unwrap();
+        let key = first.key();
+        let last_seen = beacons[&key].last_seen;
+        assert_eq!(beacons[&key].first_seen, first.first_seen);
+        let thresholds = StateThresholds {
+            stale: Duration::from_secs(3),
+            lost: Duration::from_secs(10),
+            forget: Some(Duration::from_secs(60)),
+        };
+        let state_after = |beacons: &mut BeaconAnswers, secs| {
+            update_states(beacons, last_seen + Duration::from_secs(secs), &thresholds);
+            beacons.get(&key).map(|b| b.state)
+        };
+        assert_eq!(state_after(&mut beacons, 1), Some(BeaconState::Online));
+        assert_eq!(state_after(&mut beacons, 3), Some(BeaconState::Stale));
+        assert_eq!(state_after(&mut beacons, 10), Some(BeaconState::Lost));
+        assert_eq!(state_after(&mut beacons, 60), None);
+        assert_eq!(
+            StateThresholds::from(&ScannerConfig {
+                scan_period: 2.0,
+                ..ScannerConfig::default()
+            }),
+            StateThresholds {
+                stale: Duration::from_secs(30),
+                lost: Duration::from_secs(120),
+                forget: None,
+            }
+        );
+        let sweeping = ScannerConfig {
+            sweep: SweepConfig {
+                targets: vec!["10.1.0.0/24".parse().unwrap()],
+                ..SweepConfig::default()
+            },
+            ..ScannerConfig::default()
+        };
+        let sweep = sweeping.sweep_duration();
+        assert_eq!(
+            StateThresholds::from(&sweeping).stale,
+            Duration::from_secs(15) + sweep
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_beacons_device_id() {
+        let addr = |last_byte| SocketAddr::from((Ipv4Addr::new(192, 168, 0, last_byte), 1901));
+        let answer = |id: &str| Answer::from(format!(r#"{{"device_id":"{}"}}"#, id));
+        let mut beacons = BeaconAnswers::new();
+        let first = BeaconAnswer::new(addr(1), answer("box-a"));
+        insert_beacon(&mut beacons, first.clone());
+        insert_beacon(&mut beacons, BeaconAnswer::new(addr(1), answer("box-b")));
+        let moved = BeaconAnswer::new(addr(2), answer("box-a"));
+        insert_beacon(&mut beacons, moved.clone());
+        assert_eq!(beacons.len(), 2);
+        let key = BeaconKey::Device("box-a".into());
+        let beacon = &beacons[&key];
+        assert_eq!(beacon.addr, addr(2));
+        assert_eq!(beacon.first_seen, first.first_seen);
+        assert_eq!(
+            beacon
+                .seen_addrs
+                .iter()
+                .map(|(a, _)| *a)
+                .collect::<Vec<_>>(),
+            vec![addr(1), addr(2)]
+        );
+        let thresholds = StateThresholds::default();
+        update_states(&mut beacons, moved.last_seen, &thresholds);
+        assert_eq!(beacons[&key].previous_addr, None); // both addresses may be in use
+        update_states(
+            &mut beacons,
+            first.last_seen + thresholds.stale,
+            &thresholds,
+        );
+        assert_eq!(beacons[&key].previous_addr, Some(addr(1)));
+        let forged = BeaconAnswer {
+            authenticity: Authenticity::Unverified,
+            ..BeaconAnswer::new(addr(3), answer("box-a"))
         };
+        assert_eq!(forged.key(), BeaconKey::Addr(addr(3)));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_reachable_at() {
+        let payload = r#"{
+            "received_on": {"interface": "eth1", "addr": "192.168.1.2"},
+            "addresses": [
+                {"addr": "fe80::2", "prefix_len": 64, "interface": "eth1"},
+                {"addr": "2001:db8::2", "prefix_len": 64, "interface": "eth1"},
+                {"addr": "10.8.0.2", "prefix_len": 24, "interface": "wg0"},
+                {"addr": "172.16.0.2", "prefix_len": 16, "interface": "eth0"},
+                {"addr": "192.168.1.2", "prefix_len": 24, "interface": "eth1"}
+            ]
+        }"#;
+        let mut answer = BeaconAnswer::new(
+            SocketAddr::from((Ipv4Addr::new(172, 16, 0, 2), 1901)),
+            Answer::from(payload.to_string()),
+        );
+        answer.interface = Some("eth0".into());
+        let local = vec![InterfaceAddr {
+            name: "wg0".into(),
+            index: Some(4),
+            ip: "10.8.0.1".parse().unwrap(),
+            prefix_len: 24,
+            broadcast: None,
+        }];
+        assert_eq!(
+            answer.reachable_at(&local),
+            vec![
+                "192.168.1.2",
+                "172.16.0.2",
+                "10.8.0.2",
+                "2001:db8::2",
+                "fe80::2%eth0"
+            ]
+        );
+        let legacy = BeaconAnswer::new(
+            SocketAddr::from((Ipv4Addr::new(172, 16, 0, 3), 1901)),
+            Answer::from(r#"{"hostname":"h"}"#.to_string()),
+        );
+        assert_eq!(legacy.reachable_at(&[]), vec!["172.16.0.3"]);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_put_in_queue() {
+        let (sender, receiver) = init_input_channel();
+        let an_answer = BeaconAnswer::new(
+            SocketAddr::from((Ipv4Addr::new(127, 0, 0, 1), 1901)),
+            Answer::default(),
+        );
         sender.send(an_answer.clone()).unwrap();
         assert_eq!(receiver.try_recv().unwrap(), an_answer);
     }
@@ -1,32 +1,143 @@
 use crate::conf::ScannerConfig;
+use crate::requests::IssuedRequests;
+use bytes::Bytes;
 use color_eyre::eyre::Report;
-use ipdisserver::signature::Signature;
-use std::net::Ipv4Addr;
-use std::net::SocketAddr;
+use ipdisserver::auth::{AuthenticatedRequest, Nonce};
+use ipdisserver::net::{bind_udp, interface_addrs, InterfaceAddr};
+use ipdisserver::protocol::{new_request_id, pad, Flags, Header, MessageType};
 use std::net::UdpSocket;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{SocketAddr, SocketAddrV6};
 use std::thread;
-use std::time::Duration;
-use tracing::{info, instrument, trace};
+use std::time::{Duration, Instant};
+use tracing::{info, instrument, trace, warn};
 
 const SCANNER_ADDR: Ipv4Addr = Ipv4Addr::UNSPECIFIED; // "0.0.0.0"
+const SCANNER_ADDR_V6: Ipv6Addr = Ipv6Addr::UNSPECIFIED; // "::"
 
-#[instrument]
-pub fn run(socket: &UdpSocket, conf: &ScannerConfig) -> Result<(), Report> {
+/// Send requests from every socket: broadcast from IPv4 sockets, multicast on every interface
+/// from IPv6 sockets. Every round has a new request id, recorded in `issued` with the nonces of
+/// the authenticated requests.
+#[instrument(skip(issued))]
+pub fn run(
+    sockets: &[UdpSocket],
+    conf: &ScannerConfig,
+    issued: IssuedRequests,
+) -> Result<(), Report> {
     let frequency = 1.0 / conf.scan_period;
     {
-        info!(?socket, %frequency, ?conf.signatures, "Scanning for beacons.");
+        info!(?sockets, %frequency, ?conf.signatures, "Scanning for beacons.");
         loop {
-            send_single(
-                socket,
-                conf.broadcast_addr,
-                conf.target_port,
-                &conf.signatures,
-            )?;
+            let request_id = new_request_id()?;
+            issued.issue(request_id, Instant::now());
+            let interfaces = selected_interfaces(conf);
+            for socket in sockets {
+                let (requests, nonce) = build_requests(conf, request_id)?;
+                if let Some(nonce) = nonce {
+                    issued.issue_nonce(request_id, nonce);
+                }
+                match socket.local_addr()? {
+                    SocketAddr::V4(_) => {
+                        for addr in broadcast_targets(conf, &interfaces) {
+                            send_single(socket, addr, conf.target_port, &requests)?
+                        }
+                    }
+                    SocketAddr::V6(_) => send_multicast(
+                        socket,
+                        conf.multicast_addr,
+                        conf.target_port,
+                        &multicast_interfaces(&interfaces),
+                        &requests,
+                    )?,
+                }
+            }
             wait_duty_cycle(conf.scan_period);
         }
     }
 }
 
+/// Addresses of the interfaces selected in the configuration, all if none is selected. Listed
+/// again at every scan, so that interfaces going up and down are followed.
+fn selected_interfaces(conf: &ScannerConfig) -> Vec<InterfaceAddr> {
+    let interfaces = match interface_addrs() {
+        Ok(i) => i,
+        Err(error) => {
+            warn!(?error, "Failed listing the network interfaces.");
+            Vec::new()
+        }
+    };
+    interfaces
+        .into_iter()
+        .filter(|i| conf.interfaces.is_empty() || conf.interfaces.contains(&i.name))
+        .collect()
+}
+
+/// The configured broadcast addresses, or the subnet-directed broadcast address of every
+/// interface. The limited broadcast address is used if no interface supports broadcast, unless
+/// interfaces are selected.
+fn broadcast_targets(conf: &ScannerConfig, interfaces: &[InterfaceAddr]) -> Vec<Ipv4Addr> {
+    if !conf.broadcast_addrs.is_empty() {
+        return conf.broadcast_addrs.clone();
+    }
+    let mut addrs: Vec<Ipv4Addr> = interfaces.iter().filter_map(|i| i.broadcast).collect();
+    addrs.sort_unstable();
+    addrs.dedup();
+    if addrs.is_empty() && conf.interfaces.is_empty() {
+        addrs.push(Ipv4Addr::BROADCAST);
+    }
+    trace!(?addrs, "Broadcast targets.");
+    addrs
+}
+
+/// Indexes of the interfaces with an IPv6 address.
+fn multicast_interfaces(interfaces: &[InterfaceAddr]) -> Vec<u32> {
+    let mut indexes: Vec<u32> = interfaces
+        .iter()
+        .filter(|i| i.ip.is_ipv6())
+        .filter_map(|i| i.index)
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes
+}
+
+/// Datagrams to send: a request for each signature, or a single authenticated request (with a
+/// fresh nonce, returned too) if a shared key is configured, padded to `pad_requests`. With
+/// `legacy_requests` the bare signatures are sent too, for servers not supporting the versioned
+/// protocol.
+pub fn build_requests(
+    conf: &ScannerConfig,
+    request_id: u32,
+) -> Result<(Vec<Bytes>, Option<Nonce>), Report> {
+    let mut capabilities = Flags::CAN_REASSEMBLE | Flags::CAN_DECRYPT;
+    if conf.pad_requests > 0 {
+        capabilities = capabilities | Flags::PADDED;
+    }
+    let encode = |header: &Header, body: &[u8]| match conf.pad_requests {
+        0 => header.encode(body),
+        size => header.encode(&pad(body, size)),
+    };
+    match &conf.shared_key {
+        None => {
+            let header = Header::new(MessageType::Request, capabilities, request_id);
+            let requests = conf.signatures.iter().map(|s| encode(&header, &s.0));
+            let legacy = conf
+                .signatures
+                .iter()
+                .filter(|_| conf.legacy_requests)
+                .map(|s| s.0.clone());
+            Ok((requests.chain(legacy).collect(), None))
+        }
+        Some(key) => {
+            let flags = capabilities | Flags::AUTHENTICATED;
+            let header = Header::new(MessageType::Request, flags, request_id);
+            let request = AuthenticatedRequest::new()?;
+            let body = request.to_bytes(&header, key);
+            Ok((vec![encode(&header, &body)], Some(request.nonce)))
+        }
+    }
+}
+
 pub fn socket_setup(scanner_port: u16) -> Result<UdpSocket, Report> {
     let socket = UdpSocket::bind(format!("{}:{}", SCANNER_ADDR, scanner_port))
         .expect("Failed to setup broadcasting socket");
@@ -34,23 +145,50 @@ pub fn socket_setup(scanner_port: u16) -> Result<UdpSocket, Report> {
     Ok(socket)
 }
 
+pub fn socket_setup_v6(scanner_port: u16) -> Result<UdpSocket, Report> {
+    bind_udp(SocketAddr::from((SCANNER_ADDR_V6, scanner_port)))
+}
+
 #[instrument]
 fn send_single(
     socket: &UdpSocket,
     broadcast_addr: Ipv4Addr,
     target_port: u16,
-    signatures: &[Signature],
+    requests: &[Bytes],
 ) -> Result<(), Report> {
     let beacon_broadcast_addr = SocketAddr::from((broadcast_addr, target_port));
-    for signature in signatures {
-        socket
-            .send_to(&signature.0, beacon_broadcast_addr)
-            .expect("Failed broadcasting signature");
-        trace!(
-            dest = %beacon_broadcast_addr,
-            payload = ?signature.0,
-            "Broadcasted."
-        );
+    for request in requests {
+        match socket.send_to(request, beacon_broadcast_addr) {
+            Ok(_) => trace!(
+                dest = %beacon_broadcast_addr,
+                payload = ?request,
+                "Broadcasted."
+            ),
+            Err(error) => {
+                warn!(dest = %beacon_broadcast_addr, ?error, "Failed broadcasting request.")
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Send the requests to the multicast group, once for every interface.
+#[instrument]
+fn send_multicast(
+    socket: &UdpSocket,
+    multicast_addr: Ipv6Addr,
+    target_port: u16,
+    interfaces: &[u32],
+    requests: &[Bytes],
+) -> Result<(), Report> {
+    for &index in interfaces {
+        let dest = SocketAddr::V6(SocketAddrV6::new(multicast_addr, target_port, 0, index));
+        for request in requests {
+            match socket.send_to(request, dest) {
+                Ok(_) => trace!(%dest, payload = ?request, "Multicasted."),
+                Err(error) => warn!(%dest, ?error, "Failed multicasting request."),
+            }
+        }
     }
     Ok(())
 }
@@ -62,6 +200,10 @@ fn wait_duty_cycle(scan_period: f64) {
 #[cfg(test)]
 mod test {
     use super::*;
+    use ipdisserver::auth::SharedKey;
+    use ipdisserver::net::multicast_interfaces_v6;
+    use ipdisserver::protocol::{unpad, REQUEST_MAX_SIZE};
+    use ipdisserver::signature::Signature;
     use std::thread;
     use std::time::Duration;
 
@@ -69,7 +211,7 @@ mod test {
     #[tracing_test::traced_test]
     fn test_send() {
         let signature: Signature = Signature::from("test-signature");
-        let signatures = vec![signature.clone()];
+        let signatures = vec![signature.0.clone()];
         let listener_socket = UdpSocket::bind(format!("{}:{}", "0.0.0.0", 0)).unwrap();
         let mut buf = [0; 14];
         let listener_port = listener_socket.local_addr().unwrap().port();
@@ -85,4 +227,99 @@ mod test {
         assert_eq!(buf.to_vec(), signature.0);
         sender_handle.join().unwrap();
     }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_build_requests() {
+        let key = SharedKey::from("secret".as_bytes());
+        let mut conf = ScannerConfig::default();
+        let (requests, nonce) = build_requests(&conf, 42).unwrap();
+        assert_eq!(requests.len(), conf.signatures.len());
+        assert_eq!(nonce, None);
+        let (header, body) = Header::parse(&requests[0]).unwrap();
+        assert_eq!(header.request_id, 42);
+        assert!(header
+            .flags
+            .contains(Flags::CAN_REASSEMBLE | Flags::CAN_DECRYPT | Flags::PADDED));
+        assert_eq!(requests[0].len(), REQUEST_MAX_SIZE);
+        assert_eq!(unpad(body), Ok(conf.signatures[0].0.as_ref()));
+        conf.pad_requests = 0;
+        let (requests, _) = build_requests(&conf, 42).unwrap();
+        let (header, body) = Header::parse(&requests[0]).unwrap();
+        assert!(!header.flags.contains(Flags::PADDED));
+        assert_eq!(body, conf.signatures[0].0);
+        conf.pad_requests = REQUEST_MAX_SIZE;
+        conf.legacy_requests = true;
+        let (requests, _) = build_requests(&conf, 42).unwrap();
+        assert_eq!(requests.last().unwrap(), &conf.signatures.last().unwrap().0);
+        conf.shared_key = Some(key.clone());
+        let (requests, nonce) = build_requests(&conf, 42).unwrap();
+        assert_eq!(requests.len(), 1);
+        let (header, body) = Header::parse(&requests[0]).unwrap();
+        assert!(header.flags.contains(Flags::AUTHENTICATED));
+        let request = AuthenticatedRequest::from_bytes(&header, unpad(body).unwrap(), &key);
+        assert_eq!(Some(request.unwrap().nonce), nonce);
+        assert_ne!(build_requests(&conf, 42).unwrap().1, nonce); // fresh nonce
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_broadcast_targets() {
+        let interface = |name: &str, ip: &str, broadcast| InterfaceAddr {
+            name: name.into(),
+            index: Some(2),
+            ip: ip.parse().unwrap(),
+            prefix_len: 24,
+            broadcast,
+        };
+        let interfaces = vec![
+            interface(
+                "eth0",
+                "192.168.1.10",
+                Some(Ipv4Addr::new(192, 168, 1, 255)),
+            ),
+            interface("eth0", "fe80::1", None),
+            interface("wg0", "10.0.0.1", None),
+        ];
+        let mut conf = ScannerConfig::default();
+        assert_eq!(
+            broadcast_targets(&conf, &interfaces),
+            vec![Ipv4Addr::new(192, 168, 1, 255)]
+        );
+        assert_eq!(broadcast_targets(&conf, &[]), vec![Ipv4Addr::BROADCAST]);
+        assert_eq!(multicast_interfaces(&interfaces), vec![2]);
+        conf.interfaces = vec!["wg0".into()];
+        assert!(broadcast_targets(&conf, &interfaces[2..]).is_empty());
+        conf.broadcast_addrs = vec![Ipv4Addr::new(10, 255, 255, 255)];
+        assert_eq!(
+            broadcast_targets(&conf, &interfaces),
+            vec![Ipv4Addr::new(10, 255, 255, 255)]
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_send_multicast() {
+        let group = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x1999);
+        let listener_socket = match bind_udp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))) {
+            Ok(s) => s,
+            Err(_) => return, // no IPv6 support
+        };
+        let interfaces = multicast_interfaces_v6().unwrap();
+        if interfaces.is_empty() {
+            return;
+        }
+        ipdisserver::net::join_multicast_v6(&listener_socket, &group, &mut Default::default())
+            .unwrap();
+        let listener_port = listener_socket.local_addr().unwrap().port();
+        let signature = Signature::from("test-signature");
+        let signatures = vec![signature.0.clone()];
+        let socket = socket_setup_v6(0).unwrap();
+        send_multicast(&socket, group, listener_port, &interfaces, &signatures).unwrap();
+        let mut buf = [0; 14];
+        let (lenght, source) = listener_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(lenght, signature.0.len());
+        assert_eq!(buf.to_vec(), signature.0);
+        assert!(source.is_ipv6());
+    }
 }
@@ -1,20 +1,63 @@
+use ipdisserver::auth::SharedKey;
+use ipdisserver::conf::MULTICAST_ADDR_V6_DEFAULT;
 use ipdisserver::conf::SERVER_PORT_DEFAULT;
 use ipdisserver::conf::SIGNATURE_DEFAULT;
+use ipdisserver::crypto::PrivateKey;
+use ipdisserver::net::directed_broadcast;
+use ipdisserver::protocol::REQUEST_MAX_SIZE;
 use ipdisserver::signature::Signature;
-use std::net::Ipv4Addr;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
 
 const SCANNER_PORT_DEFAULT: u16 = 1902;
 const SCAN_PERIOD_DEFAULT: f64 = 1.0;
-const BROADCAST_ADDR_DEFAULT: Ipv4Addr = Ipv4Addr::BROADCAST; // 255.255.255.255
+// scan periods, ipdisserver answers a same scanner at most every 10s by default. Swept hosts are
+// sent a request once per sweep, so the sweep duration is added when sweeping.
+const STALE_AFTER_DEFAULT: f64 = 15.0;
+const LOST_AFTER_DEFAULT: f64 = 60.0;
+const SWEEP_RATE_DEFAULT: f64 = 100.0; // datagrams per second
+const SWEEP_RETRIES_DEFAULT: u32 = 2;
+/// Shortest prefix of a swept network, a /16 is 65534 hosts.
+pub const SWEEP_PREFIX_LEN_MIN: u8 = 16;
+const BATCH_TIMEOUT_DEFAULT: Duration = Duration::from_secs(3);
+const MIN_SERVERS_DEFAULT: usize = 1;
 const EXTRA_SIGNATURE_DEFAULT: &str = "pang-supremacy-maritime-revoke-afterglow"; // compatibility with original ipdiscan
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct ScannerConfig {
     pub port: u16,
     pub scan_period: f64,
-    pub broadcast_addr: Ipv4Addr,
+    /// A beacon is stale if it did not answer for this many scan periods.
+    pub stale_after: f64,
+    /// A beacon is lost if it did not answer for this many scan periods.
+    pub lost_after: f64,
+    /// Lost beacons are removed after this many scan periods without answers, if set.
+    pub forget_after: Option<f64>,
+    /// IPv4 broadcast destinations. If empty, the subnet-directed broadcast address of every
+    /// interface (or of the selected ones) is used, or 255.255.255.255 if there is none.
+    pub broadcast_addrs: Vec<Ipv4Addr>,
+    /// Scan only on these interfaces: IPv4 broadcasts to the discovered subnets and IPv6
+    /// multicast. Every interface if empty.
+    pub interfaces: Vec<String>,
+    /// Requests are sent to this group on every IPv6 interface.
+    pub multicast_addr: Ipv6Addr,
+    pub use_ipv4: bool,
+    pub use_ipv6: bool,
     pub target_port: u16,
     pub signatures: Vec<Signature>,
+    /// Send the bare signatures too, for servers not supporting the versioned protocol.
+    pub legacy_requests: bool,
+    /// Requests are padded to this size, servers limit the answer size to a multiple of the
+    /// request size. Zero disables padding.
+    pub pad_requests: usize,
+    /// If set, authenticated requests are sent instead of signatures, and answers are verified.
+    pub shared_key: Option<SharedKey>,
+    /// Used to decrypt encrypted answers.
+    pub private_key: Option<PrivateKey>,
+    pub sweep: SweepConfig,
 }
 
 impl Default for ScannerConfig {
@@ -22,12 +65,193 @@ impl Default for ScannerConfig {
         Self {
             port: SCANNER_PORT_DEFAULT,
             scan_period: SCAN_PERIOD_DEFAULT,
-            broadcast_addr: BROADCAST_ADDR_DEFAULT,
+            stale_after: STALE_AFTER_DEFAULT,
+            lost_after: LOST_AFTER_DEFAULT,
+            forget_after: None,
+            broadcast_addrs: Vec::new(),
+            interfaces: Vec::new(),
+            multicast_addr: MULTICAST_ADDR_V6_DEFAULT,
+            use_ipv4: true,
+            use_ipv6: true,
             target_port: SERVER_PORT_DEFAULT,
             signatures: vec![
                 Signature::from(SIGNATURE_DEFAULT),
                 Signature::from(EXTRA_SIGNATURE_DEFAULT),
             ],
+            legacy_requests: false,
+            pad_requests: REQUEST_MAX_SIZE,
+            shared_key: None,
+            private_key: None,
+            sweep: SweepConfig::default(),
+        }
+    }
+}
+
+impl ScannerConfig {
+    /// Longest time between two requests to a same swept host: the passes of a sweep when no host
+    /// answers, each followed by a scan period. Zero without sweep.
+    pub fn sweep_duration(&self) -> Duration {
+        if self.sweep.targets.is_empty() {
+            return Duration::ZERO;
+        }
+        let datagrams_per_host = match self.shared_key {
+            Some(_) => 1,
+            None if self.legacy_requests => 2 * self.signatures.len(),
+            None => self.signatures.len(),
+        };
+        let hosts: usize = self.sweep.targets.iter().map(|t| t.hosts().count()).sum();
+        let pass = (hosts * datagrams_per_host) as f64 / self.sweep.rate + self.scan_period;
+        Duration::from_secs_f64(pass * f64::from(self.sweep.retries + 1))
+    }
+}
+
+/// Unicast sweep configurations, for networks broadcasts do not reach.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SweepConfig {
+    /// Hosts and networks sent a request in every sweep. No sweep if empty.
+    pub targets: Vec<SweepTarget>,
+    /// Datagrams per second.
+    pub rate: f64,
+    /// Hosts not answering are sent the request again this many times in a sweep.
+    pub retries: u32,
+}
+
+impl Default for SweepConfig {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            rate: SWEEP_RATE_DEFAULT,
+            retries: SWEEP_RETRIES_DEFAULT,
+        }
+    }
+}
+
+/// A host, or an IPv4 network in CIDR notation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SweepTarget {
+    Host(IpAddr),
+    Network(Ipv4Addr, u8),
+}
+
+impl SweepTarget {
+    /// Addresses to send the request to. The network and broadcast addresses of networks are
+    /// skipped, except for /31 and /32.
+    pub fn hosts(&self) -> Box<dyn Iterator<Item = IpAddr>> {
+        match *self {
+            Self::Host(ip) => Box::new(std::iter::once(ip)),
+            Self::Network(ip, prefix_len) => {
+                let last = u32::from(directed_broadcast(ip, prefix_len));
+                let first = last & !u32::MAX.checked_shr(u32::from(prefix_len)).unwrap_or(0);
+                let (first, last) = match prefix_len {
+                    31 | 32 => (first, last),
+                    _ => (first + 1, last - 1),
+                };
+                Box::new((first..=last).map(|n| IpAddr::V4(Ipv4Addr::from(n))))
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SweepTargetError {
+    #[error(
+        "invalid sweep target `{0}`, expected an IP address or an IPv4 network (e.g. 10.1.0.0/24)"
+    )]
+    Invalid(String),
+    #[error(
+        "sweep network `{0}` too large, the prefix length must be at least /{SWEEP_PREFIX_LEN_MIN}"
+    )]
+    TooLarge(String),
+}
+
+impl FromStr for SweepTarget {
+    type Err = SweepTargetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || SweepTargetError::Invalid(s.to_string());
+        match s.split_once('/') {
+            None => s.parse().map(Self::Host).map_err(|_| error()),
+            Some((ip, prefix_len)) => match (ip.parse(), prefix_len.parse()) {
+                (Ok(_), Ok(prefix_len)) if prefix_len < SWEEP_PREFIX_LEN_MIN => {
+                    Err(SweepTargetError::TooLarge(s.to_string()))
+                }
+                (Ok(ip), Ok(prefix_len)) if prefix_len <= 32 => Ok(Self::Network(ip, prefix_len)),
+                _ => Err(error()),
+            },
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error(
+    "invalid broadcast address `{0}`, expected an IPv4 address or network (e.g. 192.168.1.0/24)"
+)]
+pub struct BroadcastAddrError(String);
+
+/// Parse a broadcast address, or a network in CIDR notation, giving its directed broadcast
+/// address.
+pub fn parse_broadcast_addr(s: &str) -> Result<Ipv4Addr, BroadcastAddrError> {
+    let error = || BroadcastAddrError(s.to_string());
+    match s.split_once('/') {
+        None => s.parse().map_err(|_| error()),
+        Some((ip, prefix_len)) => {
+            let ip: Ipv4Addr = ip.parse().map_err(|_| error())?;
+            match prefix_len.parse() {
+                Ok(prefix_len) if prefix_len <= 32 => Ok(directed_broadcast(ip, prefix_len)),
+                _ => Err(error()),
+            }
+        }
+    }
+}
+
+/// Output of the non-interactive scan mode.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// A single JSON array, printed when the scan is over.
+    Json,
+    /// A JSON object per line, printed as soon as a new beacon answers.
+    Ndjson,
+    /// A CSV table with a header, printed when the scan is over.
+    Csv,
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("invalid output format `{0}`, expected one of: json, ndjson, csv")]
+pub struct OutputFormatError(String);
+
+impl FromStr for OutputFormat {
+    type Err = OutputFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            "csv" => Ok(Self::Csv),
+            other => Err(OutputFormatError(other.to_string())),
+        }
+    }
+}
+
+/// Non-interactive scan configurations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchConfig {
+    pub timeout: Duration,
+    pub format: OutputFormat,
+    /// CSV columns, taken from the answer keys. `addr` is the beacon address. If empty, `addr`
+    /// and every key found in the answers are used.
+    pub columns: Vec<String>,
+    /// Minimum number of answering beacons for a successful exit code.
+    pub min_servers: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            timeout: BATCH_TIMEOUT_DEFAULT,
+            format: OutputFormat::Json,
+            columns: Vec::new(),
+            min_servers: MIN_SERVERS_DEFAULT,
         }
     }
 }
@@ -45,13 +269,97 @@ mod test {
             ScannerConfig {
                 port: 1902,
                 scan_period: 1.0f64,
-                broadcast_addr: Ipv4Addr::new(255, 255, 255, 255),
+                stale_after: 15.0,
+                lost_after: 60.0,
+                forget_after: None,
+                broadcast_addrs: Vec::new(),
+                interfaces: Vec::new(),
+                multicast_addr: Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x1901),
+                use_ipv4: true,
+                use_ipv6: true,
                 target_port: 1901,
                 signatures: vec![
                     Signature::from("ipdisbeacon"),
                     Signature::from("pang-supremacy-maritime-revoke-afterglow")
-                ]
+                ],
+                legacy_requests: false,
+                pad_requests: 1200,
+                shared_key: None,
+                private_key: None,
+                sweep: SweepConfig {
+                    targets: Vec::new(),
+                    rate: 100.0,
+                    retries: 2,
+                },
             }
         );
     }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_output_format() {
+        assert_eq!(OutputFormat::from_str("ndjson"), Ok(OutputFormat::Ndjson));
+        assert_eq!(OutputFormat::from_str("csv"), Ok(OutputFormat::Csv));
+        assert!(OutputFormat::from_str("yaml").is_err());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_sweep_duration() {
+        assert_eq!(ScannerConfig::default().sweep_duration(), Duration::ZERO);
+        let conf = ScannerConfig {
+            sweep: SweepConfig {
+                targets: vec![SweepTarget::from_str("10.1.0.0/16").unwrap()],
+                ..SweepConfig::default()
+            },
+            ..ScannerConfig::default()
+        };
+        // 65534 hosts, 2 signatures, 3 passes at 100 datagrams per second
+        assert_eq!(conf.sweep_duration().as_secs(), 3935);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_sweep_target() {
+        let hosts = |s: &str| -> Vec<String> {
+            SweepTarget::from_str(s)
+                .unwrap()
+                .hosts()
+                .map(|ip| ip.to_string())
+                .collect()
+        };
+        assert_eq!(hosts("10.1.0.9/30"), vec!["10.1.0.9", "10.1.0.10"]);
+        assert_eq!(hosts("10.1.0.9/31"), vec!["10.1.0.8", "10.1.0.9"]);
+        assert_eq!(hosts("10.1.0.9/32"), vec!["10.1.0.9"]);
+        assert_eq!(hosts("2001:db8::1"), vec!["2001:db8::1"]);
+        assert_eq!(
+            SweepTarget::from_str("10.1.0.0/16")
+                .unwrap()
+                .hosts()
+                .count(),
+            65534
+        );
+        assert_eq!(
+            SweepTarget::from_str("10.0.0.0/8"),
+            Err(SweepTargetError::TooLarge("10.0.0.0/8".to_string()))
+        );
+        assert!(SweepTarget::from_str("10.1.0.0/33").is_err());
+        assert!(SweepTarget::from_str("2001:db8::/64").is_err());
+        assert!(SweepTarget::from_str("host.lan").is_err());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_parse_broadcast_addr() {
+        assert_eq!(
+            parse_broadcast_addr("192.168.1.255"),
+            Ok(Ipv4Addr::new(192, 168, 1, 255))
+        );
+        assert_eq!(
+            parse_broadcast_addr("10.1.0.0/16"),
+            Ok(Ipv4Addr::new(10, 1, 255, 255))
+        );
+        assert!(parse_broadcast_addr("10.1.0.0/33").is_err());
+        assert!(parse_broadcast_addr("fe80::1").is_err());
+    }
 }
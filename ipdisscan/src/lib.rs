@@ -1,6 +1,10 @@
+pub mod batch;
 pub mod beacons;
 pub mod broadcast;
 pub mod conf;
 pub mod listen;
+pub mod profiles;
+pub mod requests;
 pub mod setup;
+pub mod sweep;
 pub mod ui;
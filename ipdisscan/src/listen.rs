@@ -1,45 +1,221 @@
-use crate::beacons::BeaconAnswer;
+use crate::beacons::{Authenticity, BeaconAnswer, Encryption};
+use crate::conf::ScannerConfig;
+use crate::requests::IssuedRequests;
 use color_eyre::eyre::Report;
 use crossbeam::channel::Sender;
 use ipdisserver::answers::Answer;
+use ipdisserver::auth::{open_answer_unverified, verify_answer, Nonce, SharedKey};
+use ipdisserver::crypto::{decrypt_answer, PrivateKey};
+use ipdisserver::fragment::Reassembler;
+use ipdisserver::net::{interface_of, CachedInterfaceAddrs};
+use ipdisserver::protocol::{Flags, Header, MessageType, ProtocolError};
 use std::net::UdpSocket;
-use tracing::{debug, info, instrument, trace};
+use std::time::Instant;
+use tracing::{debug, info, instrument, trace, warn};
 
-const RECV_BUFFER_LENGHT: usize = 2usize.pow(10); // 1KiB
+const RECV_BUFFER_LENGHT: usize = 2usize.pow(16); // 64KiB, maximum UDP payload
 
-#[instrument]
-pub fn run(socket: &UdpSocket, channel_send_end: Sender<BeaconAnswer>) -> Result<(), Report> {
+/// Configurations of the listening threads: keys used to verify and decrypt the answers.
+#[derive(Debug, Clone, Default)]
+pub struct ListenConfig {
+    pub shared_key: Option<SharedKey>,
+    pub private_key: Option<PrivateKey>,
+    /// Accept answers without protocol header (and request id), sent by legacy servers.
+    pub accept_legacy: bool,
+}
+
+impl From<&ScannerConfig> for ListenConfig {
+    fn from(conf: &ScannerConfig) -> Self {
+        Self {
+            shared_key: conf.shared_key.clone(),
+            private_key: conf.private_key.clone(),
+            accept_legacy: conf.legacy_requests && conf.shared_key.is_none(),
+        }
+    }
+}
+
+/// Receive answers, dropping the ones to requests never issued by the broadcasting or sweeping
+/// threads.
+#[instrument(skip(issued))]
+pub fn run(
+    socket: &UdpSocket,
+    channel_send_end: Sender<BeaconAnswer>,
+    conf: ListenConfig,
+    issued: IssuedRequests,
+) -> Result<(), Report> {
     {
         info!(?socket, "Listening for beacon answers.");
+        let mut reassembler = Reassembler::default();
+        let mut interfaces = CachedInterfaceAddrs::default();
         loop {
-            serve_single(socket, channel_send_end.clone())?;
+            serve_single(
+                socket,
+                channel_send_end.clone(),
+                &conf,
+                &issued,
+                &mut reassembler,
+                &mut interfaces,
+            )?;
         }
     }
 }
 
-#[instrument]
-fn serve_single(socket: &UdpSocket, channel_send_end: Sender<BeaconAnswer>) -> Result<(), Report> {
-    let beacon_answer = receive(socket)?;
+#[instrument(skip(issued, reassembler, interfaces))]
+fn serve_single(
+    socket: &UdpSocket,
+    channel_send_end: Sender<BeaconAnswer>,
+    conf: &ListenConfig,
+    issued: &IssuedRequests,
+    reassembler: &mut Reassembler,
+    interfaces: &mut CachedInterfaceAddrs,
+) -> Result<(), Report> {
+    let beacon_answer = match receive(socket, conf, issued, reassembler, interfaces)? {
+        Some(a) => a,
+        None => return Ok(()), // dropped, or waiting for more fragments
+    };
     trace!(?beacon_answer.addr, %beacon_answer.payload, "Putting in queue.");
     channel_send_end.send(beacon_answer)?;
     Ok(())
 }
 
-fn receive(socket: &UdpSocket) -> Result<BeaconAnswer, Report> {
-    let mut buf = [0; RECV_BUFFER_LENGHT];
+/// Return the answer, if complete (answers can be split in more datagrams) and matching an
+/// issued request. The receiving interface is found among the local `interfaces`.
+fn receive(
+    socket: &UdpSocket,
+    conf: &ListenConfig,
+    issued: &IssuedRequests,
+    reassembler: &mut Reassembler,
+    interfaces: &mut CachedInterfaceAddrs,
+) -> Result<Option<BeaconAnswer>, Report> {
+    let mut buf = vec![0; RECV_BUFFER_LENGHT];
     trace!(?socket, "Listening.");
     let (lenght, source) = socket.recv_from(&mut buf)?;
-    let payload: Answer = (&buf[..lenght]).into();
     debug!(%lenght, %source, "Datagram received.");
-    Ok(BeaconAnswer {
-        addr: source.ip(),
-        payload,
-    })
+    let datagram = &buf[..lenght];
+    // Checked before reassembly, so that stray fragments are not buffered.
+    let sent_at = match Header::parse(datagram) {
+        Ok((header, _)) => match issued.answered(header.request_id, source.ip()) {
+            Some(t) => Some(t),
+            None => {
+                debug!(%source, %header.request_id, "Answer to a request never issued, dropped.");
+                return Ok(None);
+            }
+        },
+        Err(ProtocolError::Legacy) if conf.accept_legacy => None,
+        Err(error) => {
+            debug!(%source, %error, "Invalid answer dropped.");
+            return Ok(None);
+        }
+    };
+    let now = Instant::now();
+    let message = match reassembler.push(source, datagram, now) {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+    let (header, body) = match parse_answer(&message) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            debug!(%source, %error, "Invalid answer dropped.");
+            return Ok(None);
+        }
+    };
+    let is_issued = |nonce: &Nonce| {
+        header.is_some_and(|h| issued.is_nonce_issued(h.request_id, source.ip(), nonce))
+    };
+    let (payload, authenticity) =
+        open_answer(header.as_ref(), body, conf.shared_key.as_ref(), is_issued);
+    if authenticity == Authenticity::Unverified {
+        warn!(%source, "Unverified answer received.");
+    }
+    let encrypted = header.is_some_and(|h| h.flags.contains(Flags::ENCRYPTED));
+    let (payload, encryption) = decrypt_payload(payload, encrypted, conf.private_key.as_ref());
+    Ok(Some(BeaconAnswer {
+        authenticity,
+        encryption,
+        rtt: sent_at.map(|t| now.saturating_duration_since(t)),
+        interface: interface_of(&source, interfaces.get()),
+        ..BeaconAnswer::new(source, payload)
+    }))
+}
+
+/// Split the answer in header and body. Legacy answers, bare JSON, have no header.
+fn parse_answer(message: &[u8]) -> Result<(Option<Header>, &[u8]), ProtocolError> {
+    match Header::parse(message) {
+        Ok((header, body)) if header.message_type == MessageType::Answer => {
+            Ok((Some(header), body))
+        }
+        Ok((header, _)) => Err(ProtocolError::UnknownMessageType(header.message_type as u8)),
+        Err(ProtocolError::Legacy) => Ok((None, message)),
+        Err(error) => Err(error),
+    }
+}
+
+/// Decrypt the payload if it is encrypted, if not possible it is returned as is.
+fn decrypt_payload(
+    payload: Answer,
+    encrypted: bool,
+    private_key: Option<&PrivateKey>,
+) -> (Answer, Encryption) {
+    if !encrypted {
+        return (payload, Encryption::Plain);
+    }
+    let key = match private_key {
+        Some(k) => k,
+        None => return (payload, Encryption::NoKey),
+    };
+    match decrypt_answer(&payload.0, key) {
+        Ok(plaintext) => (Answer::from(&plaintext), Encryption::Decrypted),
+        Err(error) => {
+            debug!(%error, "Cannot decrypt answer.");
+            (payload, Encryption::NoKey)
+        }
+    }
+}
+
+/// Extract the payload of the answer body, verifying it if a shared key is configured. Answers
+/// signed with a nonce not `is_issued` are replays of older answers, not verified.
+fn open_answer<F>(
+    header: Option<&Header>,
+    body: &[u8],
+    shared_key: Option<&SharedKey>,
+    is_issued: F,
+) -> (Answer, Authenticity)
+where
+    F: Fn(&Nonce) -> bool,
+{
+    let authenticated = header.filter(|h| h.flags.contains(Flags::AUTHENTICATED));
+    match (shared_key, authenticated) {
+        (None, None) => (body.into(), Authenticity::NotChecked),
+        (None, Some(_)) => match open_answer_unverified(body) {
+            Ok((_nonce, payload)) => (Answer::from(&payload), Authenticity::NotChecked),
+            Err(_) => (body.into(), Authenticity::NotChecked),
+        },
+        (Some(_), None) => (body.into(), Authenticity::Unverified),
+        (Some(key), Some(header)) => match verify_answer(key, header, body) {
+            Ok((nonce, payload)) if is_issued(&nonce) => {
+                (Answer::from(&payload), Authenticity::Verified)
+            }
+            Ok((_, payload)) => {
+                debug!(request_id = %header.request_id, "Answer signed with a nonce never issued.");
+                (Answer::from(&payload), Authenticity::Unverified)
+            }
+            Err(error) => {
+                trace!(%error, "Answer verification failed.");
+                match open_answer_unverified(body) {
+                    Ok((_nonce, payload)) => (Answer::from(&payload), Authenticity::Unverified),
+                    Err(_) => (body.into(), Authenticity::Unverified),
+                }
+            }
+        },
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use ipdisserver::auth::{sign_answer, NONCE_SIZE};
+    use ipdisserver::crypto::encrypt_answer;
+    use ipdisserver::fragment::fragment;
     use std::net::SocketAddr;
     use std::thread;
     use std::time::Duration;
@@ -60,8 +236,213 @@ mod test {
             println!("[{}] -> {}", listener_addr, payload);
         });
 
-        let answer = receive(&listener_socket).unwrap();
+        let conf = ListenConfig {
+            accept_legacy: true,
+            ..ListenConfig::default()
+        };
+        let answer = receive(
+            &listener_socket,
+            &conf,
+            &IssuedRequests::default(),
+            &mut Reassembler::default(),
+            &mut CachedInterfaceAddrs::default(),
+        )
+        .unwrap()
+        .unwrap();
         assert_eq!(answer.payload.0, expected.0);
+        assert_eq!(answer.authenticity, Authenticity::NotChecked);
+        assert_eq!(answer.rtt, None);
         sender_handle.join().unwrap();
     }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_receive_fragmented() {
+        let payload = format!(r#"{{"big":"{}"}}"#, "x".repeat(5000));
+        let listener_socket = UdpSocket::bind(format!("{}:{}", "127.0.0.1", 0)).unwrap();
+        let sending_socket = UdpSocket::bind(format!("{}:{}", "127.0.0.1", 0)).unwrap();
+        let listener_addr = listener_socket.local_addr().unwrap();
+        let issued = IssuedRequests::default();
+        issued.issue(1, Instant::now());
+        let message =
+            Header::new(MessageType::Answer, Flags::empty(), 1).encode(payload.as_bytes());
+        let fragments = fragment(&message, 1, 1, 1200).unwrap();
+        for datagram in fragments.iter().rev() {
+            sending_socket.send_to(datagram, listener_addr).unwrap();
+        }
+        let conf = ListenConfig::default();
+        let mut reassembler = Reassembler::default();
+        let mut interfaces = CachedInterfaceAddrs::default();
+        for _ in 1..fragments.len() {
+            assert!(receive(
+                &listener_socket,
+                &conf,
+                &issued,
+                &mut reassembler,
+                &mut interfaces
+            )
+            .unwrap()
+            .is_none());
+        }
+        let answer = receive(
+            &listener_socket,
+            &conf,
+            &issued,
+            &mut reassembler,
+            &mut interfaces,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(answer.payload.0, payload.as_bytes());
+        assert!(answer.rtt.is_some());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_receive_unknown_request() {
+        let listener_socket = UdpSocket::bind(format!("{}:{}", "127.0.0.1", 0)).unwrap();
+        let sending_socket = UdpSocket::bind(format!("{}:{}", "127.0.0.1", 0)).unwrap();
+        let listener_addr = listener_socket.local_addr().unwrap();
+        let issued = IssuedRequests::default();
+        issued.issue(1, Instant::now());
+        let payload = br#"{"hostname":"h"}"#;
+        let stray = Header::new(MessageType::Answer, Flags::empty(), 2).encode(payload);
+        let answer = Header::new(MessageType::Answer, Flags::empty(), 1).encode(payload);
+        for datagram in [stray.as_ref(), payload.as_slice(), answer.as_ref()] {
+            sending_socket.send_to(datagram, listener_addr).unwrap();
+        }
+        let conf = ListenConfig::default();
+        let mut reassembler = Reassembler::default();
+        let mut interfaces = CachedInterfaceAddrs::default();
+        for _ in 0..2 {
+            // unknown request id, legacy answer not accepted
+            assert!(receive(
+                &listener_socket,
+                &conf,
+                &issued,
+                &mut reassembler,
+                &mut interfaces
+            )
+            .unwrap()
+            .is_none());
+        }
+        let answer = receive(
+            &listener_socket,
+            &conf,
+            &issued,
+            &mut reassembler,
+            &mut interfaces,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(answer.payload.0, payload.as_slice());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_open_answer() {
+        let key = SharedKey::from("secret".as_bytes());
+        let other_key = SharedKey::from("other".as_bytes());
+        let header = Header::new(MessageType::Answer, Flags::AUTHENTICATED, 1);
+        let payload = br#"{"hostname":"h"}"#;
+        let signed = sign_answer(&key, &header, &[1; NONCE_SIZE], payload);
+        let expected = Answer::from(payload.as_slice());
+        assert_eq!(
+            open_answer(Some(&header), &signed, Some(&key), |_| true),
+            (expected.clone(), Authenticity::Verified)
+        );
+        assert_eq!(
+            open_answer(Some(&header), &signed, Some(&other_key), |_| true),
+            (expected.clone(), Authenticity::Unverified)
+        );
+        assert_eq!(
+            open_answer(None, payload, Some(&key), |_| true),
+            (expected.clone(), Authenticity::Unverified)
+        );
+        assert_eq!(
+            open_answer(Some(&header), &signed, None, |_| true),
+            (expected.clone(), Authenticity::NotChecked)
+        );
+        assert_eq!(
+            open_answer(Some(&header), &signed, Some(&key), |_| false),
+            (expected, Authenticity::Unverified)
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_receive_replayed_answer() {
+        let listener_socket = UdpSocket::bind(format!("{}:{}", "127.0.0.1", 0)).unwrap();
+        let sending_socket = UdpSocket::bind(format!("{}:{}", "127.0.0.1", 0)).unwrap();
+        let listener_addr = listener_socket.local_addr().unwrap();
+        let key = SharedKey::from("secret".as_bytes());
+        let issued = IssuedRequests::default();
+        issued.issue(1, Instant::now());
+        issued.issue_nonce(1, [1; NONCE_SIZE]);
+        let header = Header::new(MessageType::Answer, Flags::AUTHENTICATED, 1);
+        let payload = br#"{"hostname":"h"}"#;
+        let fresh = sign_answer(&key, &header, &[1; NONCE_SIZE], payload);
+        let replayed = sign_answer(&key, &header, &[7; NONCE_SIZE], payload); // older request
+        for body in [fresh, replayed] {
+            sending_socket
+                .send_to(&header.encode(&body), listener_addr)
+                .unwrap();
+        }
+        let conf = ListenConfig {
+            shared_key: Some(key),
+            ..ListenConfig::default()
+        };
+        let mut reassembler = Reassembler::default();
+        let mut interfaces = CachedInterfaceAddrs::default();
+        let mut receive_next = || {
+            receive(
+                &listener_socket,
+                &conf,
+                &issued,
+                &mut reassembler,
+                &mut interfaces,
+            )
+            .unwrap()
+            .unwrap()
+        };
+        assert_eq!(receive_next().authenticity, Authenticity::Verified);
+        assert_eq!(receive_next().authenticity, Authenticity::Unverified);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_decrypt_payload() {
+        let key = PrivateKey::generate().unwrap();
+        let other_key = PrivateKey::generate().unwrap();
+        let plain = Answer::from(r#"{"hostname":"h"}"#.to_string());
+        let encrypted = Answer::from(&encrypt_answer(&plain.0, &[key.public_key()]).unwrap());
+        assert_eq!(
+            decrypt_payload(plain.clone(), false, Some(&key)),
+            (plain.clone(), Encryption::Plain)
+        );
+        assert_eq!(
+            decrypt_payload(encrypted.clone(), true, Some(&key)),
+            (plain, Encryption::Decrypted)
+        );
+        assert_eq!(
+            decrypt_payload(encrypted.clone(), true, Some(&other_key)).1,
+            Encryption::NoKey
+        );
+        assert_eq!(decrypt_payload(encrypted, true, None).1, Encryption::NoKey);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_parse_answer() {
+        let legacy = br#"{"hostname":"h"}"#;
+        assert_eq!(parse_answer(legacy), Ok((None, legacy.as_slice())));
+        let header = Header::new(MessageType::Answer, Flags::ENCRYPTED, 3);
+        let message = header.encode(b"body");
+        assert_eq!(
+            parse_answer(&message),
+            Ok((Some(header), b"body".as_slice()))
+        );
+        let request = Header::new(MessageType::Request, Flags::empty(), 3).encode(b"ipdisbeacon");
+        assert!(parse_answer(&request).is_err());
+    }
 }
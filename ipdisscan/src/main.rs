@@ -1,26 +1,81 @@
 use clap::{App, Arg};
-use color_eyre::eyre::Report;
+use color_eyre::eyre::{eyre, Report};
+use ipdisscan::batch;
 use ipdisscan::beacons;
+use ipdisscan::beacons::StateThresholds;
 use ipdisscan::broadcast;
-use ipdisscan::broadcast::socket_setup;
-use ipdisscan::conf::ScannerConfig;
+use ipdisscan::broadcast::{socket_setup, socket_setup_v6};
+use ipdisscan::conf::{parse_broadcast_addr, BatchConfig, ScannerConfig, SweepTarget};
 use ipdisscan::listen;
+use ipdisscan::listen::ListenConfig;
+use ipdisscan::profiles::{self, ProfilesFile};
+use ipdisscan::requests::IssuedRequests;
 use ipdisscan::setup::setup;
+use ipdisscan::sweep;
 use ipdisscan::ui;
+use ipdisserver::auth::SharedKey;
+use ipdisserver::crypto::PrivateKey;
+use ipdisserver::protocol::REQUEST_MAX_SIZE;
 use ipdisserver::signature::Signature;
-use std::net::Ipv4Addr;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::{Ipv6Addr, UdpSocket};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::thread;
-use tracing::trace;
+use std::time::Duration;
+use tracing::{trace, warn};
+
+const EXIT_NOT_ENOUGH_SERVERS: i32 = 2;
 
 fn main() -> Result<(), Report> {
+    const CONFIG_OPT: &str = "config";
+    const PROFILE_OPT: &str = "profile";
     const PORT_OPT: &str = "port";
     const TARGET_PORT_OPT: &str = "target_port";
     const ADDR_OPT: &str = "addr";
+    const INTERFACE_OPT: &str = "interface";
     const SIGNATURE_OPT: &str = "signatures";
+    const LEGACY_OPT: &str = "legacy";
+    const PAD_REQUESTS_OPT: &str = "pad_requests";
+    const SCAN_PERIOD_OPT: &str = "scan_period";
+    const STALE_AFTER_OPT: &str = "stale_after";
+    const LOST_AFTER_OPT: &str = "lost_after";
+    const FORGET_AFTER_OPT: &str = "forget_after";
+    const SWEEP_OPT: &str = "sweep";
+    const SWEEP_FILE_OPT: &str = "sweep_file";
+    const SWEEP_RATE_OPT: &str = "sweep_rate";
+    const SWEEP_RETRIES_OPT: &str = "sweep_retries";
+    const MULTICAST_ADDR_OPT: &str = "multicast_addr";
+    const IPV4_ONLY_OPT: &str = "ipv4_only";
+    const IPV6_ONLY_OPT: &str = "ipv6_only";
+    const SHARED_KEY_OPT: &str = "shared_key";
+    const PRIVATE_KEY_OPT: &str = "private_key";
+    const GENERATE_KEY_OPT: &str = "generate_key";
+    const ONCE_OPT: &str = "once";
+    const TIMEOUT_OPT: &str = "timeout";
+    const FORMAT_OPT: &str = "format";
+    const COLUMNS_OPT: &str = "columns";
+    const MIN_SERVERS_OPT: &str = "min_servers";
     let matches = App::new("ipdisscan")
         .version("0.1.1")
         .about("Search for active instances of ipdisserver and get system informations.")
+        .arg(
+            Arg::with_name(CONFIG_OPT)
+                .long("config")
+                .value_name("CONFIG_FILE")
+                .help("Path of the TOML configuration file with the profiles. Default: $XDG_CONFIG_HOME/ipdisscan/config.toml (~/.config/ipdisscan/config.toml), if it exists.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(PROFILE_OPT)
+                .short("P")
+                .long("profile")
+                .value_name("NAME")
+                .help("Use the settings of a profile of the configuration file, overridden by the other options. Default: the `default_profile` of the configuration file, if any.")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name(PORT_OPT)
                 .short("p")
@@ -42,9 +97,73 @@ fn main() -> Result<(), Report> {
                 .short("a")
                 .long("broadcast-addr")
                 .value_name("ADDR")
-                .help("Broadcasting address. Default is the limited broadcast address: 255.255.255.255. You can also use any subnet-directed broadcast address, e.g. 192.168.1.255 (for the network 192.168.1.0/24).")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Broadcasting address, e.g. the limited broadcast address 255.255.255.255 or a subnet-directed broadcast address as 192.168.1.255. A network in CIDR notation (e.g. 192.168.1.0/24) is scanned with its broadcast address. This option can be used more than once. Default: the subnet-directed broadcast address of every interface, found again at every scan.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(INTERFACE_OPT)
+                .short("i")
+                .long("interface")
+                .value_name("INTERFACE")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Scan only on this interface (subnet broadcast and IPv6 multicast). This option can be used more than once. Explicit --broadcast-addr addresses are used whatever the interface. Default: every interface.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(SWEEP_OPT)
+                .long("sweep")
+                .value_name("TARGETS")
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .help("Comma separated hosts and IPv4 networks in CIDR notation (e.g. 10.1.0.0/24,10.2.0.5), each sent the requests by unicast, for routed networks that broadcasts do not reach. Networks larger than a /16 are refused. This option can be used more than once. By default no sweep is done.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(SWEEP_FILE_OPT)
+                .long("sweep-file")
+                .value_name("FILE")
+                .help("Path of a file with sweep targets, one per line. Empty lines and lines starting with `#` are ignored. Added to the --sweep targets.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(SWEEP_RATE_OPT)
+                .long("sweep-rate")
+                .value_name("PPS")
+                .help("Maximum number of sweep datagrams sent per second. Default: 100.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(SWEEP_RETRIES_OPT)
+                .long("sweep-retries")
+                .value_name("N")
+                .help("Hosts not answering are sent the requests again this many times in every sweep, a scan period apart. Default: 2.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MULTICAST_ADDR_OPT)
+                .short("m")
+                .long("multicast-addr")
+                .value_name("MULTICAST_ADDR")
+                .help("IPv6 multicast group, requests are sent to it on every interface. Default: ff02::1901.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name(IPV4_ONLY_OPT)
+                .short("4")
+                .long("ipv4-only")
+                .conflicts_with(IPV6_ONLY_OPT)
+                .help("Scan only with IPv4 broadcast. By default both IPv4 and IPv6 are used."),
+        )
+        .arg(
+            Arg::with_name(IPV6_ONLY_OPT)
+                .short("6")
+                .long("ipv6-only")
+                .help("Scan only with IPv6 multicast. By default both IPv4 and IPv6 are used."),
+        )
         .arg(
             Arg::with_name(SIGNATURE_OPT)
                 .short("s")
@@ -55,12 +174,137 @@ fn main() -> Result<(), Report> {
                 .help("Strings used to recognize ipdisserver instances. UTF-8 characters are allowed. Each signature length must be 128 bytes at most. This option can be used more than once. Default: `ipdisbeacon` and `pang-supremacy-maritime-revoke-afterglow` (the second one is for backward compatibility).")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name(LEGACY_OPT)
+                .short("l")
+                .long("legacy")
+                .help("Send the bare signatures too, without protocol header, to find ipdisserver versions older than the versioned protocol. Ignored with a shared key."),
+        )
+        .arg(
+            Arg::with_name(PAD_REQUESTS_OPT)
+                .long("pad-requests")
+                .value_name("BYTES")
+                .help("Pad requests to this size, at most 1200: ipdisserver does not send answers larger than a multiple of the request size (10 by default). 0 disables padding. Default: 1200.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(SCAN_PERIOD_OPT)
+                .long("scan-period")
+                .value_name("SECS")
+                .help("Time between broadcasts. Default: 1.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(STALE_AFTER_OPT)
+                .long("stale-after")
+                .value_name("PERIODS")
+                .help("Beacons not answering for this many scan periods are shown as stale, plus the sweep duration when sweeping. Default: 15.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(LOST_AFTER_OPT)
+                .long("lost-after")
+                .value_name("PERIODS")
+                .help("Beacons not answering for this many scan periods are shown as lost, plus the sweep duration when sweeping. Default: 60.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(FORGET_AFTER_OPT)
+                .long("forget-after")
+                .value_name("PERIODS")
+                .help("Beacons not answering for this many scan periods are removed from the list. By default lost beacons are kept.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(SHARED_KEY_OPT)
+                .short("k")
+                .long("shared-key-file")
+                .value_name("KEY_FILE")
+                .help("Path of a file containing a secret shared with the servers. If specified, requests authenticated with the key (HMAC-SHA256) are sent instead of the signatures, and answers not authenticated with the key are marked as unverified.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(PRIVATE_KEY_OPT)
+                .short("d")
+                .long("private-key-file")
+                .value_name("PRIVATE_KEY_FILE")
+                .help("Path of the private key used to decrypt encrypted answers. Answers that cannot be decrypted are shown as `encrypted, no key`.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(GENERATE_KEY_OPT)
+                .long("generate-key")
+                .value_name("PRIVATE_KEY_FILE")
+                .help("Generate a new key pair and exit. The private key is written to PRIVATE_KEY_FILE, the public key to PRIVATE_KEY_FILE.pub (to be used with `ipdisserver --encrypt-to`) and printed on standard output.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(ONCE_OPT)
+                .short("1")
+                .long("once")
+                .help("Non-interactive mode: scan for a bounded time, print the answers on standard output and exit. Exit code is 0 if enough servers answered (see --min-servers), 2 otherwise."),
+        )
+        .arg(
+            Arg::with_name(TIMEOUT_OPT)
+                .short("t")
+                .long("timeout")
+                .value_name("SECS")
+                .help("Duration of the non-interactive scan, implies --once. Default: 3.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(FORMAT_OPT)
+                .short("o")
+                .long("output-format")
+                .value_name("FORMAT")
+                .possible_values(&["json", "ndjson", "csv"])
+                .help("Non-interactive output format. `ndjson` prints a record as soon as a new server answers. Implies --once. Default: json.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(COLUMNS_OPT)
+                .short("c")
+                .long("columns")
+                .value_name("COLUMNS")
+                .use_delimiter(true)
+                .help("Comma separated answer keys used as CSV columns, `addr` is the server address. Implies --once. Default: `addr` and all the keys found in the answers.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MIN_SERVERS_OPT)
+                .short("n")
+                .long("min-servers")
+                .value_name("N")
+                .help("Minimum number of answering servers for a successful non-interactive scan, implies --once. Default: 1.")
+                .takes_value(true),
+        )
         .get_matches();
 
     setup()?;
     trace!(?matches);
 
+    if matches.is_present(GENERATE_KEY_OPT) {
+        return generate_key(Path::new(matches.value_of(GENERATE_KEY_OPT).unwrap()));
+    }
+
     let mut conf = ScannerConfig::default();
+    let mut batch_profile = BatchConfig::default();
+    let config_path = match matches.value_of(CONFIG_OPT) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => profiles::default_path().filter(|path| path.exists()),
+    };
+    match config_path {
+        Some(path) => {
+            let file = ProfilesFile::read(&path)?;
+            if let Some((name, profile)) = file.profile(matches.value_of(PROFILE_OPT))? {
+                file.apply(name, profile, &mut conf, &mut batch_profile)?;
+            }
+        }
+        None if matches.is_present(PROFILE_OPT) => {
+            return Err(eyre!("Profile given, but no configuration file found"));
+        }
+        None => (),
+    }
     if matches.is_present(PORT_OPT) {
         conf.port = matches.value_of(PORT_OPT).unwrap().parse()?;
     }
@@ -68,26 +312,207 @@ fn main() -> Result<(), Report> {
         conf.target_port = matches.value_of(TARGET_PORT_OPT).unwrap().parse()?;
     }
     if matches.is_present(ADDR_OPT) {
-        let str_broadcast_addr = matches.value_of(ADDR_OPT).unwrap().parse::<String>()?;
-        conf.broadcast_addr = Ipv4Addr::from_str(&str_broadcast_addr)?;
+        conf.broadcast_addrs = matches
+            .values_of(ADDR_OPT)
+            .unwrap()
+            .map(parse_broadcast_addr)
+            .collect::<Result<_, _>>()?;
+    }
+    if matches.is_present(INTERFACE_OPT) {
+        conf.interfaces = matches
+            .values_of(INTERFACE_OPT)
+            .unwrap()
+            .map(String::from)
+            .collect();
+    }
+    if matches.is_present(SWEEP_OPT) || matches.is_present(SWEEP_FILE_OPT) {
+        conf.sweep.targets = matches
+            .values_of(SWEEP_OPT)
+            .unwrap_or_default()
+            .map(SweepTarget::from_str)
+            .collect::<Result<_, _>>()?;
+        if let Some(path) = matches.value_of(SWEEP_FILE_OPT) {
+            conf.sweep
+                .targets
+                .extend(sweep::parse_targets_file(Path::new(path))?);
+        }
+    }
+    if matches.is_present(SWEEP_RATE_OPT) {
+        conf.sweep.rate = matches.value_of(SWEEP_RATE_OPT).unwrap().parse()?;
+        if !(conf.sweep.rate.is_finite() && conf.sweep.rate > 0.0) {
+            return Err(eyre!("Invalid sweep rate given"));
+        }
+    }
+    if matches.is_present(SWEEP_RETRIES_OPT) {
+        conf.sweep.retries = matches.value_of(SWEEP_RETRIES_OPT).unwrap().parse()?;
+    }
+    if matches.is_present(MULTICAST_ADDR_OPT) {
+        conf.multicast_addr = Ipv6Addr::from_str(matches.value_of(MULTICAST_ADDR_OPT).unwrap())?;
+    }
+    if matches.is_present(IPV4_ONLY_OPT) {
+        conf.use_ipv4 = true;
+        conf.use_ipv6 = false;
+    }
+    if matches.is_present(IPV6_ONLY_OPT) {
+        conf.use_ipv4 = false;
+        conf.use_ipv6 = true;
     }
     if matches.is_present(SIGNATURE_OPT) {
         conf.signatures = matches
             .values_of(SIGNATURE_OPT)
             .unwrap()
-            .into_iter()
             .map(Signature::from)
             .collect();
         // replace default signatures
     }
+    if matches.is_present(LEGACY_OPT) {
+        conf.legacy_requests = true;
+    }
+    if matches.is_present(PAD_REQUESTS_OPT) {
+        conf.pad_requests = matches.value_of(PAD_REQUESTS_OPT).unwrap().parse()?;
+        if conf.pad_requests > REQUEST_MAX_SIZE {
+            return Err(eyre!(
+                "Requests can be padded to {} bytes at most",
+                REQUEST_MAX_SIZE
+            ));
+        }
+    }
+    if matches.is_present(SCAN_PERIOD_OPT) {
+        conf.scan_period = matches.value_of(SCAN_PERIOD_OPT).unwrap().parse()?;
+        if !(conf.scan_period.is_finite() && conf.scan_period > 0.0) {
+            return Err(eyre!("Invalid scan period given"));
+        }
+    }
+    let periods = |opt: &str| -> Result<Option<f64>, Report> {
+        match matches.value_of(opt) {
+            None => Ok(None),
+            Some(value) => match value.parse::<f64>()? {
+                p if p.is_finite() && p > 0.0 => Ok(Some(p)),
+                _ => Err(eyre!("Invalid number of scan periods given: {}", value)),
+            },
+        }
+    };
+    if let Some(p) = periods(STALE_AFTER_OPT)? {
+        conf.stale_after = p;
+    }
+    if let Some(p) = periods(LOST_AFTER_OPT)? {
+        conf.lost_after = p;
+    }
+    if let Some(p) = periods(FORGET_AFTER_OPT)? {
+        conf.forget_after = Some(p);
+    }
+    if conf.lost_after < conf.stale_after {
+        return Err(eyre!(
+            "The lost threshold must not be lower than the stale one"
+        ));
+    }
+    if matches.is_present(SHARED_KEY_OPT) {
+        conf.shared_key = Some(SharedKey::from_file(Path::new(
+            matches.value_of(SHARED_KEY_OPT).unwrap(),
+        ))?);
+    }
+    if matches.is_present(PRIVATE_KEY_OPT) {
+        conf.private_key = Some(PrivateKey::from_file(Path::new(
+            matches.value_of(PRIVATE_KEY_OPT).unwrap(),
+        ))?);
+    }
+    let mut batch_conf = None;
+    // the non-interactive options are not ignored: they imply --once
+    let batch_opts = [
+        ONCE_OPT,
+        TIMEOUT_OPT,
+        FORMAT_OPT,
+        COLUMNS_OPT,
+        MIN_SERVERS_OPT,
+    ];
+    if batch_opts.iter().any(|opt| matches.is_present(opt)) {
+        let mut batch = batch_profile;
+        if matches.is_present(TIMEOUT_OPT) {
+            let timeout = matches.value_of(TIMEOUT_OPT).unwrap().parse()?;
+            batch.timeout = match Duration::try_from_secs_f64(timeout) {
+                Ok(timeout) if !timeout.is_zero() => timeout,
+                _ => return Err(eyre!("Invalid timeout given")),
+            };
+        }
+        if matches.is_present(FORMAT_OPT) {
+            batch.format = matches.value_of(FORMAT_OPT).unwrap().parse()?;
+        }
+        if matches.is_present(COLUMNS_OPT) {
+            batch.columns = matches
+                .values_of(COLUMNS_OPT)
+                .unwrap()
+                .map(String::from)
+                .collect();
+        }
+        if matches.is_present(MIN_SERVERS_OPT) {
+            batch.min_servers = matches.value_of(MIN_SERVERS_OPT).unwrap().parse()?;
+        }
+        batch_conf = Some(batch);
+    }
 
-    let socket = socket_setup(conf.port)?;
-    let socket_c = socket.try_clone()?;
+    let mut sockets = Vec::new();
+    if conf.use_ipv4 {
+        sockets.push(socket_setup(conf.port)?);
+    }
+    if conf.use_ipv6 {
+        match socket_setup_v6(conf.port) {
+            Ok(socket) => sockets.push(socket),
+            Err(error) if conf.use_ipv4 => warn!(?error, "IPv6 not available, scanning IPv4 only."),
+            Err(error) => return Err(error),
+        }
+    }
     let (input_channel_send_end, input_channel_receive_end) = beacons::init_input_channel();
+    let issued = IssuedRequests::default();
+    for socket in &sockets {
+        let socket_c = socket.try_clone()?;
+        let channel_send_end = input_channel_send_end.clone();
+        let listen_conf = ListenConfig::from(&conf);
+        let issued = issued.clone();
+        thread::spawn(move || listen::run(&socket_c, channel_send_end, listen_conf, issued));
+    }
+    let thresholds = StateThresholds::from(&conf);
+    if !conf.sweep.targets.is_empty() {
+        let sweep_sockets = sockets
+            .iter()
+            .map(UdpSocket::try_clone)
+            .collect::<Result<Vec<_>, _>>()?;
+        let sweep_conf = conf.clone();
+        let issued = issued.clone();
+        thread::spawn(move || sweep::run(&sweep_sockets, &sweep_conf, issued));
+    }
+    thread::spawn(move || broadcast::run(&sockets, &conf, issued));
+    if let Some(batch_conf) = batch_conf {
+        let found = batch::run(input_channel_receive_end, &batch_conf, &mut io::stdout())?;
+        if found.len() < batch_conf.min_servers {
+            std::process::exit(EXIT_NOT_ENOUGH_SERVERS);
+        }
+        return Ok(());
+    }
     let (output_channel_send_end, output_channel_receive_end) = beacons::init_output_channel();
-    thread::spawn(move || listen::run(&socket_c, input_channel_send_end));
-    thread::spawn(move || broadcast::run(&socket, &conf));
-    thread::spawn(move || beacons::run(input_channel_receive_end, output_channel_send_end));
+    thread::spawn(move || {
+        beacons::run(
+            input_channel_receive_end,
+            output_channel_send_end,
+            thresholds,
+        )
+    });
     ui::run(output_channel_receive_end)?;
     Ok(())
 }
+
+/// Write a new private key (readable only by the owner) and its public key.
+fn generate_key(path: &Path) -> Result<(), Report> {
+    let key = PrivateKey::generate()?;
+    let public_key = key.public_key().to_hex();
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{}", key.to_hex())?;
+    let mut public_path = path.as_os_str().to_owned();
+    public_path.push(".pub");
+    std::fs::write(&public_path, format!("{}\n", public_key))?;
+    println!("{}", public_key);
+    Ok(())
+}
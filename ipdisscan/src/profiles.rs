@@ -0,0 +1,411 @@
+//! User configuration file with named profiles, by default
+//! `$XDG_CONFIG_HOME/ipdisscan/config.toml` (`~/.config/ipdisscan/config.toml`), e.g.:
+//!
+//! ```toml
+//! default_profile = "lab-a"
+//!
+//! [profiles.lab-a]
+//! broadcast_addr = ["192.168.1.255", "10.0.0.0/8"]
+//! sweep = ["10.1.0.0/24", "10.2.0.5"]
+//! signatures = ["lab-a-beacon"]
+//! scan_period = 2.0
+//! lost_after = 30
+//! shared_key_file = "lab-a.key"
+//!
+//! [profiles.lab-a.display]
+//! format = "csv"
+//! columns = ["addr", "hostname"]
+//! ```
+//!
+//! Every key is optional, missing keys take the default value. Relative paths are relative to the
+//! configuration file directory.
+use crate::conf::{parse_broadcast_addr, BatchConfig, OutputFormat, ScannerConfig};
+use crate::sweep::parse_targets_file;
+use ipdisserver::auth::SharedKey;
+use ipdisserver::conf_file::{read_toml, ConfigError};
+use ipdisserver::crypto::PrivateKey;
+use ipdisserver::protocol::REQUEST_MAX_SIZE;
+use ipdisserver::server::SIGNATURE_MAX_LENGHT;
+use ipdisserver::signature::Signature;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const CONFIG_DIR: &str = "ipdisscan";
+const CONFIG_FILENAME: &str = "config.toml";
+
+/// Default location of the configuration file, as per the XDG base directory specification.
+/// None if neither XDG_CONFIG_HOME nor HOME are set.
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join(CONFIG_DIR).join(CONFIG_FILENAME))
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProfilesFile {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Used when no profile is selected on the command line.
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Scanner settings, overridden by command line options.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub port: Option<u16>,
+    pub target_port: Option<u16>,
+    /// An address or network, or a list of them.
+    pub broadcast_addr: Option<OneOrMany<String>>,
+    pub interfaces: Option<Vec<String>>,
+    /// Hosts and networks swept by unicast, a single one or a list.
+    pub sweep: Option<OneOrMany<String>>,
+    /// Sweep targets file, added to `sweep`.
+    pub sweep_file: Option<PathBuf>,
+    /// Datagrams per second.
+    pub sweep_rate: Option<f64>,
+    pub sweep_retries: Option<u32>,
+    pub multicast_addr: Option<Ipv6Addr>,
+    pub ipv4: Option<bool>,
+    pub ipv6: Option<bool>,
+    pub signatures: Option<Vec<String>>,
+    pub legacy: Option<bool>,
+    /// Bytes, 0 disables padding.
+    pub pad_requests: Option<usize>,
+    /// Seconds between broadcasts.
+    pub scan_period: Option<f64>,
+    /// Beacon state thresholds, in scan periods without answers.
+    pub stale_after: Option<f64>,
+    pub lost_after: Option<f64>,
+    pub forget_after: Option<f64>,
+    pub shared_key_file: Option<PathBuf>,
+    pub private_key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub display: DisplaySection,
+}
+
+/// A single value, or a list.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    pub fn as_slice(&self) -> &[T] {
+        match self {
+            Self::One(value) => std::slice::from_ref(value),
+            Self::Many(values) => values,
+        }
+    }
+}
+
+/// Output of the non-interactive mode.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DisplaySection {
+    pub format: Option<OutputFormat>,
+    pub columns: Option<Vec<String>>,
+}
+
+impl ProfilesFile {
+    pub fn read(path: &Path) -> Result<Self, ConfigError> {
+        let mut file: Self = read_toml(path)?;
+        file.path = path.into();
+        Ok(file)
+    }
+
+    /// The profile named `name`, or the default profile if None. Returns None if no profile is
+    /// selected.
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<(&str, &Profile)>, ConfigError> {
+        let name = match name.or(self.default_profile.as_deref()) {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+        match self.profiles.get_key_value(name) {
+            Some((name, profile)) => Ok(Some((name, profile))),
+            None => {
+                let available: Vec<_> = self.profiles.keys().map(String::as_str).collect();
+                let message = format!(
+                    "profile `{}` not found, available: {}",
+                    name,
+                    available.join(", ")
+                );
+                Err(self.invalid("profiles", message))
+            }
+        }
+    }
+
+    /// Apply the profile `name` settings to the configurations. Referenced key files are read too.
+    pub fn apply(
+        &self,
+        name: &str,
+        profile: &Profile,
+        conf: &mut ScannerConfig,
+        batch: &mut BatchConfig,
+    ) -> Result<(), ConfigError> {
+        info!(%name, "Using profile.");
+        let key = |k: &str| format!("profiles.{}.{}", name, k);
+        if let Some(port) = profile.port {
+            conf.port = port;
+        }
+        if let Some(port) = profile.target_port {
+            conf.target_port = port;
+        }
+        if let Some(addrs) = &profile.broadcast_addr {
+            conf.broadcast_addrs = Vec::new();
+            for addr in addrs.as_slice() {
+                let addr = parse_broadcast_addr(addr)
+                    .map_err(|e| self.invalid(&key("broadcast_addr"), e))?;
+                conf.broadcast_addrs.push(addr);
+            }
+        }
+        if let Some(interfaces) = &profile.interfaces {
+            conf.interfaces = interfaces.clone();
+        }
+        if profile.sweep.is_some() || profile.sweep_file.is_some() {
+            conf.sweep.targets = Vec::new();
+            for target in profile.sweep.iter().flat_map(OneOrMany::as_slice) {
+                let target = target.parse().map_err(|e| self.invalid(&key("sweep"), e))?;
+                conf.sweep.targets.push(target);
+            }
+        }
+        if let Some(path) = &profile.sweep_file {
+            let targets = parse_targets_file(&self.resolve(path))
+                .map_err(|e| self.invalid(&key("sweep_file"), e))?;
+            conf.sweep.targets.extend(targets);
+        }
+        if let Some(rate) = profile.sweep_rate {
+            if !(rate.is_finite() && rate > 0.0) {
+                return Err(self.invalid(&key("sweep_rate"), "must be positive"));
+            }
+            conf.sweep.rate = rate;
+        }
+        conf.sweep.retries = profile.sweep_retries.unwrap_or(conf.sweep.retries);
+        if let Some(addr) = profile.multicast_addr {
+            if !addr.is_multicast() {
+                return Err(self.invalid(&key("multicast_addr"), "not a multicast address"));
+            }
+            conf.multicast_addr = addr;
+        }
+        conf.use_ipv4 = profile.ipv4.unwrap_or(conf.use_ipv4);
+        conf.use_ipv6 = profile.ipv6.unwrap_or(conf.use_ipv6);
+        if !conf.use_ipv4 && !conf.use_ipv6 {
+            return Err(self.invalid(&key("ipv6"), "IPv4 and IPv6 both disabled"));
+        }
+        if let Some(signatures) = &profile.signatures {
+            conf.signatures = Vec::new();
+            for (i, signature) in signatures.iter().enumerate() {
+                if signature.is_empty() || signature.len() > SIGNATURE_MAX_LENGHT {
+                    let message = format!("length must be 1 to {} bytes", SIGNATURE_MAX_LENGHT);
+                    return Err(self.invalid(&key(&format!("signatures[{}]", i)), message));
+                }
+                conf.signatures.push(Signature::from(signature.as_str()));
+            }
+        }
+        conf.legacy_requests = profile.legacy.unwrap_or(conf.legacy_requests);
+        if let Some(size) = profile.pad_requests {
+            if size > REQUEST_MAX_SIZE {
+                let message = format!("must be {} bytes at most", REQUEST_MAX_SIZE);
+                return Err(self.invalid(&key("pad_requests"), message));
+            }
+            conf.pad_requests = size;
+        }
+        if let Some(period) = profile.scan_period {
+            if !(period.is_finite() && period > 0.0) {
+                return Err(self.invalid(&key("scan_period"), "must be positive"));
+            }
+            conf.scan_period = period;
+        }
+        for (name, value, field) in [
+            ("stale_after", profile.stale_after, &mut conf.stale_after),
+            ("lost_after", profile.lost_after, &mut conf.lost_after),
+        ] {
+            if let Some(periods) = value {
+                if !(periods.is_finite() && periods > 0.0) {
+                    return Err(self.invalid(&key(name), "must be positive"));
+                }
+                *field = periods;
+            }
+        }
+        if let Some(periods) = profile.forget_after {
+            if !(periods.is_finite() && periods > 0.0) {
+                return Err(self.invalid(&key("forget_after"), "must be positive"));
+            }
+            conf.forget_after = Some(periods);
+        }
+        if let Some(path) = &profile.shared_key_file {
+            let shared_key = SharedKey::from_file(&self.resolve(path))
+                .map_err(|e| self.invalid(&key("shared_key_file"), e))?;
+            conf.shared_key = Some(shared_key);
+        }
+        if let Some(path) = &profile.private_key_file {
+            let private_key = PrivateKey::from_file(&self.resolve(path))
+                .map_err(|e| self.invalid(&key("private_key_file"), e))?;
+            conf.private_key = Some(private_key);
+        }
+        if let Some(format) = profile.display.format {
+            batch.format = format;
+        }
+        if let Some(columns) = &profile.display.columns {
+            batch.columns = columns.clone();
+        }
+        Ok(())
+    }
+
+    /// Relative paths are relative to the configuration file directory.
+    fn resolve(&self, path: &Path) -> PathBuf {
+        match self.path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() => Path::new(".").join(path),
+            Some(dir) => dir.join(path),
+            None => path.into(),
+        }
+    }
+
+    fn invalid<M>(&self, key: &str, message: M) -> ConfigError
+    where
+        M: ToString,
+    {
+        ConfigError::InvalidKey {
+            path: self.path.clone(),
+            key: key.into(),
+            message: message.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conf::{SweepConfig, SweepTarget};
+    use ipdisserver::conf_file::parse_toml;
+    use std::net::Ipv4Addr;
+
+    const CONTENT: &str = r#"
+        default_profile = "lab-a"
+
+        [profiles.lab-a]
+        broadcast_addr = "192.168.1.255"
+        signatures = ["lab-a-beacon"]
+        scan_period = 2.5
+        ipv6 = false
+        lost_after = 20
+        forget_after = 100
+
+        [profiles.lab-a.display]
+        format = "csv"
+        columns = ["addr", "hostname"]
+
+        [profiles.lab-b]
+        scan_period = 0
+
+        [profiles.lab-c]
+        broadcast_addr = ["10.0.0.0/8", "192.168.1.255"]
+        interfaces = ["eth0"]
+        sweep = ["10.1.0.0/24", "10.2.0.5"]
+        sweep_rate = 50
+        sweep_retries = 0
+        pad_requests = 0
+
+        [profiles.lab-e]
+        sweep = "10.1.0.0/40"
+    "#;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_profile_apply() {
+        let file: ProfilesFile = parse_toml(CONTENT).unwrap();
+        let (name, profile) = file.profile(None).unwrap().unwrap();
+        assert_eq!(name, "lab-a");
+        let mut conf = ScannerConfig::default();
+        let mut batch = BatchConfig::default();
+        file.apply(name, profile, &mut conf, &mut batch).unwrap();
+        assert_eq!(
+            conf,
+            ScannerConfig {
+                broadcast_addrs: vec![Ipv4Addr::new(192, 168, 1, 255)],
+                signatures: vec![Signature::from("lab-a-beacon")],
+                scan_period: 2.5,
+                lost_after: 20.0,
+                forget_after: Some(100.0),
+                use_ipv6: false,
+                ..ScannerConfig::default()
+            }
+        );
+        assert_eq!(batch.format, OutputFormat::Csv);
+        assert_eq!(batch.columns, vec!["addr", "hostname"]);
+        let (name, profile) = file.profile(Some("lab-c")).unwrap().unwrap();
+        file.apply(name, profile, &mut conf, &mut batch).unwrap();
+        assert_eq!(
+            conf.broadcast_addrs,
+            vec![
+                Ipv4Addr::new(10, 255, 255, 255),
+                Ipv4Addr::new(192, 168, 1, 255)
+            ]
+        );
+        assert_eq!(conf.interfaces, vec!["eth0"]);
+        assert_eq!(
+            conf.sweep,
+            SweepConfig {
+                targets: vec![
+                    SweepTarget::Network(Ipv4Addr::new(10, 1, 0, 0), 24),
+                    SweepTarget::Host(Ipv4Addr::new(10, 2, 0, 5).into())
+                ],
+                rate: 50.0,
+                retries: 0,
+            }
+        );
+        assert_eq!(conf.pad_requests, 0);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_profile_errors() {
+        let file: ProfilesFile = parse_toml(CONTENT).unwrap();
+        let (name, profile) = file.profile(Some("lab-b")).unwrap().unwrap();
+        let error = file
+            .apply(
+                name,
+                profile,
+                &mut ScannerConfig::default(),
+                &mut BatchConfig::default(),
+            )
+            .unwrap_err();
+        assert!(
+            matches!(error, ConfigError::InvalidKey { key, .. } if key == "profiles.lab-b.scan_period")
+        );
+        assert!(file.profile(Some("lab-d")).is_err());
+        let (name, profile) = file.profile(Some("lab-e")).unwrap().unwrap();
+        let error = file
+            .apply(
+                name,
+                profile,
+                &mut ScannerConfig::default(),
+                &mut BatchConfig::default(),
+            )
+            .unwrap_err();
+        assert!(
+            matches!(error, ConfigError::InvalidKey { key, .. } if key == "profiles.lab-e.sweep")
+        );
+        assert_eq!(
+            parse_toml::<ProfilesFile>("")
+                .unwrap()
+                .profile(None)
+                .unwrap(),
+            None
+        );
+        assert!(matches!(
+            parse_toml::<ProfilesFile>("[profiles.x]\nformat = \"csv\""),
+            Err(ConfigError::InvalidKey { key, .. }) if key == "profiles.x.format"
+        ));
+    }
+}
@@ -0,0 +1,247 @@
+use ipdisserver::auth::Nonce;
+use lru::LruCache;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::trace;
+
+/// Answers to older rounds are dropped, at the default scan period this is about a minute.
+const MAX_TRACKED_REQUESTS: usize = 64;
+/// Swept hosts tracked, the least recently sent a request are forgotten first (and retried in
+/// the sweep even if they answered). A /16 sweep fits.
+const MAX_TRACKED_HOSTS: usize = 1 << 16;
+
+/// Request ids issued by the broadcasting and sweeping threads, with their sending time and the
+/// nonces of the authenticated requests. Shared with the listening threads, which drop answers to
+/// requests never issued, and verify authenticated answers only if signed with an issued nonce.
+#[derive(Debug, Clone)]
+pub struct IssuedRequests {
+    issued: Arc<Mutex<VecDeque<IssuedRequest>>>,
+    /// Last unicast request sent to each swept host, tracked apart so that large sweeps do not
+    /// push the broadcast requests out.
+    unicast: Arc<Mutex<LruCache<IpAddr, UnicastRequest>>>,
+}
+
+impl Default for IssuedRequests {
+    fn default() -> Self {
+        Self::with_max_hosts(MAX_TRACKED_HOSTS)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct IssuedRequest {
+    request_id: u32,
+    sent_at: Instant,
+    /// A request is authenticated with a new nonce for every socket it is sent from.
+    nonces: Vec<Nonce>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct UnicastRequest {
+    request_id: u32,
+    sent_at: Instant,
+    answered: bool,
+    nonce: Option<Nonce>,
+}
+
+impl IssuedRequests {
+    fn with_max_hosts(max_hosts: usize) -> Self {
+        let max_hosts = NonZeroUsize::new(max_hosts).expect("No swept host tracked");
+        Self {
+            issued: Default::default(),
+            unicast: Arc::new(Mutex::new(LruCache::new(max_hosts))),
+        }
+    }
+
+    pub fn issue(&self, request_id: u32, sent_at: Instant) {
+        let mut issued = self.issued.lock().expect("Poisoned issued requests lock");
+        if issued.len() >= MAX_TRACKED_REQUESTS {
+            issued.pop_front();
+        }
+        issued.push_back(IssuedRequest {
+            request_id,
+            sent_at,
+            nonces: Vec::new(),
+        });
+        trace!(%request_id, tracked = issued.len(), "Request issued.");
+    }
+
+    /// Record the nonce the request was authenticated with, if it is still tracked.
+    pub fn issue_nonce(&self, request_id: u32, nonce: Nonce) {
+        let mut issued = self.issued.lock().expect("Poisoned issued requests lock");
+        if let Some(request) = issued.iter_mut().rev().find(|r| r.request_id == request_id) {
+            request.nonces.push(nonce);
+        }
+    }
+
+    /// Sending time of the request, None if it was never issued (or is too old).
+    pub fn sent_at(&self, request_id: u32) -> Option<Instant> {
+        let issued = self.issued.lock().expect("Poisoned issued requests lock");
+        issued
+            .iter()
+            .rev()
+            .find(|r| r.request_id == request_id)
+            .map(|r| r.sent_at)
+    }
+
+    /// Whether the answer of `source` to the request is signed with a nonce issued for it, sent
+    /// with the request or to the source. Answers signed with the nonce of a request no longer
+    /// tracked are replays.
+    pub fn is_nonce_issued(&self, request_id: u32, source: IpAddr, nonce: &Nonce) -> bool {
+        let broadcast = {
+            let issued = self.issued.lock().expect("Poisoned issued requests lock");
+            issued
+                .iter()
+                .any(|r| r.request_id == request_id && r.nonces.contains(nonce))
+        };
+        let unicast = self.unicast.lock().expect("Poisoned issued requests lock");
+        broadcast
+            || unicast
+                .peek(&source)
+                .is_some_and(|r| r.request_id == request_id && r.nonce.as_ref() == Some(nonce))
+    }
+
+    /// Record a request sent to a single host, with the nonce it was authenticated with if any.
+    /// The host is considered not answering until an answer from it is received.
+    pub fn issue_unicast(
+        &self,
+        request_id: u32,
+        host: IpAddr,
+        sent_at: Instant,
+        nonce: Option<Nonce>,
+    ) {
+        let mut unicast = self.unicast.lock().expect("Poisoned issued requests lock");
+        unicast.put(
+            host,
+            UnicastRequest {
+                request_id,
+                sent_at,
+                answered: false,
+                nonce,
+            },
+        );
+        trace!(%request_id, %host, "Unicast request issued.");
+    }
+
+    /// Whether the host answered since it was last sent the request `request_id`.
+    pub fn is_answered(&self, host: IpAddr, request_id: u32) -> bool {
+        let unicast = self.unicast.lock().expect("Poisoned issued requests lock");
+        unicast
+            .peek(&host)
+            .is_some_and(|r| r.request_id == request_id && r.answered)
+    }
+
+    /// Sending time of the request answered by `source`, None if it was never issued. The
+    /// unicast request sent to the source is preferred, and marked as answered.
+    pub fn answered(&self, request_id: u32, source: IpAddr) -> Option<Instant> {
+        let broadcast_sent_at = self.sent_at(request_id);
+        let mut unicast = self.unicast.lock().expect("Poisoned issued requests lock");
+        match unicast.peek_mut(&source) {
+            Some(request) if request.request_id == request_id => {
+                request.answered = true;
+                Some(request.sent_at)
+            }
+            Some(request) if broadcast_sent_at.is_some() => {
+                request.answered = true; // already answering to broadcasts, no need to retry
+                broadcast_sent_at
+            }
+            _ => broadcast_sent_at,
+        }
+    }
+
+    /// Round-trip time of an answer to the request received at `now`.
+    pub fn round_trip(&self, request_id: u32, now: Instant) -> Option<Duration> {
+        self.sent_at(request_id)
+            .map(|sent_at| now.saturating_duration_since(sent_at))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_issued_requests() {
+        let requests = IssuedRequests::default();
+        let shared = requests.clone();
+        let sent_at = Instant::now();
+        requests.issue(1, sent_at);
+        assert_eq!(shared.sent_at(1), Some(sent_at));
+        assert_eq!(shared.sent_at(2), None);
+        assert_eq!(
+            shared.round_trip(1, sent_at + Duration::from_millis(20)),
+            Some(Duration::from_millis(20))
+        );
+        for id in 2..=MAX_TRACKED_REQUESTS as u32 + 1 {
+            requests.issue(id, sent_at);
+        }
+        assert_eq!(shared.sent_at(1), None); // forgotten
+        assert_eq!(shared.sent_at(2), Some(sent_at));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_unicast_requests() {
+        let requests = IssuedRequests::default();
+        let host: IpAddr = "10.1.0.7".parse().unwrap();
+        let other: IpAddr = "10.1.0.8".parse().unwrap();
+        let broadcast_at = Instant::now();
+        let sent_at = broadcast_at + Duration::from_millis(5);
+        requests.issue(100, broadcast_at);
+        requests.issue_unicast(200, host, sent_at, None);
+        requests.issue_unicast(200, other, sent_at, None);
+        assert_eq!(requests.sent_at(200), None); // not in the broadcast requests
+        assert!(!requests.is_answered(host, 200));
+        assert_eq!(requests.answered(200, host), Some(sent_at));
+        assert!(requests.is_answered(host, 200));
+        assert_eq!(requests.answered(200, "10.1.0.9".parse().unwrap()), None);
+        assert_eq!(requests.answered(100, other), Some(broadcast_at));
+        assert!(requests.is_answered(other, 200));
+        requests.issue_unicast(201, host, sent_at, None);
+        assert!(!requests.is_answered(host, 201));
+        assert!(!requests.is_answered(host, 200));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_unicast_requests_bounded() {
+        let requests = IssuedRequests::with_max_hosts(2);
+        let hosts: Vec<IpAddr> = (1..=3).map(|n| IpAddr::from([10, 1, 0, n])).collect();
+        let sent_at = Instant::now();
+        for &host in &hosts {
+            requests.issue_unicast(1, host, sent_at, None);
+            requests.answered(1, host);
+        }
+        assert!(!requests.is_answered(hosts[0], 1)); // forgotten, retried
+        assert!(requests.is_answered(hosts[1], 1));
+        assert!(requests.is_answered(hosts[2], 1));
+        assert_eq!(requests.answered(1, hosts[0]), None);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_issued_nonces() {
+        let requests = IssuedRequests::default();
+        let host: IpAddr = "10.1.0.7".parse().unwrap();
+        let other: IpAddr = "10.1.0.8".parse().unwrap();
+        let sent_at = Instant::now();
+        requests.issue(1, sent_at);
+        requests.issue_nonce(1, [1; 16]);
+        requests.issue_nonce(1, [2; 16]);
+        requests.issue_unicast(2, host, sent_at, Some([3; 16]));
+        assert!(requests.is_nonce_issued(1, other, &[1; 16]));
+        assert!(requests.is_nonce_issued(1, other, &[2; 16]));
+        assert!(!requests.is_nonce_issued(2, other, &[1; 16])); // another request
+        assert!(requests.is_nonce_issued(2, host, &[3; 16]));
+        assert!(!requests.is_nonce_issued(2, other, &[3; 16])); // sent to another host
+        requests.issue_unicast(3, host, sent_at, Some([4; 16]));
+        assert!(!requests.is_nonce_issued(2, host, &[3; 16])); // superseded
+        for id in 10..10 + MAX_TRACKED_REQUESTS as u32 {
+            requests.issue(id, sent_at);
+        }
+        assert!(!requests.is_nonce_issued(1, other, &[1; 16])); // forgotten
+    }
+}
@@ -0,0 +1,178 @@
+//! Unicast sweep, for routed networks that broadcasts and link-local multicast do not reach.
+//! Answers are received by the listening threads, as broadcast answers.
+use crate::broadcast::build_requests;
+use crate::conf::{ScannerConfig, SweepTarget};
+use crate::requests::IssuedRequests;
+use color_eyre::eyre::{eyre, Report};
+use ipdisserver::protocol::new_request_id;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument, trace};
+
+/// Send the requests to every target host, at most `conf.sweep.rate` datagrams per second. Hosts
+/// not answering are sent the requests again up to `conf.sweep.retries` times, a scan period
+/// apart. Every sweep has a new request id.
+#[instrument(skip(sockets, issued))]
+pub fn run(
+    sockets: &[UdpSocket],
+    conf: &ScannerConfig,
+    issued: IssuedRequests,
+) -> Result<(), Report> {
+    let mut socket_v4 = None;
+    let mut socket_v6 = None;
+    for socket in sockets {
+        match socket.local_addr()? {
+            SocketAddr::V4(_) => socket_v4 = Some(socket),
+            SocketAddr::V6(_) => socket_v6 = Some(socket),
+        }
+    }
+    let mut pacer = Pacer::new(conf.sweep.rate);
+    info!(?conf.sweep, "Sweeping hosts.");
+    loop {
+        let request_id = new_request_id()?;
+        // Also answers from addresses other than the swept ones (e.g. multi-homed servers) are
+        // accepted.
+        issued.issue(request_id, Instant::now());
+        for attempt in 0..=conf.sweep.retries {
+            let mut sent = 0;
+            for host in conf.sweep.targets.iter().flat_map(SweepTarget::hosts) {
+                if attempt > 0 && issued.is_answered(host, request_id) {
+                    continue;
+                }
+                let socket = match host {
+                    IpAddr::V4(_) => socket_v4,
+                    IpAddr::V6(_) => socket_v6,
+                };
+                let socket = match socket {
+                    Some(s) => s,
+                    None => {
+                        debug!(%host, "No socket for the address family, host skipped.");
+                        continue;
+                    }
+                };
+                let (requests, nonce) = build_requests(conf, request_id)?;
+                let dest = SocketAddr::new(host, conf.target_port);
+                issued.issue_unicast(request_id, host, Instant::now(), nonce);
+                for request in &requests {
+                    pacer.wait();
+                    match socket.send_to(request, dest) {
+                        Ok(_) => trace!(%dest, payload = ?request, "Sent."),
+                        Err(error) => debug!(%dest, ?error, "Failed sending request."),
+                    }
+                }
+                sent += 1;
+            }
+            debug!(%request_id, %attempt, %sent, "Sweep pass done.");
+            thread::sleep(Duration::from_secs_f64(conf.scan_period));
+        }
+    }
+}
+
+/// Spaces the sendings to keep a constant rate.
+struct Pacer {
+    interval: Duration,
+    next: Instant,
+}
+
+impl Pacer {
+    /// `rate` sendings per second.
+    fn new(rate: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next: Instant::now(),
+        }
+    }
+
+    /// Sleep until the next sending is allowed. Time not used while idle is not recovered.
+    fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        }
+        self.next = self.next.max(now) + self.interval;
+    }
+}
+
+/// Read sweep targets from a file, one per line.
+/// Empty lines and lines starting with `#` are ignored.
+pub fn parse_targets_file(path: &Path) -> Result<Vec<SweepTarget>, Report> {
+    info!(?path, "Reading sweep targets from file.");
+    let file = File::open(path).map_err(|e| eyre!("{}: {}", path.display(), e))?;
+    let mut targets = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        match line?.trim() {
+            "" => continue,
+            l if l.starts_with('#') => continue,
+            l => match l.parse() {
+                Ok(target) => targets.push(target),
+                Err(error) => return Err(eyre!("{}:{}: {}", path.display(), i + 1, error)),
+            },
+        }
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conf::SweepConfig;
+    use ipdisserver::protocol::Header;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_parse_targets_file() {
+        let path = std::env::temp_dir().join("rust-ipdisscan-test-sweep-targets");
+        std::fs::write(&path, "# site B\n10.2.0.0/30\n\n  10.3.0.1  \n").unwrap();
+        assert_eq!(
+            parse_targets_file(&path).unwrap(),
+            vec![
+                SweepTarget::Network(Ipv4Addr::new(10, 2, 0, 0), 30),
+                SweepTarget::Host(IpAddr::V4(Ipv4Addr::new(10, 3, 0, 1)))
+            ]
+        );
+        std::fs::write(&path, "10.2.0.0/33\n").unwrap();
+        assert!(parse_targets_file(&path).is_err());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_pacer() {
+        let mut pacer = Pacer::new(100.0);
+        let start = Instant::now();
+        for _ in 0..5 {
+            pacer.wait();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_sweep() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let conf = ScannerConfig {
+            target_port: listener.local_addr().unwrap().port(),
+            scan_period: 0.1,
+            sweep: SweepConfig {
+                targets: vec![SweepTarget::Host(IpAddr::V4(Ipv4Addr::LOCALHOST))],
+                rate: 1000.0,
+                retries: 1,
+            },
+            ..ScannerConfig::default()
+        };
+        let issued = IssuedRequests::default();
+        let shared = issued.clone();
+        thread::spawn(move || run(&[socket], &conf, shared));
+        let mut buf = [0; 1024];
+        let (lenght, _) = listener.recv_from(&mut buf).unwrap();
+        let (header, _) = Header::parse(&buf[..lenght]).unwrap();
+        let host = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(issued.answered(header.request_id, host).is_some());
+        assert!(issued.is_answered(host, header.request_id));
+    }
+}
@@ -1,15 +1,19 @@
-use crate::beacons::BeaconAnswer;
+use crate::beacons::{Authenticity, BeaconAnswer, BeaconState, Encryption};
 use color_eyre::eyre::Report;
 use crossbeam::channel::Receiver;
 use crossterm::event::{self, Event, KeyCode};
 use crossterm::terminal::{
     disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
 };
+use ipdisserver::answers::BeaconInfos;
+use ipdisserver::net::{format_scoped_ip, CachedInterfaceAddrs};
+use serde_json::Value;
 use std::io::{self, Stdout};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tui::backend::CrosstermBackend;
 use tui::layout::{Alignment, Constraint, Direction, Layout};
 use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans, Text};
 use tui::widgets::{Block, BorderType, Borders, List, ListItem, ListState, Paragraph, Wrap};
 use tui::Terminal;
 
@@ -43,6 +47,8 @@ enum AppAction {
 struct App {
     server_answers: Vec<BeaconAnswer>,
     list_state: ListState,
+    /// Local addresses, to sort the addresses the selected beacon is reachable at.
+    interfaces: CachedInterfaceAddrs,
 }
 
 impl App {
@@ -80,25 +86,113 @@ impl App {
         self.list_state.select(Some(index));
     }
 
-    fn get_info_text(&self) -> String {
+    fn get_info_text(&mut self) -> Text<'static> {
         let index = match self.get_cursor() {
-            None => return String::default(),
+            None => return Text::default(),
             Some(i) => i,
         };
-        let info_text = match self.server_answers.get(index) {
-            None => String::default(),
-            Some(a) => a.payload.pretty_format(),
+        let answer = match self.server_answers.get(index) {
+            None => return Text::default(),
+            Some(a) => a,
         };
-        info_text
+        let mut text = format_infos(&answer.infos());
+        if answer.seen_addrs.len() > 1 {
+            let addrs: Vec<String> = answer
+                .seen_addrs
+                .iter()
+                .map(|(addr, _)| format_scoped_ip(addr))
+                .collect();
+            let line = format!("seen on: {}", addrs.join(", "));
+            text.lines.insert(
+                0,
+                Spans::from(Span::styled(line, Style::default().fg(Color::DarkGray))),
+            );
+        }
+        let mut reachable = answer.reachable_at(self.interfaces.get()).into_iter();
+        if let Some(best) = reachable.next() {
+            let mut spans = vec![
+                Span::styled("reachable at: ", Style::default().fg(Color::DarkGray)),
+                Span::styled(
+                    best,
+                    Style::default()
+                        .fg(Color::Green)
+                        .add_modifier(Modifier::BOLD),
+                ),
+            ];
+            let others: Vec<String> = reachable.collect();
+            if !others.is_empty() {
+                spans.push(Span::styled(
+                    format!(", {}", others.join(", ")),
+                    Style::default().fg(Color::DarkGray),
+                ));
+            }
+            text.lines.insert(0, Spans::from(spans));
+        }
+        text
     }
 
-    fn get_list_items(&self) -> Vec<ListItem> {
+    fn get_list_items(&self) -> Vec<ListItem<'_>> {
+        let now = Instant::now();
         self.server_answers
             .iter()
-            .map(|a| ListItem::new(a.addr.to_string()))
+            .map(|a| {
+                let mut label = match a.rtt {
+                    Some(rtt) => format!("{} ({} ms)", a.host(), rtt.as_millis()),
+                    None => a.host(),
+                };
+                if let Some(interface) = &a.interface {
+                    label = format!("{} on {}", label, interface);
+                }
+                let (label, style) = match (a.authenticity, a.encryption) {
+                    (Authenticity::Unverified, _) => (
+                        format!("{} [unverified]", label),
+                        Style::default().fg(Color::Red),
+                    ),
+                    (_, Encryption::NoKey) => (
+                        format!("{} [encrypted, no key]", label),
+                        Style::default().fg(Color::Yellow),
+                    ),
+                    _ => (label, Style::default()),
+                };
+                let (state, state_color) = match a.state {
+                    BeaconState::Online => (a.state.to_string(), Color::Green),
+                    BeaconState::Stale => (
+                        format!("{}, {} ago", a.state, format_age(a.age(now))),
+                        Color::Yellow,
+                    ),
+                    BeaconState::Lost => (
+                        format!("{}, {} ago", a.state, format_age(a.age(now))),
+                        Color::DarkGray,
+                    ),
+                };
+                let mut spans = vec![
+                    Span::styled(label, style),
+                    Span::styled(format!(" {}", state), Style::default().fg(state_color)),
+                ];
+                if let Some(previous_addr) = a.previous_addr {
+                    spans.push(Span::styled(
+                        format!(" [IP changed, was {}]", format_scoped_ip(&previous_addr)),
+                        Style::default().fg(Color::Magenta),
+                    ));
+                }
+                ListItem::new(Spans::from(spans))
+            })
             .collect()
     }
 
+    /// First and last answer times of the selected beacon.
+    fn get_info_title(&self) -> String {
+        let now = Instant::now();
+        match self.get_cursor().and_then(|i| self.server_answers.get(i)) {
+            None => "Informations".into(),
+            Some(a) => format!(
+                "Informations (first seen {} ago, last seen {} ago)",
+                format_age(now.saturating_duration_since(a.first_seen)),
+                format_age(a.age(now))
+            ),
+        }
+    }
+
     fn update_answers(
         &mut self,
         channel_receiving_end: Receiver<Vec<BeaconAnswer>>,
@@ -128,6 +222,59 @@ impl App {
     }
 }
 
+/// Rounded down to the largest unit, e.g. `42s`, `5m`, `3h`.
+fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86400 => format!("{}h", s / 3600),
+        s => format!("{}d", s / 86400),
+    }
+}
+
+/// One line per value, nested objects and arrays indented under their key. Values are colored by
+/// type, so that e.g. the number 1 and the string "1" can be told apart.
+fn format_infos(infos: &BeaconInfos) -> Text<'static> {
+    let mut lines = Vec::new();
+    for (key, value) in infos {
+        push_value(&mut lines, 0, format!("{}: ", key), value);
+    }
+    Text::from(lines)
+}
+
+fn push_value(lines: &mut Vec<Spans<'static>>, depth: usize, label: String, value: &Value) {
+    let prefix = vec![
+        Span::raw("  ".repeat(depth)),
+        Span::styled(label, Style::default().add_modifier(Modifier::BOLD)),
+    ];
+    match value {
+        Value::Object(object) if !object.is_empty() => {
+            lines.push(Spans::from(prefix));
+            for (key, value) in object {
+                push_value(lines, depth + 1, format!("{}: ", key), value);
+            }
+        }
+        Value::Array(array) if !array.is_empty() => {
+            lines.push(Spans::from(prefix));
+            for value in array {
+                push_value(lines, depth + 1, "- ".into(), value);
+            }
+        }
+        scalar => {
+            let (text, color) = match scalar {
+                Value::String(s) if s.is_empty() => (r#""""#.to_string(), Color::DarkGray),
+                Value::String(s) => (s.clone(), Color::White),
+                Value::Number(n) => (n.to_string(), Color::Cyan),
+                Value::Bool(b) => (b.to_string(), Color::Yellow),
+                other => (other.to_string(), Color::DarkGray), // null, {} and []
+            };
+            let mut spans = prefix;
+            spans.push(Span::styled(text, Style::default().fg(color)));
+            lines.push(Spans::from(spans));
+        }
+    }
+}
+
 fn init_terminal() -> Result<ConcreteTerminal, Report> {
     let mut stdout = io::stdout();
     enable_raw_mode()?;
@@ -150,6 +297,7 @@ fn draw_frame(terminal: &mut ConcreteTerminal, app: &mut App) -> Result<(), Repo
         let app_clone = app.clone();
         let address_list = app_clone.get_list_items();
         let info_text = app.get_info_text();
+        let info_title = app.get_info_title();
 
         // Surrounding block
         let block = Block::default()
@@ -186,7 +334,7 @@ fn draw_frame(terminal: &mut ConcreteTerminal, app: &mut App) -> Result<(), Repo
 
         // Info block
         let info = Paragraph::new(info_text)
-            .block(Block::default().title("Informations").borders(Borders::ALL))
+            .block(Block::default().title(info_title).borders(Borders::ALL))
             .style(Style::default().fg(Color::White).bg(Color::Black))
             .alignment(Alignment::Left)
             .wrap(Wrap { trim: false });
@@ -194,3 +342,47 @@ fn draw_frame(terminal: &mut ConcreteTerminal, app: &mut App) -> Result<(), Repo
     })?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_format_infos() {
+        let infos = serde_json::json!({
+            "cpu_count": 4,
+            "net": {"eth0": {"ipv4": ["10.0.0.1", "10.0.0.2"], "up": false}},
+            "tags": [],
+        });
+        let text = format_infos(infos.as_object().unwrap());
+        let lines: Vec<String> = text
+            .lines
+            .iter()
+            .map(|l| l.0.iter().map(|s| s.content.as_ref()).collect())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "cpu_count: 4",
+                "net: ",
+                "  eth0: ",
+                "    ipv4: ",
+                "      - 10.0.0.1",
+                "      - 10.0.0.2",
+                "    up: false",
+                "tags: []",
+            ]
+        );
+        assert_eq!(text.lines[0].0[2].style.fg, Some(Color::Cyan));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::from_millis(1500)), "1s");
+        assert_eq!(format_age(Duration::from_secs(300)), "5m");
+        assert_eq!(format_age(Duration::from_secs(7300)), "2h");
+        assert_eq!(format_age(Duration::from_secs(200_000)), "2d");
+    }
+}
@@ -0,0 +1,86 @@
+//! The server own addresses, sent in every answer: scanners only see the source address of the
+//! answer, which on multi-homed hosts or behind NAT may not be the one to connect to.
+use crate::answers::BeaconInfos;
+use crate::inventory::{ExecuteInventory, InventoryOutput, CACHE_TTL_DEFAULT};
+use crate::net::{interface_addrs, InterfaceAddr};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+pub const ADDRESSES_KEY: &str = "addresses";
+
+/// Outputs the addresses of the non-loopback interfaces under the addresses key, as a list of
+/// `{"addr": ..., "prefix_len": ..., "interface": ...}` objects. Failures are reported under the
+/// same key in the diagnostics.
+pub struct AddressesInventory {
+    pub cache_ttl: Duration,
+}
+
+impl Default for AddressesInventory {
+    fn default() -> Self {
+        Self {
+            cache_ttl: CACHE_TTL_DEFAULT,
+        }
+    }
+}
+
+impl ExecuteInventory for AddressesInventory {
+    fn execute(&self) -> InventoryOutput {
+        let mut res = BeaconInfos::new();
+        match interface_addrs() {
+            Ok(interfaces) => {
+                res.insert(ADDRESSES_KEY.into(), addresses(&interfaces));
+                InventoryOutput {
+                    output: res,
+                    ..InventoryOutput::default()
+                }
+            }
+            Err(error) => {
+                res.insert(ADDRESSES_KEY.into(), error.to_string().into());
+                InventoryOutput {
+                    diagnostics: res,
+                    ..InventoryOutput::default()
+                }
+            }
+        }
+    }
+
+    fn cache_ttl(&self) -> Duration {
+        self.cache_ttl
+    }
+}
+
+fn addresses(interfaces: &[InterfaceAddr]) -> Value {
+    interfaces
+        .iter()
+        .map(|i| {
+            json!({
+                "addr": i.ip.to_string(),
+                "prefix_len": i.prefix_len,
+                "interface": i.name,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_addresses() {
+        let interfaces = vec![InterfaceAddr {
+            name: "eth0".into(),
+            index: Some(2),
+            ip: "192.168.1.2".parse().unwrap(),
+            prefix_len: 24,
+            broadcast: None,
+        }];
+        assert_eq!(
+            addresses(&interfaces),
+            json!([{"addr": "192.168.1.2", "prefix_len": 24, "interface": "eth0"}])
+        );
+        let output = AddressesInventory::default().execute();
+        assert!(output.output[ADDRESSES_KEY].is_array());
+    }
+}
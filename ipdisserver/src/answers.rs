@@ -1,52 +1,146 @@
 use crate::bytes::safe_format_bytes;
-use crate::inventory::{ExecuteInventory, InternalInventory, InventoryFile};
+use crate::inventory::{ExecuteInventory, InternalInventory, InventoryFile, InventoryOutput};
 use bytes::Bytes;
-use color_eyre::eyre::Report;
+use color_eyre::eyre::{eyre, Report};
 use serde_json;
 use serde_json::value::Value;
 use std::fmt;
 use std::path::Path;
 use tracing::{debug, error, instrument, trace, warn};
 
-const FALLBACK_INFO_KEY: &str = "info";
+pub const FALLBACK_INFO_KEY: &str = "info";
+/// Inventory failures (e.g. timed out scripts) are reported under this key, by inventory.
+pub const DIAGNOSTICS_KEY: &str = "diagnostics";
 
 pub type BeaconInfos = serde_json::map::Map<String, Value>;
 
+/// Output lines after this one (the first) are parsed as a JSON object, whatever the script
+/// language.
+pub const JSON_OUTPUT_MARKER: &str = "#ipdis:json";
+/// Separates the levels of nested keys, e.g. `network.eth0.ipv4=...`.
+pub const KEY_SEPARATOR: char = '.';
+/// Separates the type hint from the key, e.g. `cpu_count:int=4`.
+const TYPE_HINT_SEPARATOR: char = ':';
+
 /// Expecting one ore more lines formatted according to https://docs.mender.io/3.0/client-installation/inventory
+///
+/// Keys can end with a type hint (`:str`, `:int`, `:float`, `:bool`, `:json`), values not
+/// matching it are kept as strings. Dotted keys build nested objects. If the first line is the
+/// JSON marker, the rest of the output is parsed as JSON.
 pub trait FromCmdOutput {
     fn from_cmd_output(lines: &str) -> Result<BeaconInfos, Report>;
+    /// Expecting a JSON object.
+    fn from_json_output(json: &str) -> Result<BeaconInfos, Report>;
 }
 
 impl FromCmdOutput for BeaconInfos {
     fn from_cmd_output(lines: &str) -> Result<BeaconInfos, Report> {
+        if lines.lines().next().map(str::trim) == Some(JSON_OUTPUT_MARKER) {
+            let json = lines.split_once('\n').map_or("", |(_, rest)| rest);
+            return Self::from_json_output(json);
+        }
         let separator = "=";
         let mut res = BeaconInfos::new();
         for line in lines.lines() {
-            if let Some((key, value)) = line.to_string().split_once(separator) {
-                match res.get_mut(&key.to_string()) {
-                    None => {
-                        res.insert(key.into(), Value::String(value.to_string()));
-                    }
-                    Some(previous_value) => {
-                        match previous_value {
-                            Value::String(previous_string) => {
-                                *previous_value = Value::Array(vec![
-                                    Value::String(previous_string.to_string()),
-                                    Value::String(value.to_string()),
-                                ]);
-                            }
-                            Value::Array(previous_array) => {
-                                previous_array.push(Value::String(value.to_string()));
-                                *previous_value = Value::Array(previous_array.to_vec());
-                            }
-                            _ => unreachable!(), // Only inserting String or Array!
-                        };
-                    }
-                };
+            if let Some((key, value)) = line.split_once(separator) {
+                let (key, value) = typed_value(key, value);
+                insert_nested(&mut res, key, value);
             }
         }
         Ok(res)
     }
+
+    fn from_json_output(json: &str) -> Result<BeaconInfos, Report> {
+        if json.trim().is_empty() {
+            return Ok(BeaconInfos::new());
+        }
+        match serde_json::from_str(json) {
+            Ok(Value::Object(infos)) => Ok(infos),
+            Ok(_) => Err(eyre!("JSON output is not an object")),
+            Err(e) => Err(eyre!("invalid JSON output: {}", e)),
+        }
+    }
+}
+
+/// Strip the type hint from the key, and convert the value accordingly.
+fn typed_value<'a>(key: &'a str, value: &str) -> (&'a str, Value) {
+    let (key, hint) = match key.rsplit_once(TYPE_HINT_SEPARATOR) {
+        Some((k, h)) if ["str", "int", "float", "bool", "json"].contains(&h) => (k, h),
+        _ => return (key, Value::String(value.into())),
+    };
+    let typed = match hint {
+        "int" => value.trim().parse::<i64>().ok().map(Value::from),
+        "float" => value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number),
+        "bool" => value.trim().parse::<bool>().ok().map(Value::Bool),
+        "json" => serde_json::from_str(value).ok(),
+        _ => Some(Value::String(value.into())),
+    };
+    match typed {
+        Some(v) => (key, v),
+        None => {
+            warn!(
+                key,
+                hint, value, "Value not matching the type hint, kept as string."
+            );
+            (key, Value::String(value.into()))
+        }
+    }
+}
+
+/// Insert the value in the object nested under the dotted key, creating the missing levels.
+/// Levels already holding something else than an object are not replaced: the rest of the key is
+/// kept dotted. Repeated keys are collected in an array.
+fn insert_nested(infos: &mut BeaconInfos, key: &str, value: Value) {
+    let mut object = infos;
+    let mut key = key;
+    loop {
+        let (parent, child) = match key.split_once(KEY_SEPARATOR) {
+            Some((p, c))
+                if !p.is_empty()
+                    && !c.is_empty()
+                    && matches!(object.get(p), None | Some(Value::Object(_))) =>
+            {
+                (p, c)
+            }
+            _ => break,
+        };
+        object = match object
+            .entry(parent)
+            .or_insert_with(|| Value::Object(BeaconInfos::new()))
+        {
+            Value::Object(o) => o,
+            _ => unreachable!(), // checked above
+        };
+        key = child;
+    }
+    match object.get_mut(key) {
+        None => {
+            object.insert(key.into(), value);
+        }
+        Some(Value::Array(previous_array)) => previous_array.push(value),
+        Some(previous_value) => {
+            *previous_value = Value::Array(vec![previous_value.take(), value]);
+        }
+    };
+}
+
+/// Merge `other` into `infos`: nested objects are merged, other values are overridden.
+pub fn merge_infos(infos: &mut BeaconInfos, other: BeaconInfos) {
+    for (key, value) in other {
+        match (infos.get_mut(&key), value) {
+            (Some(Value::Object(object)), Value::Object(other_object)) => {
+                merge_infos(object, other_object)
+            }
+            (_, value) => {
+                infos.insert(key, value);
+            }
+        }
+    }
 }
 
 /// Message returned to the scanner (JSON formatted).
@@ -78,8 +172,10 @@ impl From<String> for Answer {
 }
 
 impl Answer {
-    pub fn pretty_format(&self) -> String {
-        let json = match serde_json::from_slice(&self.0) {
+    /// Deserialized payload. If the payload is not a JSON object it is reported as a string
+    /// under the fallback key.
+    pub fn infos(&self) -> BeaconInfos {
+        match serde_json::from_slice(&self.0) {
             Ok(p) => p,
             Err(e) => {
                 warn!(?e, "Error deserializing Answer payload.");
@@ -87,20 +183,15 @@ impl Answer {
                 info.insert(FALLBACK_INFO_KEY.into(), safe_format_bytes(&self.0).into());
                 info
             }
-        };
-        serde_json::to_string_pretty(&json).expect("Error serializing JSON")
+        }
+    }
+
+    pub fn pretty_format(&self) -> String {
+        serde_json::to_string_pretty(&self.infos()).expect("Error serializing JSON")
     }
 
     fn safe_format(&self) -> String {
-        let res = match serde_json::from_slice(&self.0) {
-            Ok(p) => p,
-            Err(e) => {
-                warn!(?e, "Error deserializing Answer payload.");
-                let mut info = BeaconInfos::new();
-                info.insert(FALLBACK_INFO_KEY.into(), safe_format_bytes(&self.0).into());
-                info
-            }
-        };
+        let res = self.infos();
         match serde_json::to_string(&res) {
             Ok(f) => f,
             Err(e) => {
@@ -126,40 +217,46 @@ fn get_answer_hostname_and_files<P>(
 where
     P: AsRef<Path>,
 {
-    let mut hostname_answer = get_internal_inventory_answer(hostname_inventory);
-    debug!(?hostname_answer);
-    let mut inventory_answer = get_inventory_files_answer(inventory_files);
-    debug!(?inventory_answer);
-    let answer = Answer::from(serde_json::to_string(&join_answers(
-        &mut hostname_answer,
-        &mut inventory_answer,
-    ))?);
-    Ok(answer)
-}
-
-fn join_answers(first: &mut BeaconInfos, second: &mut BeaconInfos) -> BeaconInfos {
-    first.append(second);
-    first.clone()
+    let hostname_output = hostname_inventory.execute();
+    debug!(?hostname_output);
+    let mut outputs = vec![hostname_output];
+    outputs.append(&mut get_inventory_files_outputs(inventory_files));
+    let infos = join_outputs(outputs);
+    debug!(?infos);
+    Ok(Answer::from(serde_json::to_string(&infos)?))
 }
 
-#[instrument(skip(inventory))]
-fn get_internal_inventory_answer(inventory: InternalInventory) -> BeaconInfos {
-    inventory.execute().output
+/// Merge inventory outputs, later outputs overriding earlier ones (nested objects are merged
+/// too). Failures are collected in an object under the diagnostics key, omitted if there are
+/// none.
+pub fn join_outputs<I>(outputs: I) -> BeaconInfos
+where
+    I: IntoIterator<Item = InventoryOutput>,
+{
+    let mut res = BeaconInfos::new();
+    let mut diagnostics = BeaconInfos::new();
+    for mut output in outputs {
+        merge_infos(&mut res, output.output);
+        diagnostics.append(&mut output.diagnostics);
+    }
+    if !diagnostics.is_empty() {
+        res.insert(DIAGNOSTICS_KEY.into(), Value::Object(diagnostics));
+    }
+    res
 }
 
 #[instrument(skip(inventory_file_paths))]
-fn get_inventory_files_answer<P>(inventory_file_paths: &[P]) -> BeaconInfos
+fn get_inventory_files_outputs<P>(inventory_file_paths: &[P]) -> Vec<InventoryOutput>
 where
     P: AsRef<Path>,
 {
-    let mut res = BeaconInfos::new();
+    let mut res = Vec::new();
     for inventory_path in inventory_file_paths {
         let inventory = InventoryFile::from(inventory_path.as_ref());
         trace!(?inventory, "Executing inventory file.");
-        let mut inventory_result = inventory.execute();
+        let inventory_result = inventory.execute();
         trace!(?inventory_result, ?inventory, "Inventory file executed.");
-        res = join_answers(&mut res, &mut inventory_result.output);
-        trace!(?res, "Updated inventory info.");
+        res.push(inventory_result);
     }
     res
 }
@@ -221,6 +318,68 @@ mod test {
         );
     }
 
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_from_cmd_output_typed_nested() {
+        let output = "cpu_count:int=4\nload:float=0.5\nup:bool=true\nbad:int=four\n\
+            tags:json=[\"a\",1]\nversion:str=1.0\nkey:other=x\n\
+            net.eth0.ipv4=10.0.0.1\nnet.eth0.ipv4=10.0.0.2\nnet.eth0.up:bool=false\n\
+            os=linux\nos.id=debian\n.hidden.=1";
+        assert_eq!(
+            Value::Object(BeaconInfos::from_cmd_output(output).unwrap()),
+            serde_json::json!({
+                "cpu_count": 4,
+                "load": 0.5,
+                "up": true,
+                "bad": "four",
+                "tags": ["a", 1],
+                "version": "1.0",
+                "key:other": "x",
+                "net": {"eth0": {"ipv4": ["10.0.0.1", "10.0.0.2"], "up": false}},
+                "os": "linux",
+                "os.id": "debian",
+                ".hidden.": "1",
+            })
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_from_json_output() {
+        let output = "#ipdis:json\n{\"disks\": [{\"name\": \"sda\", \"size\": 512}]}";
+        assert_eq!(
+            BeaconInfos::from_cmd_output(output).unwrap()["disks"][0]["size"],
+            512
+        );
+        assert!(BeaconInfos::from_json_output("").unwrap().is_empty());
+        assert_eq!(
+            BeaconInfos::from_json_output("[1]")
+                .unwrap_err()
+                .to_string(),
+            "JSON output is not an object"
+        );
+        assert!(BeaconInfos::from_json_output("{")
+            .unwrap_err()
+            .to_string()
+            .starts_with("invalid JSON output"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_join_outputs_nested() {
+        let output = |json: Value| InventoryOutput {
+            output: json.as_object().unwrap().clone(),
+            ..InventoryOutput::default()
+        };
+        assert_eq!(
+            Value::Object(join_outputs(vec![
+                output(serde_json::json!({"net": {"eth0": 1, "eth1": 2}, "os": "a"})),
+                output(serde_json::json!({"net": {"eth1": 3}, "os": {"id": "b"}})),
+            ])),
+            serde_json::json!({"net": {"eth0": 1, "eth1": 3}, "os": {"id": "b"}})
+        );
+    }
+
     fn write_inventory_file(filename: &str, content: &str) -> PathBuf {
         let datadir = std::env::temp_dir()
             .as_path()
@@ -239,7 +398,7 @@ mod test {
         let mut permissions = metadata.permissions();
         permissions.set_mode(0o755);
         file.set_permissions(permissions).unwrap();
-        path.into()
+        path
     }
 
     #[test]
@@ -266,17 +425,22 @@ mod test {
             echo_multiple_lines_path,
             echo_nothing_path,
             wrong_format_path,
-            return_error_path,
-            empty_file_path,
+            return_error_path.clone(),
+            empty_file_path.clone(),
             nonexisting_path,
         ];
-        let expected = r#"{"foo":["bar","baz"],"foo1":"1","foo2":"2","foo3 ":" 3","hostname":"dummy-hostname"}"#;
+        let expected = format!(
+            r#"{{"diagnostics":{{"{}":"failed executing: Exec format error (os error 8)","{}":"failed with exit status: 1","non-existing-file":"failed executing: No such file or directory (os error 2)"}},"foo":["bar","baz"],"foo1":"1","foo2":"2","foo3 ":" 3","hostname":"dummy-hostname"}}"#,
+            empty_file_path.display(),
+            return_error_path.display()
+        );
         assert_eq!(
             std::str::from_utf8(
                 &get_answer_hostname_and_files(
                     InternalInventory {
                         key: "hostname".to_string(),
-                        source: Box::new(|| "dummy-hostname".to_string())
+                        source: Box::new(|| "dummy-hostname".to_string()),
+                        ..InternalInventory::default()
                     }, // mock hostname
                     inventory_files.as_slice()
                 )
@@ -0,0 +1,597 @@
+//! Audit trail of the scanner requests, and alerts on sources sending invalid signatures.
+use crate::conf::ServerConfig;
+use crossbeam_channel::{Receiver, Sender};
+use lru::LruCache;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::dispatcher::{self, Dispatch};
+use tracing::{error, info, warn};
+use tracing_subscriber::prelude::*;
+
+pub const AUDIT_MAX_SIZE_DEFAULT: u64 = 10 * 1024 * 1024;
+pub const AUDIT_ROTATED_FILES_DEFAULT: usize = 5;
+/// Records waiting for the writer thread, more are dropped until it catches up.
+const AUDIT_QUEUE_SIZE: usize = 1024;
+pub const ALERT_THRESHOLD_DEFAULT: u32 = 20;
+pub const ALERT_PERIOD_DEFAULT: Duration = Duration::from_secs(60);
+/// Journal fields of the audit records are the record keys with this prefix, e.g. `IPDIS_SOURCE`.
+const JOURNALD_FIELD_PREFIX: &str = "IPDIS";
+const JOURNALD_IDENTIFIER: &str = "ipdisserver-audit";
+
+/// Where the audit records are written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditLog {
+    /// JSON lines. The file is rotated when larger than `max_size` bytes, keeping
+    /// `rotated_files` previous files (`.1` being the most recent).
+    File {
+        path: PathBuf,
+        max_size: u64,
+        rotated_files: usize,
+    },
+    /// Journal entries, with a field for each record key.
+    Journald,
+}
+
+impl AuditLog {
+    pub fn file(path: PathBuf) -> Self {
+        Self::File {
+            path,
+            max_size: AUDIT_MAX_SIZE_DEFAULT,
+            rotated_files: AUDIT_ROTATED_FILES_DEFAULT,
+        }
+    }
+}
+
+/// Result of the signature check of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureCheck {
+    /// The request was dropped before checking it.
+    Unchecked,
+    Valid,
+    /// Wrong signature, missing or failed authentication.
+    Invalid,
+    /// The request could not be parsed.
+    Malformed,
+}
+
+impl fmt::Display for SignatureCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Unchecked => "unchecked",
+            Self::Valid => "valid",
+            Self::Invalid => "invalid",
+            Self::Malformed => "malformed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A request, and what was done with it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditRecord {
+    pub source: SocketAddr,
+    /// Sent by the scanner, none for authenticated and malformed requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    pub signature_check: SignatureCheck,
+    /// `answered`, or why the request was not answered, e.g. `source_rate_limited`.
+    pub outcome: &'static str,
+    /// Why the request is not valid.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Bytes of the answer sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub answer_size: Option<usize>,
+}
+
+impl AuditRecord {
+    pub fn new(
+        source: SocketAddr,
+        signature: Option<String>,
+        signature_check: SignatureCheck,
+        outcome: &'static str,
+    ) -> Self {
+        Self {
+            source,
+            signature,
+            signature_check,
+            outcome,
+            error: None,
+            answer_size: None,
+        }
+    }
+}
+
+/// Queues the audit records for a writer thread, started when a log is first configured, so
+/// that recording never waits for the log. Records are dropped, and counted, when the queue is
+/// full.
+#[derive(Debug, Default)]
+pub struct Audit {
+    /// Log the records are written to, none until configured.
+    log: Mutex<Option<AuditLog>>,
+    writer: OnceLock<Sender<AuditMessage>>,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug)]
+enum AuditMessage {
+    Record(SystemTime, AuditRecord),
+    Configure(Option<AuditSink>),
+    /// Answered when the previous messages are handled.
+    Flush(Sender<()>),
+}
+
+impl Audit {
+    fn log(&self) -> MutexGuard<'_, Option<AuditLog>> {
+        self.log.lock().expect("Poisoned audit lock")
+    }
+
+    fn writer(&self) -> &Sender<AuditMessage> {
+        self.writer.get_or_init(|| {
+            let (writer, messages) = crossbeam_channel::bounded(AUDIT_QUEUE_SIZE);
+            thread::spawn(move || write_forever(messages));
+            writer
+        })
+    }
+
+    /// Write to `log` from now on, opening it again only if changed. On errors the current log is
+    /// kept.
+    pub fn configure(&self, log: Option<&AuditLog>) -> io::Result<()> {
+        let mut current = self.log();
+        if current.as_ref() == log {
+            return Ok(());
+        }
+        let sink = log.map(AuditSink::open).transpose()?;
+        self.writer()
+            .send(AuditMessage::Configure(sink))
+            .expect("Audit writer thread stopped");
+        *current = log.cloned();
+        info!(?log, "Audit log configured.");
+        Ok(())
+    }
+
+    /// Queue the record, built only if an audit log is configured.
+    pub fn record<F>(&self, record: F)
+    where
+        F: FnOnce() -> AuditRecord,
+    {
+        if self.log().is_none() {
+            return;
+        }
+        let message = AuditMessage::Record(SystemTime::now(), record());
+        if self.writer().try_send(message).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records dropped since the start, because the writer thread was late.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Wait for the queued records to be written.
+    pub fn flush(&self) {
+        if let Some(writer) = self.writer.get() {
+            let (done, flushed) = crossbeam_channel::bounded(1);
+            if writer.send(AuditMessage::Flush(done)).is_ok() {
+                let _ = flushed.recv();
+            }
+        }
+    }
+}
+
+/// Write the queued records, until the `Audit` is dropped.
+fn write_forever(messages: Receiver<AuditMessage>) {
+    let mut sink: Option<AuditSink> = None;
+    for message in messages {
+        match message {
+            AuditMessage::Record(time, record) => {
+                if let Some(sink) = &mut sink {
+                    sink.write(time, &record);
+                }
+            }
+            AuditMessage::Configure(new) => sink = new,
+            AuditMessage::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum AuditSink {
+    File(AuditFile),
+    /// Dispatches only to journald, whatever the logging settings.
+    Journald(Dispatch),
+}
+
+impl AuditSink {
+    fn open(log: &AuditLog) -> io::Result<Self> {
+        match log {
+            AuditLog::File {
+                path,
+                max_size,
+                rotated_files,
+            } => Ok(Self::File(AuditFile::open(
+                path,
+                *max_size,
+                *rotated_files,
+            )?)),
+            AuditLog::Journald => {
+                let layer = tracing_journald::layer()?
+                    .with_field_prefix(Some(JOURNALD_FIELD_PREFIX.into()))
+                    .with_syslog_identifier(JOURNALD_IDENTIFIER.into());
+                Ok(Self::Journald(Dispatch::new(
+                    tracing_subscriber::registry().with(layer),
+                )))
+            }
+        }
+    }
+
+    fn write(&mut self, time: SystemTime, record: &AuditRecord) {
+        match self {
+            Self::File(file) => file.write(time, record),
+            Self::Journald(dispatch) => dispatcher::with_default(dispatch, || {
+                info!(
+                    source = %record.source,
+                    signature = record.signature.as_deref(),
+                    signature_check = %record.signature_check,
+                    outcome = record.outcome,
+                    error = record.error.as_deref(),
+                    answer_size = record.answer_size,
+                    "Scanner request."
+                )
+            }),
+        }
+    }
+}
+
+/// A record in the audit file, with the time in seconds since the epoch.
+#[derive(Serialize)]
+struct TimedRecord<'a> {
+    time: f64,
+    #[serde(flatten)]
+    record: &'a AuditRecord,
+}
+
+#[derive(Debug)]
+struct AuditFile {
+    path: PathBuf,
+    max_size: u64,
+    rotated_files: usize,
+    file: File,
+    size: u64,
+    /// Set after a failed write, so that failures are logged once until a write succeeds.
+    failing: bool,
+}
+
+impl AuditFile {
+    fn open(path: &Path, max_size: u64, rotated_files: usize) -> io::Result<Self> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.into(),
+            max_size,
+            rotated_files,
+            file,
+            size,
+            failing: false,
+        })
+    }
+
+    fn write(&mut self, time: SystemTime, record: &AuditRecord) {
+        match self.try_write(time, record) {
+            Ok(()) => self.failing = false,
+            Err(error) if !self.failing => {
+                error!(path = ?self.path, %error, "Failed writing the audit log.");
+                self.failing = true;
+            }
+            Err(_) => (),
+        }
+    }
+
+    fn try_write(&mut self, time: SystemTime, record: &AuditRecord) -> io::Result<()> {
+        let time = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let record = TimedRecord {
+            time: time.as_millis() as f64 / 1000.0,
+            record,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift the previous files, dropping the oldest, and start a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..self.rotated_files).rev() {
+            let previous = rotated_path(&self.path, i);
+            if previous.exists() {
+                fs::rename(previous, rotated_path(&self.path, i + 1))?;
+            }
+        }
+        match self.rotated_files {
+            0 => fs::remove_file(&self.path)?,
+            _ => fs::rename(&self.path, rotated_path(&self.path, 1))?,
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// `path` with the `.n` suffix.
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    rotated.into()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertConfig {
+    /// Invalid signatures from a source within the period raising an alert, zero disables the
+    /// alerts.
+    pub threshold: u32,
+    pub period: Duration,
+    /// Executed on every alert.
+    pub hook: Option<PathBuf>,
+    /// Sources tracked, the least recently seen are forgotten.
+    pub max_sources: usize,
+}
+
+impl From<&ServerConfig> for AlertConfig {
+    fn from(conf: &ServerConfig) -> Self {
+        Self {
+            threshold: conf.alert_threshold,
+            period: conf.alert_period,
+            hook: conf.alert_hook.clone(),
+            max_sources: conf.rate_limited_sources,
+        }
+    }
+}
+
+/// Counts the invalid signatures of each source, and raises an alert when a source sends too
+/// many of them.
+#[derive(Debug)]
+pub struct SignatureAlerts(Mutex<Offenders>);
+
+#[derive(Debug)]
+struct Offenders {
+    conf: AlertConfig,
+    sources: LruCache<IpAddr, Offender>,
+}
+
+/// Invalid signatures from a source since `since`.
+#[derive(Debug, Clone, Copy)]
+struct Offender {
+    since: Instant,
+    count: u32,
+}
+
+impl SignatureAlerts {
+    pub fn new(conf: AlertConfig) -> Self {
+        let sources = LruCache::new(max_sources(&conf));
+        Self(Mutex::new(Offenders { conf, sources }))
+    }
+
+    fn offenders(&self) -> MutexGuard<'_, Offenders> {
+        self.0.lock().expect("Poisoned alerts lock")
+    }
+
+    pub fn update(&self, conf: AlertConfig) {
+        let mut offenders = self.offenders();
+        offenders.sources.resize(max_sources(&conf));
+        offenders.conf = conf;
+    }
+
+    /// Count an invalid signature from the IP. When the source reaches the threshold within the
+    /// period from its first invalid signature, the alert is logged and the hook executed: at most
+    /// once per period for each source. Return whether the alert was raised.
+    pub fn invalid_signature(&self, ip: IpAddr, now: Instant) -> bool {
+        let (count, period, hook) = {
+            let mut offenders = self.offenders();
+            let Offenders { conf, sources } = &mut *offenders;
+            if conf.threshold == 0 {
+                return false;
+            }
+            let offender = sources.get_or_insert_mut(ip, || Offender {
+                since: now,
+                count: 0,
+            });
+            if now.saturating_duration_since(offender.since) >= conf.period {
+                *offender = Offender {
+                    since: now,
+                    count: 0,
+                };
+            }
+            offender.count += 1;
+            if offender.count != conf.threshold {
+                return false;
+            }
+            (offender.count, conf.period, conf.hook.clone())
+        };
+        warn!(%ip, invalid_signatures = count, ?period, "Source sending invalid signatures.");
+        if let Some(hook) = hook {
+            run_hook(hook, ip, count, period);
+        }
+        true
+    }
+}
+
+fn max_sources(conf: &AlertConfig) -> NonZeroUsize {
+    NonZeroUsize::new(conf.max_sources).unwrap_or(NonZeroUsize::MIN)
+}
+
+/// Execute the hook without waiting for it to complete. The alert is given in the environment:
+/// `IPDIS_SOURCE`, `IPDIS_INVALID_SIGNATURES` and `IPDIS_PERIOD` (in seconds).
+fn run_hook(hook: PathBuf, ip: IpAddr, count: u32, period: Duration) {
+    let child = Command::new(&hook)
+        .env("IPDIS_SOURCE", ip.to_string())
+        .env("IPDIS_INVALID_SIGNATURES", count.to_string())
+        .env("IPDIS_PERIOD", period.as_secs().to_string())
+        .stdin(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(c) => c,
+        Err(error) => {
+            error!(?hook, %error, "Failed executing the alert hook.");
+            return;
+        }
+    };
+    thread::spawn(move || match child.wait() {
+        Ok(status) if status.success() => (),
+        Ok(status) => warn!(?hook, %status, "Alert hook failed."),
+        Err(error) => warn!(?hook, %error, "Failed waiting for the alert hook."),
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn record(outcome: &'static str) -> AuditRecord {
+        AuditRecord::new(
+            SocketAddr::from(([192, 0, 2, 7], 5000)),
+            Some("ipdisbeacon".into()),
+            SignatureCheck::Valid,
+            outcome,
+        )
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_audit_file() {
+        let dir = std::env::temp_dir().join("rust-ipdisserver-test-audit");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let audit = Audit::default();
+        audit.record(|| panic!("not configured, no record built"));
+        let line_size = serde_json::to_vec(&TimedRecord {
+            time: 1.0,
+            record: &record("answered"),
+        })
+        .unwrap()
+        .len() as u64;
+        let log = AuditLog::File {
+            path: path.clone(),
+            max_size: 2 * line_size + 40, // two lines, whatever the time
+            rotated_files: 2,
+        };
+        audit.configure(Some(&log)).unwrap();
+        for outcome in [
+            "answered",
+            "invalid",
+            "queue_full",
+            "amplification",
+            "answered",
+        ] {
+            audit.record(|| record(outcome));
+        }
+        audit.flush();
+        let lines = |path: &Path| -> Vec<serde_json::Value> {
+            fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+        let current = lines(&path);
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0]["outcome"], "answered");
+        assert_eq!(current[0]["source"], "192.0.2.7:5000");
+        assert_eq!(current[0]["signature_check"], "valid");
+        assert!(current[0]["time"].as_f64().unwrap() > 0.0);
+        assert!(current[0].get("answer_size").is_none());
+        assert_eq!(lines(&rotated_path(&path, 1))[0]["outcome"], "queue_full");
+        assert_eq!(lines(&rotated_path(&path, 2))[1]["outcome"], "invalid");
+        assert!(!rotated_path(&path, 3).exists());
+        assert_eq!(audit.dropped(), 0);
+        audit.configure(None).unwrap();
+        audit.record(|| panic!("not configured, no record built"));
+        assert!(audit
+            .configure(Some(&AuditLog::file(dir.join("missing/audit.log"))))
+            .is_err());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_audit_queue_full() {
+        let (writer, _messages) = crossbeam_channel::bounded(2); // no writer thread
+        let audit = Audit {
+            log: Mutex::new(Some(AuditLog::Journald)),
+            writer: OnceLock::from(writer),
+            ..Audit::default()
+        };
+        for _ in 0..5 {
+            audit.record(|| record("queue_full"));
+        }
+        assert_eq!(audit.dropped(), 3);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_signature_alerts() {
+        let marker = std::env::temp_dir().join("rust-ipdisserver-test-alert-marker");
+        let _ = fs::remove_file(&marker);
+        let hook = std::env::temp_dir().join("rust-ipdisserver-test-alert-hook");
+        let script = format!(
+            "#!/bin/sh\necho \"$IPDIS_SOURCE $IPDIS_INVALID_SIGNATURES $IPDIS_PERIOD\" > {}",
+            marker.display()
+        );
+        fs::write(&hook, script).unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+        let conf = AlertConfig {
+            threshold: 3,
+            period: Duration::from_secs(60),
+            hook: Some(hook),
+            max_sources: 10,
+        };
+        let alerts = SignatureAlerts::new(conf.clone());
+        let (ip, other) = ("192.0.2.7".parse().unwrap(), "192.0.2.8".parse().unwrap());
+        let start = Instant::now();
+        let raised: Vec<bool> = (0..5)
+            .map(|i| alerts.invalid_signature(ip, start + Duration::from_secs(i)))
+            .collect();
+        assert_eq!(raised, [false, false, true, false, false]); // once per period
+        assert!(!alerts.invalid_signature(other, start));
+        assert!(logs_contain("Source sending invalid signatures."));
+        let later = start + Duration::from_secs(61);
+        assert!(!alerts.invalid_signature(ip, later));
+        assert!(!alerts.invalid_signature(ip, later));
+        assert!(alerts.invalid_signature(ip, later));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !marker.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(100)); // written
+        assert_eq!(fs::read_to_string(&marker).unwrap(), "192.0.2.7 3 60\n");
+        alerts.update(AlertConfig {
+            threshold: 0,
+            ..conf
+        });
+        assert!(!(0..5).any(|_| alerts.invalid_signature(other, start)));
+    }
+}
@@ -0,0 +1,297 @@
+use crate::protocol::Header;
+use bytes::{BufMut, Bytes, BytesMut};
+use color_eyre::eyre::{eyre, Report};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::{info, trace};
+
+pub const NONCE_SIZE: usize = 16;
+const MAC_SIZE: usize = 32;
+const TIMESTAMP_SIZE: usize = 8;
+const REQUEST_SIZE: usize = NONCE_SIZE + TIMESTAMP_SIZE + MAC_SIZE;
+const ANSWER_PREFIX_SIZE: usize = NONCE_SIZE + MAC_SIZE;
+pub const REPLAY_WINDOW_DEFAULT: Duration = Duration::from_secs(30);
+
+type HmacSha256 = Hmac<Sha256>;
+pub type Nonce = [u8; NONCE_SIZE];
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AuthError {
+    #[error("malformed authenticated message")]
+    Malformed,
+    #[error("HMAC verification failed")]
+    BadMac,
+    #[error("timestamp outside of the replay window")]
+    Expired,
+    #[error("nonce already used")]
+    Replayed,
+}
+
+/// Secret shared between scanners and servers, used as HMAC-SHA256 key.
+#[derive(Clone, PartialEq)]
+pub struct SharedKey(Bytes);
+
+impl fmt::Debug for SharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedKey(<redacted>)")
+    }
+}
+
+impl From<&[u8]> for SharedKey {
+    fn from(bytes: &[u8]) -> Self {
+        Self(Bytes::copy_from_slice(bytes))
+    }
+}
+
+impl SharedKey {
+    /// Read the key from a file, trailing new lines are ignored.
+    pub fn from_file(path: &Path) -> Result<Self, Report> {
+        info!(?path, "Reading shared key from file.");
+        let content = std::fs::read(path)?;
+        let end = content
+            .iter()
+            .rposition(|b| !matches!(b, b'\n' | b'\r'))
+            .map_or(0, |i| i + 1);
+        if end == 0 {
+            return Err(eyre!("Empty shared key file {:?}", path));
+        }
+        Ok(Self::from(&content[..end]))
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC accepts keys of any size");
+        for part in parts {
+            mac.update(part);
+        }
+        mac
+    }
+}
+
+/// Request body sent by scanners in place of a bare signature: a random nonce and a timestamp,
+/// authenticated with the shared key together with the protocol header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthenticatedRequest {
+    pub nonce: Nonce,
+    /// Seconds since UNIX epoch.
+    pub timestamp: u64,
+}
+
+impl AuthenticatedRequest {
+    pub fn new() -> Result<Self, Report> {
+        let mut nonce = [0; NONCE_SIZE];
+        getrandom::getrandom(&mut nonce).map_err(|e| eyre!("Nonce generation failed: {}", e))?;
+        Ok(Self {
+            nonce,
+            timestamp: unix_timestamp(SystemTime::now()),
+        })
+    }
+
+    pub fn to_bytes(&self, header: &Header, key: &SharedKey) -> Bytes {
+        let timestamp = self.timestamp.to_be_bytes();
+        let mac = key.mac(&[&header.to_bytes(), &self.nonce, &timestamp]);
+        let mut buf = BytesMut::with_capacity(REQUEST_SIZE);
+        buf.put_slice(&self.nonce);
+        buf.put_slice(&timestamp);
+        buf.put_slice(&mac.finalize().into_bytes());
+        buf.freeze()
+    }
+
+    /// Parse the request body and verify its HMAC.
+    pub fn from_bytes(header: &Header, bytes: &[u8], key: &SharedKey) -> Result<Self, AuthError> {
+        if bytes.len() != REQUEST_SIZE {
+            return Err(AuthError::Malformed);
+        }
+        let (nonce, rest) = bytes.split_at(NONCE_SIZE);
+        let (timestamp, mac) = rest.split_at(TIMESTAMP_SIZE);
+        key.mac(&[&header.to_bytes(), nonce, timestamp])
+            .verify_slice(mac)
+            .map_err(|_| AuthError::BadMac)?;
+        Ok(Self {
+            nonce: nonce.try_into().expect("Checked size"),
+            timestamp: u64::from_be_bytes(timestamp.try_into().expect("Checked size")),
+        })
+    }
+}
+
+/// Authenticate the payload of an answer, binding it to its header and to the nonce of the
+/// request.
+pub fn sign_answer(key: &SharedKey, header: &Header, nonce: &Nonce, payload: &[u8]) -> Bytes {
+    let mac = key.mac(&[&header.to_bytes(), nonce, payload]);
+    let mut buf = BytesMut::with_capacity(ANSWER_PREFIX_SIZE + payload.len());
+    buf.put_slice(nonce);
+    buf.put_slice(&mac.finalize().into_bytes());
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+/// Return nonce and payload of an authenticated answer body, after verifying its HMAC.
+pub fn verify_answer(
+    key: &SharedKey,
+    header: &Header,
+    bytes: &[u8],
+) -> Result<(Nonce, Bytes), AuthError> {
+    let (nonce, mac, payload) = split_answer(bytes)?;
+    key.mac(&[&header.to_bytes(), &nonce, &payload])
+        .verify_slice(mac)
+        .map_err(|_| AuthError::BadMac)?;
+    Ok((nonce, payload))
+}
+
+/// Return nonce and payload of an authenticated answer body, without verifying it.
+pub fn open_answer_unverified(bytes: &[u8]) -> Result<(Nonce, Bytes), AuthError> {
+    let (nonce, _mac, payload) = split_answer(bytes)?;
+    Ok((nonce, payload))
+}
+
+fn split_answer(bytes: &[u8]) -> Result<(Nonce, &[u8], Bytes), AuthError> {
+    if bytes.len() < ANSWER_PREFIX_SIZE {
+        return Err(AuthError::Malformed);
+    }
+    let (nonce, rest) = bytes.split_at(NONCE_SIZE);
+    let (mac, payload) = rest.split_at(MAC_SIZE);
+    Ok((
+        nonce.try_into().expect("Checked size"),
+        mac,
+        Bytes::copy_from_slice(payload),
+    ))
+}
+
+/// Reject requests with a timestamp too far from the local clock, or with a nonce already seen
+/// within the window.
+#[derive(Debug, Clone)]
+pub struct ReplayGuard {
+    window: Duration,
+    seen: HashMap<Nonce, u64>,
+}
+
+impl ReplayGuard {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Change the window, keeping the nonces already seen.
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    pub fn check(
+        &mut self,
+        request: &AuthenticatedRequest,
+        now: SystemTime,
+    ) -> Result<(), AuthError> {
+        let now = unix_timestamp(now);
+        let window = self.window.as_secs();
+        if now.abs_diff(request.timestamp) > window {
+            return Err(AuthError::Expired);
+        }
+        self.seen
+            .retain(|_, timestamp| now.abs_diff(*timestamp) <= window);
+        if self.seen.insert(request.nonce, request.timestamp).is_some() {
+            return Err(AuthError::Replayed);
+        }
+        trace!(tracked = self.seen.len(), "Nonce accepted.");
+        Ok(())
+    }
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::{Flags, MessageType};
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_request_roundtrip() {
+        let key = SharedKey::from("secret".as_bytes());
+        let header = Header::new(MessageType::Request, Flags::AUTHENTICATED, 1);
+        let request = AuthenticatedRequest::new().unwrap();
+        let bytes = request.to_bytes(&header, &key);
+        assert_eq!(
+            AuthenticatedRequest::from_bytes(&header, &bytes, &key).unwrap(),
+            request
+        );
+        let other_key = SharedKey::from("other".as_bytes());
+        assert_eq!(
+            AuthenticatedRequest::from_bytes(&header, &bytes, &other_key),
+            Err(AuthError::BadMac)
+        );
+        let other_header = Header::new(MessageType::Request, Flags::AUTHENTICATED, 2);
+        assert_eq!(
+            AuthenticatedRequest::from_bytes(&other_header, &bytes, &key),
+            Err(AuthError::BadMac)
+        );
+        assert_eq!(
+            AuthenticatedRequest::from_bytes(&header, b"ipdisbeacon", &key),
+            Err(AuthError::Malformed)
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_answer_roundtrip() {
+        let key = SharedKey::from("secret".as_bytes());
+        let header = Header::new(MessageType::Answer, Flags::AUTHENTICATED, 1);
+        let nonce = [7; NONCE_SIZE];
+        let signed = sign_answer(&key, &header, &nonce, br#"{"hostname":"h"}"#);
+        assert_eq!(
+            verify_answer(&key, &header, &signed).unwrap(),
+            (nonce, Bytes::from(r#"{"hostname":"h"}"#))
+        );
+        let mut tampered = signed.to_vec();
+        *tampered.last_mut().unwrap() = b']';
+        assert_eq!(
+            verify_answer(&key, &header, &tampered),
+            Err(AuthError::BadMac)
+        );
+        assert_eq!(
+            open_answer_unverified(&tampered).unwrap().1,
+            Bytes::from(r#"{"hostname":"h"]"#)
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_replay_guard() {
+        let mut guard = ReplayGuard::new(Duration::from_secs(30));
+        let request = AuthenticatedRequest::new().unwrap();
+        let now = SystemTime::now();
+        assert_eq!(guard.check(&request, now), Ok(()));
+        assert_eq!(guard.check(&request, now), Err(AuthError::Replayed));
+        assert_eq!(
+            guard.check(&request, now + Duration::from_secs(31)),
+            Err(AuthError::Expired)
+        );
+        let old_request = AuthenticatedRequest {
+            timestamp: request.timestamp - 60,
+            ..AuthenticatedRequest::new().unwrap()
+        };
+        assert_eq!(guard.check(&old_request, now), Err(AuthError::Expired));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_shared_key_from_file() {
+        let path = std::env::temp_dir().join("rust-ipdisserver-test-auth-key");
+        std::fs::write(&path, "secret\n").unwrap();
+        assert_eq!(
+            SharedKey::from_file(&path).unwrap(),
+            SharedKey::from("secret".as_bytes())
+        );
+        std::fs::write(&path, "\n").unwrap();
+        assert!(SharedKey::from_file(&path).is_err());
+    }
+}
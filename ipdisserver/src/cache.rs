@@ -0,0 +1,281 @@
+use crate::addresses::AddressesInventory;
+use crate::answers::{join_outputs, merge_infos, Answer, BeaconInfos};
+use crate::conf::ServerConfig;
+use crate::identity::DeviceIdInventory;
+use crate::inventory::{
+    ExecuteInventory, InternalInventory, InventoryDir, InventoryFile, InventoryOutput,
+};
+use crate::providers::NativeInventory;
+use color_eyre::eyre::Report;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument, trace};
+
+/// Upper bound of the refresher sleep, so that it notices when it must stop or the cache is
+/// replaced.
+const REFRESH_POLL_PERIOD: Duration = Duration::from_secs(1);
+
+type BoxedInventory = Box<dyn ExecuteInventory + Send + Sync>;
+
+struct CacheEntry {
+    inventory: BoxedInventory,
+    ttl: Duration,
+    /// Last output, with the time it was produced.
+    cached: Mutex<Option<(InventoryOutput, Instant)>>,
+}
+
+impl CacheEntry {
+    fn new(inventory: BoxedInventory) -> Self {
+        Self {
+            ttl: inventory.cache_ttl(),
+            inventory,
+            cached: Mutex::new(None),
+        }
+    }
+
+    fn is_cached(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Expiry time of the cached output, None if there is no output yet.
+    fn expiry(&self) -> Option<Instant> {
+        let cached = self.cached.lock().expect("Poisoned cache lock");
+        cached.as_ref().map(|(_, refreshed)| *refreshed + self.ttl)
+    }
+
+    fn refresh(&self) -> InventoryOutput {
+        let output = self.inventory.execute();
+        *self.cached.lock().expect("Poisoned cache lock") = Some((output.clone(), Instant::now()));
+        output
+    }
+}
+
+/// Inventory outputs (the hostname, the device ID, the addresses, the providers, every inventory
+/// file, then the scripts of the inventory directories), reused for their TTL.
+///
+/// Answers are assembled from the cached outputs, even if stale: stale outputs are refreshed by
+/// the server refresher thread (see `refresh_wait`), so that answering never waits for an
+/// inventory script. Missing outputs and inventories with a zero TTL are executed while
+/// answering. The refresher rescans the inventory directories too.
+pub struct AnswerCache {
+    entries: Vec<Arc<CacheEntry>>,
+    dirs: Vec<InventoryDir>,
+    /// Scripts found in the inventory directories, in execution order.
+    discovered: RwLock<Vec<(InventoryFile, Arc<CacheEntry>)>>,
+}
+
+impl AnswerCache {
+    pub fn new(conf: &ServerConfig) -> Self {
+        let hostname = InternalInventory {
+            cache_ttl: conf.hostname_cache_ttl,
+            ..InternalInventory::default()
+        };
+        let device_id = DeviceIdInventory {
+            cache_ttl: conf.hostname_cache_ttl,
+            ..DeviceIdInventory::from(&conf.device_id)
+        };
+        let addresses = AddressesInventory {
+            cache_ttl: conf.hostname_cache_ttl,
+        };
+        let mut inventories: Vec<BoxedInventory> =
+            vec![Box::new(hostname), Box::new(device_id), Box::new(addresses)];
+        for provider in &conf.providers {
+            inventories.push(Box::new(NativeInventory {
+                cache_ttl: conf.hostname_cache_ttl,
+                ..NativeInventory::from(*provider)
+            }));
+        }
+        for file in &conf.inventory_files {
+            inventories.push(Box::new(file.clone()));
+        }
+        let mut cache = Self::with_inventories(inventories);
+        cache.dirs = conf.inventory_dirs.clone();
+        cache.rescan_dirs();
+        cache
+    }
+
+    fn with_inventories(inventories: Vec<BoxedInventory>) -> Self {
+        let entries = inventories
+            .into_iter()
+            .map(|inventory| Arc::new(CacheEntry::new(inventory)))
+            .collect();
+        Self {
+            entries,
+            dirs: Vec::new(),
+            discovered: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Track the scripts added to and removed from the inventory directories. Outputs of the
+    /// scripts still present are kept.
+    pub fn rescan_dirs(&self) {
+        if self.dirs.is_empty() {
+            return;
+        }
+        let scripts: Vec<InventoryFile> = self.dirs.iter().flat_map(|d| d.scripts()).collect();
+        let mut discovered = self.discovered.write().expect("Poisoned cache lock");
+        if discovered
+            .iter()
+            .map(|(script, _)| script)
+            .eq(scripts.iter())
+        {
+            return;
+        }
+        let mut previous = std::mem::take(&mut *discovered);
+        for script in scripts {
+            let entry = match previous.iter().position(|(known, _)| *known == script) {
+                Some(i) => previous.swap_remove(i).1,
+                None => {
+                    info!(path = ?script.path, "Inventory script added.");
+                    Arc::new(CacheEntry::new(Box::new(script.clone())))
+                }
+            };
+            discovered.push((script, entry));
+        }
+        for (script, _) in previous {
+            info!(path = ?script.path, "Inventory script removed.");
+        }
+    }
+
+    /// Every entry, in answer order.
+    fn all_entries(&self) -> Vec<Arc<CacheEntry>> {
+        let discovered = self.discovered.read().expect("Poisoned cache lock");
+        let discovered = discovered.iter().map(|(_, entry)| entry);
+        self.entries.iter().chain(discovered).cloned().collect()
+    }
+
+    pub fn answer(&self) -> Result<Answer, Report> {
+        self.answer_with(BeaconInfos::new())
+    }
+
+    /// The answer, with infos about the request (e.g. the interface it arrived on) merged at the
+    /// end.
+    #[instrument(skip(self))]
+    pub fn answer_with(&self, request_infos: BeaconInfos) -> Result<Answer, Report> {
+        let outputs = self.all_entries().into_iter().map(|entry| {
+            let cached = match entry.is_cached() {
+                true => entry.cached.lock().expect("Poisoned cache lock").clone(),
+                false => None,
+            };
+            match cached {
+                Some((output, _)) => output,
+                None => entry.refresh(),
+            }
+        });
+        let mut infos = join_outputs(outputs);
+        merge_infos(&mut infos, request_infos);
+        debug!(?infos);
+        Ok(Answer::from(serde_json::to_string(&infos)?))
+    }
+
+    /// Execute the inventories without output or with an expired one. Return the next expiry
+    /// time, None if nothing is cached.
+    pub fn refresh_stale(&self, now: Instant) -> Option<Instant> {
+        let mut next_expiry = None;
+        for entry in self.all_entries().iter().filter(|e| e.is_cached()) {
+            let expiry = match entry.expiry() {
+                Some(expiry) if expiry > now => expiry,
+                _ => {
+                    trace!(ttl = ?entry.ttl, "Refreshing stale inventory output.");
+                    entry.refresh();
+                    Instant::now() + entry.ttl
+                }
+            };
+            next_expiry = Some(next_expiry.map_or(expiry, |next: Instant| next.min(expiry)));
+        }
+        next_expiry
+    }
+
+    /// Rescan the inventory directories and refresh stale outputs, return how long the refresher
+    /// can sleep.
+    pub fn refresh_wait(&self, now: Instant) -> Duration {
+        self.rescan_dirs();
+        let wait = match self.refresh_stale(now) {
+            Some(next_expiry) => next_expiry.saturating_duration_since(now),
+            None => REFRESH_POLL_PERIOD,
+        };
+        wait.min(REFRESH_POLL_PERIOD)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn counting_inventory(key: &str, ttl: Duration) -> (BoxedInventory, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count_c = count.clone();
+        let inventory = InternalInventory {
+            key: key.to_string(),
+            source: Box::new(move || (count_c.fetch_add(1, Ordering::SeqCst) + 1).to_string()),
+            cache_ttl: ttl,
+        };
+        (Box::new(inventory), count)
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_answer_cached() {
+        let ttl = Duration::from_secs(60);
+        let (cached, cached_count) = counting_inventory("cached", ttl);
+        let (uncached, uncached_count) = counting_inventory("uncached", Duration::ZERO);
+        let cache = AnswerCache::with_inventories(vec![cached, uncached]);
+        cache.answer().unwrap();
+        let answer = cache.answer().unwrap();
+        assert_eq!(answer.0, r#"{"cached":"1","uncached":"2"}"#);
+        assert_eq!(cached_count.load(Ordering::SeqCst), 1);
+        assert_eq!(uncached_count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_refresh_stale() {
+        let ttl = Duration::from_secs(60);
+        let (inventory, count) = counting_inventory("key", ttl);
+        let (uncached, _) = counting_inventory("uncached", Duration::ZERO);
+        let cache = AnswerCache::with_inventories(vec![inventory, uncached]);
+        let now = Instant::now();
+        let next_expiry = cache.refresh_stale(now).unwrap();
+        assert!(next_expiry >= now + ttl);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        cache.refresh_stale(now);
+        assert_eq!(count.load(Ordering::SeqCst), 1); // still fresh
+        cache.refresh_stale(next_expiry + Duration::from_secs(1));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.answer().unwrap().0, r#"{"key":"2","uncached":"1"}"#);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_rescan_dirs() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join("rust-ipdisserver-test-cache-inventory-dir");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let write_script = |name: &str, content: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        write_script("mender-inventory-a", "#!/bin/sh\necho 'key=a'");
+        let conf = ServerConfig {
+            inventory_dirs: vec![InventoryDir {
+                path: dir.clone(),
+                namespace: true,
+                file_options: InventoryFile::default(),
+            }],
+            ..ServerConfig::default()
+        };
+        let cache = AnswerCache::new(&conf);
+        let infos = cache.answer().unwrap().infos();
+        assert_eq!(infos["a"]["key"], "a");
+        write_script("mender-inventory-b", "#!/bin/sh\necho 'key=b'");
+        std::fs::remove_file(dir.join("mender-inventory-a")).unwrap();
+        cache.refresh_wait(Instant::now());
+        let infos = cache.answer().unwrap().infos();
+        assert_eq!(infos["b"]["key"], "b");
+        assert!(!infos.contains_key("a"));
+    }
+}
@@ -1,36 +1,132 @@
+use crate::audit::{AuditLog, ALERT_PERIOD_DEFAULT, ALERT_THRESHOLD_DEFAULT};
+use crate::auth::{SharedKey, REPLAY_WINDOW_DEFAULT};
+use crate::crypto::PublicKey;
+use crate::fragment::MAX_DATAGRAM_SIZE_DEFAULT;
+use crate::identity::DeviceIdSource;
+use crate::inventory::{InventoryDir, InventoryFile, CACHE_TTL_DEFAULT};
+use crate::providers::Provider;
+use crate::server::{
+    GLOBAL_RATE_LIMIT_DEFAULT, MAX_AMPLIFICATION_DEFAULT, QUEUE_SIZE_DEFAULT,
+    RATE_LIMITED_SOURCES_DEFAULT, RATE_LIMIT_BURST_DEFAULT, RATE_LIMIT_PERIOD_DEFAULT,
+    WORKERS_DEFAULT,
+};
 use crate::signature::Signature;
+use crate::sources::{SourceRange, SourceRule, ALLOWED_SOURCES_DEFAULT};
 use color_eyre::eyre::Report;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, Lines};
-use std::net::Ipv4Addr;
-use std::path::Path;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tracing::info;
 
 pub const SERVER_PORT_DEFAULT: u16 = 1901;
 pub const SIGNATURE_DEFAULT: &str = "ipdisbeacon"; // must be shorter than RECV_BUFFER_LENGHT
-const LISTENING_ADDR_DEFAULT: Ipv4Addr = Ipv4Addr::UNSPECIFIED; // "0.0.0.0"
+pub const MULTICAST_ADDR_V6_DEFAULT: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x1901); // link-local scope
+const LISTENING_ADDRS_DEFAULT: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED), // "0.0.0.0"
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED), // "::"
+];
 
 #[derive(Debug, Clone, PartialEq)]
 /// Server configurations.
-pub struct ServerConfig<'a> {
+pub struct ServerConfig {
     pub port: u16,
-    pub listening_addr: Ipv4Addr,
+    pub listening_addrs: Vec<IpAddr>,
+    /// Joined on every interface by the sockets listening on `::`.
+    pub multicast_addr_v6: Ipv6Addr,
+    /// If not empty, requests are received only on these interfaces: a socket is bound to each of
+    /// them for every listening address, while they are present.
+    pub interfaces: Vec<String>,
     pub signatures: Vec<Signature>,
-    pub inventory_files: Vec<&'a Path>,
+    /// Sent in every answer, after the hostname.
+    pub device_id: DeviceIdSource,
+    /// Built-in inventories, executed after the hostname and before the inventory files.
+    pub providers: Vec<Provider>,
+    pub inventory_files: Vec<InventoryFile>,
+    /// Scanned for inventory scripts while running, executed after the inventory files.
+    pub inventory_dirs: Vec<InventoryDir>,
+    /// The hostname and the providers outputs are reused for this long, like the inventory file
+    /// outputs.
+    pub hostname_cache_ttl: Duration,
+    /// If set, only requests authenticated with this key are answered, and answers are
+    /// authenticated too.
+    pub shared_key: Option<SharedKey>,
+    /// Maximum clock difference accepted for authenticated requests.
+    pub replay_window: Duration,
+    /// Scanner public keys. If not empty, answers are encrypted so that only these scanners can
+    /// read them.
+    pub encryption_keys: Vec<PublicKey>,
+    /// Larger answers are split in more datagrams.
+    pub max_datagram_size: usize,
+    /// Evaluated in order before the signature validation, the first matching rule allows or
+    /// denies the request.
+    pub source_rules: Vec<SourceRule>,
+    /// Only sources in these ranges are answered, if no source rule matches.
+    pub allowed_sources: Vec<SourceRange>,
+    /// Answers larger than this many times the request are not sent, scanners pad their
+    /// requests. Zero disables the check.
+    pub max_amplification: f64,
+    /// Answer only requests sent to a broadcast or multicast address.
+    pub only_broadcast: bool,
+    /// Each IP is answered at most once per period, after using up the burst.
+    pub rate_limit_period: Duration,
+    /// Answers to a same IP without waiting for the period.
+    pub rate_limit_burst: u32,
+    /// Answers per second, to any IP.
+    pub global_rate_limit: f64,
+    /// IPs tracked by the rate limiting, the least recently seen are forgotten.
+    pub rate_limited_sources: usize,
+    /// Threads answering the requests.
+    pub workers: usize,
+    /// Requests waiting for a worker, more are dropped.
+    pub queue_size: usize,
+    /// Every request is recorded there, with its outcome.
+    pub audit_log: Option<AuditLog>,
+    /// Sources sending this many invalid signatures within `alert_period` raise an alert. Zero
+    /// disables the alerts.
+    pub alert_threshold: u32,
+    pub alert_period: Duration,
+    /// Executed on every alert, with the source in the environment.
+    pub alert_hook: Option<PathBuf>,
 }
 
-impl Default for ServerConfig<'_> {
+impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             port: SERVER_PORT_DEFAULT,
-            listening_addr: LISTENING_ADDR_DEFAULT,
+            listening_addrs: LISTENING_ADDRS_DEFAULT.to_vec(),
+            multicast_addr_v6: MULTICAST_ADDR_V6_DEFAULT,
+            interfaces: Vec::new(),
             signatures: vec![Signature::from(SIGNATURE_DEFAULT)],
+            device_id: DeviceIdSource::Auto,
+            providers: Vec::new(),
             inventory_files: Vec::new(),
+            inventory_dirs: Vec::new(),
+            hostname_cache_ttl: CACHE_TTL_DEFAULT,
+            shared_key: None,
+            replay_window: REPLAY_WINDOW_DEFAULT,
+            encryption_keys: Vec::new(),
+            max_datagram_size: MAX_DATAGRAM_SIZE_DEFAULT,
+            source_rules: Vec::new(),
+            allowed_sources: ALLOWED_SOURCES_DEFAULT.to_vec(),
+            max_amplification: MAX_AMPLIFICATION_DEFAULT,
+            only_broadcast: false,
+            rate_limit_period: RATE_LIMIT_PERIOD_DEFAULT,
+            rate_limit_burst: RATE_LIMIT_BURST_DEFAULT,
+            global_rate_limit: GLOBAL_RATE_LIMIT_DEFAULT,
+            rate_limited_sources: RATE_LIMITED_SOURCES_DEFAULT,
+            workers: WORKERS_DEFAULT,
+            queue_size: QUEUE_SIZE_DEFAULT,
+            audit_log: None,
+            alert_threshold: ALERT_THRESHOLD_DEFAULT,
+            alert_period: ALERT_PERIOD_DEFAULT,
+            alert_hook: None,
         }
     }
 }
 
-impl ServerConfig<'_> {
+impl ServerConfig {
     /// Read a sequence of Signature from a file, one per line.
     /// Empty lines are ignored.
     pub fn parse_signatures_file(path: &Path) -> Result<Vec<Signature>, Report> {
@@ -68,9 +164,36 @@ mod test {
             conf,
             ServerConfig {
                 port: 1901,
-                listening_addr: Ipv4Addr::new(0, 0, 0, 0),
+                listening_addrs: vec![
+                    IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+                    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+                ],
+                multicast_addr_v6: Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x1901),
+                interfaces: Vec::new(),
                 signatures: vec![Signature::from("ipdisbeacon")],
+                device_id: DeviceIdSource::Auto,
+                providers: Vec::new(),
                 inventory_files: Vec::new(),
+                inventory_dirs: Vec::new(),
+                hostname_cache_ttl: Duration::from_secs(60),
+                shared_key: None,
+                replay_window: Duration::from_secs(30),
+                encryption_keys: Vec::new(),
+                max_datagram_size: 1200,
+                source_rules: Vec::new(),
+                allowed_sources: vec![SourceRange::Local, SourceRange::Private],
+                max_amplification: 8.0,
+                only_broadcast: false,
+                rate_limit_period: Duration::from_secs(10),
+                rate_limit_burst: 1,
+                global_rate_limit: 100.0,
+                rate_limited_sources: 4096,
+                workers: 4,
+                queue_size: 64,
+                audit_log: None,
+                alert_threshold: 20,
+                alert_period: Duration::from_secs(60),
+                alert_hook: None,
             }
         );
     }
@@ -0,0 +1,704 @@
+//! TOML configuration file, e.g.:
+//!
+//! ```toml
+//! port = 1901
+//! listening_addrs = ["0.0.0.0", "::"]
+//! interfaces = ["eth1"]
+//! signatures = ["ipdisbeacon"]
+//! script_timeout = 10
+//! workers = 4
+//! source_rules = ["deny 10.0.5.0/24"]
+//! allowed_sources = ["local", "private"]
+//!
+//! [[inventory]]
+//! path = "/usr/bin/inventory-network"
+//! cache_ttl = 10
+//!
+//! [rate_limit]
+//! period = 10
+//! burst = 1
+//!
+//! [audit]
+//! file = "/var/log/ipdisserver/audit.log"
+//!
+//! [alerts]
+//! invalid_signatures = 20
+//! hook = "/usr/local/bin/ipdis-alert"
+//!
+//! [log]
+//! journald = true
+//! ```
+//!
+//! Every key is optional, missing keys take the default value. Durations are in seconds, relative
+//! paths are relative to the configuration file directory.
+use crate::audit::{AuditLog, AUDIT_MAX_SIZE_DEFAULT, AUDIT_ROTATED_FILES_DEFAULT};
+use crate::auth::SharedKey;
+use crate::conf::ServerConfig;
+use crate::crypto::PublicKey;
+use crate::identity::DeviceIdSource;
+use crate::inventory::{InventoryDir, InventoryFile, InventoryFormat};
+use crate::providers::Provider;
+use crate::server::{GLOBAL_RATE_LIMIT_MIN, SIGNATURE_MAX_LENGHT};
+use crate::signature::Signature;
+use crate::sources::{SourceRange, SourceRule};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed reading {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{path}: invalid TOML: {message}")]
+    Syntax { path: PathBuf, message: String },
+    /// The value of `key` (e.g. `inventory[1].timeout`) is not valid.
+    #[error("{path}: invalid `{key}`: {message}")]
+    InvalidKey {
+        path: PathBuf,
+        key: String,
+        message: String,
+    },
+}
+
+/// Content of the configuration file, see the module documentation.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(skip)]
+    path: PathBuf,
+    pub port: Option<u16>,
+    pub listening_addrs: Option<Vec<IpAddr>>,
+    pub multicast_addr: Option<Ipv6Addr>,
+    /// Interface names, e.g. `["eth1", "wlan0"]`.
+    pub interfaces: Option<Vec<String>>,
+    pub signatures: Option<Vec<String>>,
+    pub signatures_file: Option<PathBuf>,
+    /// `auto`, `machine-id`, `mac` or a UUID.
+    pub device_id: Option<DeviceIdSource>,
+    pub shared_key_file: Option<PathBuf>,
+    pub replay_window: Option<u64>,
+    #[serde(default)]
+    pub encrypt_to: Vec<PathBuf>,
+    pub max_datagram_size: Option<usize>,
+    /// Ordered rules, e.g. `["deny 10.0.5.0/24", "allow 203.0.113.0/24@lab-beacon"]`.
+    #[serde(default)]
+    pub source_rules: Vec<SourceRule>,
+    /// `any`, `local`, `private`, addresses or networks, e.g. `["local", "203.0.113.0/24"]`.
+    pub allowed_sources: Option<Vec<SourceRange>>,
+    pub max_amplification: Option<f64>,
+    pub only_broadcast: Option<bool>,
+    pub cache_ttl: Option<u64>,
+    pub script_timeout: Option<u64>,
+    pub max_output_size: Option<usize>,
+    /// Threads answering the requests.
+    pub workers: Option<usize>,
+    /// Requests waiting for a worker.
+    pub queue_size: Option<usize>,
+    /// Built-in inventories, e.g. `["interfaces", "os-release"]`.
+    #[serde(default)]
+    pub providers: Vec<Provider>,
+    #[serde(default)]
+    pub inventory: Vec<InventoryEntry>,
+    #[serde(default)]
+    pub inventory_dir: Vec<InventoryDirEntry>,
+    #[serde(default)]
+    pub rate_limit: RateLimitSection,
+    #[serde(default)]
+    pub audit: AuditSection,
+    #[serde(default)]
+    pub alerts: AlertsSection,
+    #[serde(default)]
+    pub log: LogSection,
+}
+
+/// An `[[inventory]]` table, unspecified options are taken from the top level keys.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct InventoryEntry {
+    pub path: PathBuf,
+    pub cache_ttl: Option<u64>,
+    pub timeout: Option<u64>,
+    pub max_output_size: Option<usize>,
+    pub format: Option<InventoryFormat>,
+}
+
+/// An `[[inventory_dir]]` table, the options apply to every script of the directory.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct InventoryDirEntry {
+    pub path: PathBuf,
+    /// Nest the outputs under the script name.
+    #[serde(default)]
+    pub namespace: bool,
+    pub cache_ttl: Option<u64>,
+    pub timeout: Option<u64>,
+    pub max_output_size: Option<usize>,
+    pub format: Option<InventoryFormat>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitSection {
+    /// Each IP is answered at most once in this many seconds, after using up the burst.
+    pub period: Option<u64>,
+    pub burst: Option<u32>,
+    /// Answers per second, to any IP, at least `GLOBAL_RATE_LIMIT_MIN`.
+    pub global_rate: Option<f64>,
+    /// IPs tracked, the least recently seen are forgotten.
+    pub max_sources: Option<usize>,
+}
+
+/// Audit log of every request, to a file or to journald.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AuditSection {
+    /// JSON lines, rotated when larger than `max_size` bytes.
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub journald: bool,
+    pub max_size: Option<u64>,
+    /// Previous files kept, `.1` being the most recent.
+    pub rotated_files: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AlertsSection {
+    /// Invalid signatures from a source within the period raising an alert, 0 disables alerts.
+    pub invalid_signatures: Option<u32>,
+    pub period: Option<u64>,
+    /// Executed on every alert.
+    pub hook: Option<PathBuf>,
+}
+
+/// Read at startup only, changes are not applied on reload.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LogSection {
+    #[serde(default)]
+    pub journald: bool,
+    /// Logging directives, as the RUST_LOG environment variable (which takes precedence).
+    pub level: Option<String>,
+}
+
+/// Read a TOML file, errors point at the offending key. Shared with ipdisscan.
+pub fn read_toml<T>(path: &Path) -> Result<T, ConfigError>
+where
+    T: DeserializeOwned,
+{
+    info!(?path, "Reading configuration file.");
+    let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.into(),
+        source,
+    })?;
+    parse_toml(&content).map_err(|error| error.with_path(path))
+}
+
+/// Parse TOML content, errors have an empty path.
+pub fn parse_toml<T>(content: &str) -> Result<T, ConfigError>
+where
+    T: DeserializeOwned,
+{
+    let deserializer = toml::Deserializer::new(content);
+    serde_path_to_error::deserialize(deserializer).map_err(|error| {
+        let key = error.path().to_string();
+        let message = error.into_inner().message().to_string();
+        match key.as_str() {
+            "." => ConfigError::Syntax {
+                path: PathBuf::new(),
+                message,
+            },
+            _ => ConfigError::InvalidKey {
+                path: PathBuf::new(),
+                key,
+                message,
+            },
+        }
+    })
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> Result<Self, ConfigError> {
+        let mut file: Self = read_toml(path)?;
+        file.path = path.into();
+        Ok(file)
+    }
+
+    /// Server configuration, starting from the defaults. Referenced files (signatures, keys) are
+    /// read and validated too.
+    pub fn to_server_config(&self) -> Result<ServerConfig, ConfigError> {
+        let mut conf = ServerConfig::default();
+        if let Some(port) = self.port {
+            conf.port = port;
+        }
+        if let Some(addrs) = &self.listening_addrs {
+            if addrs.is_empty() {
+                return Err(self.invalid("listening_addrs", "no address given"));
+            }
+            conf.listening_addrs = addrs.clone();
+        }
+        if let Some(addr) = self.multicast_addr {
+            if !addr.is_multicast() {
+                return Err(self.invalid("multicast_addr", "not a multicast address"));
+            }
+            conf.multicast_addr_v6 = addr;
+        }
+        if let Some(interfaces) = &self.interfaces {
+            if let Some(i) = interfaces.iter().position(|name| name.is_empty()) {
+                return Err(self.invalid(&format!("interfaces[{}]", i), "empty interface name"));
+            }
+            conf.interfaces = interfaces.clone();
+        }
+        match (&self.signatures, &self.signatures_file) {
+            (Some(_), Some(_)) => {
+                return Err(self.invalid("signatures_file", "conflicts with `signatures`"))
+            }
+            (Some(signatures), None) => conf.signatures = self.parse_signatures(signatures)?,
+            (None, Some(path)) => {
+                conf.signatures = ServerConfig::parse_signatures_file(&self.resolve(path))
+                    .map_err(|e| self.invalid("signatures_file", e))?
+            }
+            (None, None) => (),
+        }
+        if let Some(source) = &self.device_id {
+            conf.device_id = source.clone();
+        }
+        if let Some(path) = &self.shared_key_file {
+            let key = SharedKey::from_file(&self.resolve(path))
+                .map_err(|e| self.invalid("shared_key_file", e))?;
+            conf.shared_key = Some(key);
+        }
+        if let Some(secs) = self.replay_window {
+            conf.replay_window = Duration::from_secs(secs);
+        }
+        for (i, path) in self.encrypt_to.iter().enumerate() {
+            let key = PublicKey::from_file(&self.resolve(path))
+                .map_err(|e| self.invalid(&format!("encrypt_to[{}]", i), e))?;
+            conf.encryption_keys.push(key);
+        }
+        if let Some(size) = self.max_datagram_size {
+            conf.max_datagram_size = size;
+        }
+        conf.source_rules = self.source_rules.clone();
+        match &self.allowed_sources {
+            Some(ranges) if ranges.is_empty() => {
+                return Err(self.invalid("allowed_sources", "no source would be answered"))
+            }
+            Some(ranges) => conf.allowed_sources = ranges.clone(),
+            None => (),
+        }
+        match self.max_amplification {
+            Some(ratio) if !(ratio.is_finite() && ratio >= 0.0) => {
+                return Err(self.invalid("max_amplification", "expected a positive ratio, or 0"))
+            }
+            Some(ratio) => conf.max_amplification = ratio,
+            None => (),
+        }
+        if let Some(only_broadcast) = self.only_broadcast {
+            conf.only_broadcast = only_broadcast;
+        }
+        if let Some(secs) = self.cache_ttl {
+            conf.hostname_cache_ttl = Duration::from_secs(secs);
+        }
+        match self.workers {
+            Some(0) => return Err(self.invalid("workers", "at least one worker is needed")),
+            Some(workers) => conf.workers = workers,
+            None => (),
+        }
+        match self.queue_size {
+            Some(0) => return Err(self.invalid("queue_size", "the queue size must be at least 1")),
+            Some(size) => conf.queue_size = size,
+            None => (),
+        }
+        conf.providers = self.providers.clone();
+        let defaults = self.inventory_defaults(&conf)?;
+        for (i, entry) in self.inventory.iter().enumerate() {
+            if entry.path.as_os_str().is_empty() {
+                return Err(self.invalid(&format!("inventory[{}].path", i), "empty path"));
+            }
+            if entry.timeout == Some(0) {
+                let key = format!("inventory[{}].timeout", i);
+                return Err(self.invalid(&key, "the timeout must be at least 1"));
+            }
+            conf.inventory_files
+                .push(self.inventory_file(entry, &defaults));
+        }
+        for (i, entry) in self.inventory_dir.iter().enumerate() {
+            if entry.path.as_os_str().is_empty() {
+                return Err(self.invalid(&format!("inventory_dir[{}].path", i), "empty path"));
+            }
+            if entry.timeout == Some(0) {
+                let key = format!("inventory_dir[{}].timeout", i);
+                return Err(self.invalid(&key, "the timeout must be at least 1"));
+            }
+            let options = InventoryEntry {
+                path: PathBuf::new(),
+                cache_ttl: entry.cache_ttl,
+                timeout: entry.timeout,
+                max_output_size: entry.max_output_size,
+                format: entry.format,
+            };
+            conf.inventory_dirs.push(InventoryDir {
+                path: self.resolve(&entry.path),
+                namespace: entry.namespace,
+                file_options: self.inventory_file(&options, &defaults),
+            });
+        }
+        match self.rate_limit.period {
+            Some(0) => {
+                return Err(self.invalid("rate_limit.period", "the period must be at least 1"))
+            }
+            Some(secs) => conf.rate_limit_period = Duration::from_secs(secs),
+            None => (),
+        }
+        match self.rate_limit.burst {
+            Some(0) => return Err(self.invalid("rate_limit.burst", "the burst must be at least 1")),
+            Some(burst) => conf.rate_limit_burst = burst,
+            None => (),
+        }
+        match self.rate_limit.global_rate {
+            Some(rate) if !(rate.is_finite() && rate >= GLOBAL_RATE_LIMIT_MIN) => {
+                return Err(self.invalid(
+                    "rate_limit.global_rate",
+                    "expected a rate of at least 0.001 answers per second",
+                ))
+            }
+            Some(rate) => conf.global_rate_limit = rate,
+            None => (),
+        }
+        match self.rate_limit.max_sources {
+            Some(0) => {
+                return Err(self.invalid("rate_limit.max_sources", "at least one source is needed"))
+            }
+            Some(max) => conf.rate_limited_sources = max,
+            None => (),
+        }
+        conf.audit_log = self.audit_log()?;
+        if let Some(threshold) = self.alerts.invalid_signatures {
+            conf.alert_threshold = threshold;
+        }
+        match self.alerts.period {
+            Some(0) => return Err(self.invalid("alerts.period", "the period must be at least 1")),
+            Some(secs) => conf.alert_period = Duration::from_secs(secs),
+            None => (),
+        }
+        conf.alert_hook = self.alerts.hook.as_deref().map(|path| self.resolve(path));
+        if let Some(level) = &self.log.level {
+            EnvFilter::try_new(level).map_err(|e| self.invalid("log.level", e))?;
+        }
+        Ok(conf)
+    }
+
+    fn parse_signatures(&self, signatures: &[String]) -> Result<Vec<Signature>, ConfigError> {
+        let mut res = Vec::new();
+        for (i, signature) in signatures.iter().enumerate() {
+            if signature.is_empty() || signature.len() > SIGNATURE_MAX_LENGHT {
+                let message = format!("length must be 1 to {} bytes", SIGNATURE_MAX_LENGHT);
+                return Err(self.invalid(&format!("signatures[{}]", i), message));
+            }
+            res.push(Signature::from(signature.as_str()));
+        }
+        Ok(res)
+    }
+
+    fn audit_log(&self) -> Result<Option<AuditLog>, ConfigError> {
+        let audit = &self.audit;
+        let path = match (&audit.file, audit.journald) {
+            (Some(_), true) => return Err(self.invalid("audit.journald", "conflicts with `file`")),
+            (None, true) => return Ok(Some(AuditLog::Journald)),
+            (Some(path), false) => self.resolve(path),
+            (None, false) => return Ok(None),
+        };
+        let max_size = match audit.max_size {
+            Some(0) => return Err(self.invalid("audit.max_size", "the size must be at least 1")),
+            Some(size) => size,
+            None => AUDIT_MAX_SIZE_DEFAULT,
+        };
+        Ok(Some(AuditLog::File {
+            path,
+            max_size,
+            rotated_files: audit.rotated_files.unwrap_or(AUDIT_ROTATED_FILES_DEFAULT),
+        }))
+    }
+
+    /// Options of inventory entries not specifying them.
+    fn inventory_defaults(&self, conf: &ServerConfig) -> Result<InventoryFile, ConfigError> {
+        let mut defaults = InventoryFile {
+            cache_ttl: conf.hostname_cache_ttl,
+            ..InventoryFile::default()
+        };
+        match self.script_timeout {
+            Some(0) => return Err(self.invalid("script_timeout", "the timeout must be at least 1")),
+            Some(secs) => defaults.timeout = Duration::from_secs(secs),
+            None => (),
+        }
+        if let Some(size) = self.max_output_size {
+            defaults.max_output_size = size;
+        }
+        Ok(defaults)
+    }
+
+    fn inventory_file(&self, entry: &InventoryEntry, defaults: &InventoryFile) -> InventoryFile {
+        InventoryFile {
+            path: self.resolve(&entry.path),
+            cache_ttl: entry
+                .cache_ttl
+                .map_or(defaults.cache_ttl, Duration::from_secs),
+            timeout: entry.timeout.map_or(defaults.timeout, Duration::from_secs),
+            max_output_size: entry.max_output_size.unwrap_or(defaults.max_output_size),
+            namespace: None,
+            format: entry.format.unwrap_or(defaults.format),
+        }
+    }
+
+    /// Relative paths are relative to the configuration file directory.
+    fn resolve(&self, path: &Path) -> PathBuf {
+        match self.path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() => Path::new(".").join(path),
+            Some(dir) => dir.join(path),
+            None => path.into(),
+        }
+    }
+
+    fn invalid<M>(&self, key: &str, message: M) -> ConfigError
+    where
+        M: ToString,
+    {
+        ConfigError::InvalidKey {
+            path: self.path.clone(),
+            key: key.into(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl ConfigError {
+    pub fn with_path(self, path: &Path) -> Self {
+        match self {
+            Self::Syntax { message, .. } => Self::Syntax {
+                path: path.into(),
+                message,
+            },
+            Self::InvalidKey { key, message, .. } => Self::InvalidKey {
+                path: path.into(),
+                key,
+                message,
+            },
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_config_file() {
+        let content = r#"
+            port = 1902
+            listening_addrs = ["127.0.0.1"]
+            interfaces = ["eth1", "wlan0"]
+            signatures = ["sig1", "sig2"]
+            cache_ttl = 30
+            script_timeout = 2
+            providers = ["kernel", "machine-id"]
+            device_id = "mac"
+            workers = 2
+            queue_size = 16
+            source_rules = ["deny 10.0.5.0/24", "allow 198.51.100.0/24@sig1"]
+            allowed_sources = ["local", "203.0.113.0/24"]
+            max_amplification = 0
+            only_broadcast = true
+
+            [[inventory]]
+            path = "/usr/bin/inventory-network"
+
+            [[inventory]]
+            path = "inventory-disks"
+            cache_ttl = 0
+            timeout = 5
+            format = "json"
+
+            [[inventory_dir]]
+            path = "/usr/share/mender/inventory"
+            namespace = true
+
+            [rate_limit]
+            period = 3
+            burst = 2
+            global_rate = 20.5
+            max_sources = 100
+
+            [audit]
+            file = "audit.log"
+            rotated_files = 2
+
+            [alerts]
+            invalid_signatures = 5
+            period = 30
+            hook = "/usr/local/bin/ipdis-alert"
+
+            [log]
+            journald = true
+        "#;
+        let mut file: ConfigFile = parse_toml(content).unwrap();
+        file.path = "/etc/ipdisserver/ipdisserver.toml".into();
+        assert!(file.log.journald);
+        let conf = file.to_server_config().unwrap();
+        assert_eq!(conf.port, 1902);
+        assert_eq!(conf.listening_addrs, vec![IpAddr::from([127, 0, 0, 1])]);
+        assert_eq!(conf.interfaces, vec!["eth1", "wlan0"]);
+        assert_eq!(
+            conf.signatures,
+            vec![Signature::from("sig1"), Signature::from("sig2")]
+        );
+        assert_eq!(conf.rate_limit_period, Duration::from_secs(3));
+        assert_eq!(conf.rate_limit_burst, 2);
+        assert_eq!(conf.global_rate_limit, 20.5);
+        assert_eq!(conf.rate_limited_sources, 100);
+        assert_eq!((conf.workers, conf.queue_size), (2, 16));
+        assert_eq!(
+            conf.audit_log,
+            Some(AuditLog::File {
+                path: "/etc/ipdisserver/audit.log".into(),
+                max_size: 10 * 1024 * 1024,
+                rotated_files: 2,
+            })
+        );
+        assert_eq!(conf.alert_threshold, 5);
+        assert_eq!(conf.alert_period, Duration::from_secs(30));
+        assert_eq!(conf.alert_hook, Some("/usr/local/bin/ipdis-alert".into()));
+        assert_eq!(
+            conf.allowed_sources,
+            vec![
+                SourceRange::Local,
+                SourceRange::Network(IpAddr::from([203, 0, 113, 0]), 24)
+            ]
+        );
+        assert_eq!(conf.source_rules.len(), 2);
+        assert_eq!(
+            conf.source_rules[1].signature,
+            Some(Signature::from("sig1"))
+        );
+        assert_eq!(conf.max_amplification, 0.0);
+        assert!(conf.only_broadcast);
+        assert_eq!(
+            conf.inventory_files,
+            vec![
+                InventoryFile {
+                    path: "/usr/bin/inventory-network".into(),
+                    cache_ttl: Duration::from_secs(30),
+                    timeout: Duration::from_secs(2),
+                    ..InventoryFile::default()
+                },
+                InventoryFile {
+                    path: "/etc/ipdisserver/inventory-disks".into(),
+                    cache_ttl: Duration::ZERO,
+                    timeout: Duration::from_secs(5),
+                    format: InventoryFormat::Json,
+                    ..InventoryFile::default()
+                },
+            ]
+        );
+        assert_eq!(conf.providers, vec![Provider::Kernel, Provider::MachineId]);
+        assert_eq!(conf.device_id, DeviceIdSource::Mac);
+        assert_eq!(conf.inventory_dirs.len(), 1);
+        assert!(conf.inventory_dirs[0].namespace);
+        assert_eq!(
+            conf.inventory_dirs[0].file_options.timeout,
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            ConfigFile::default().to_server_config().unwrap(),
+            ServerConfig::default()
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_config_file_errors() {
+        let key_of = |content: &str| match parse_toml::<ConfigFile>(content)
+            .and_then(|file| file.to_server_config())
+        {
+            Err(ConfigError::InvalidKey { key, .. }) => key,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(key_of("port = 70000"), "port");
+        assert_eq!(key_of("prot = 1901"), "prot");
+        assert_eq!(
+            key_of("listening_addrs = [\"::\", \"foo\"]"),
+            "listening_addrs[1]"
+        );
+        assert_eq!(
+            key_of("[[inventory]]\npath = \"/a\"\n[[inventory]]\npath = \"/b\"\ntimeout = \"2\""),
+            "inventory[1].timeout"
+        );
+        assert_eq!(key_of("script_timeout = 0"), "script_timeout");
+        assert_eq!(
+            key_of("[[inventory]]\npath = \"/a\"\ntimeout = 0"),
+            "inventory[0].timeout"
+        );
+        assert_eq!(
+            key_of("[[inventory_dir]]\npath = \"/d\"\ntimeout = 0"),
+            "inventory_dir[0].timeout"
+        );
+        assert_eq!(key_of("[rate_limit]\nperiod = -1"), "rate_limit.period");
+        assert_eq!(key_of("[rate_limit]\nperiod = 0"), "rate_limit.period");
+        assert_eq!(key_of("[rate_limit]\nburst = 0"), "rate_limit.burst");
+        assert_eq!(
+            key_of("[rate_limit]\nglobal_rate = 0.0"),
+            "rate_limit.global_rate"
+        );
+        assert_eq!(
+            key_of("[rate_limit]\nglobal_rate = 1e-300"),
+            "rate_limit.global_rate"
+        );
+        assert_eq!(
+            key_of("[rate_limit]\nmax_sources = 0"),
+            "rate_limit.max_sources"
+        );
+        assert_eq!(key_of("signatures = [\"ok\", \"\"]"), "signatures[1]");
+        assert_eq!(key_of("providers = [\"cpu\", \"gpu\"]"), "providers[1]");
+        assert_eq!(key_of("device_id = \"machineid\""), "device_id");
+        assert_eq!(key_of("workers = 0"), "workers");
+        assert_eq!(key_of("queue_size = 0"), "queue_size");
+        assert_eq!(
+            key_of("allowed_sources = [\"local\", \"lan\"]"),
+            "allowed_sources[1]"
+        );
+        assert_eq!(key_of("allowed_sources = []"), "allowed_sources");
+        assert_eq!(
+            key_of("source_rules = [\"deny 10.0.5.0/24\", \"block any\"]"),
+            "source_rules[1]"
+        );
+        assert_eq!(key_of("max_amplification = -1.0"), "max_amplification");
+        assert_eq!(
+            key_of("[audit]\nfile = \"audit.log\"\njournald = true"),
+            "audit.journald"
+        );
+        assert_eq!(
+            key_of("[audit]\nfile = \"audit.log\"\nmax_size = 0"),
+            "audit.max_size"
+        );
+        assert_eq!(key_of("[alerts]\nperiod = 0"), "alerts.period");
+        assert_eq!(key_of("multicast_addr = \"fe80::1\""), "multicast_addr");
+        assert_eq!(
+            key_of("shared_key_file = \"/non-existing-file\""),
+            "shared_key_file"
+        );
+        assert!(matches!(
+            parse_toml::<ConfigFile>("port = "),
+            Err(ConfigError::Syntax { .. })
+        ));
+    }
+}
@@ -0,0 +1,270 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use color_eyre::eyre::{eyre, Report};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::Path;
+use thiserror::Error;
+use tracing::{info, trace};
+use x25519_dalek::StaticSecret;
+
+const KEY_SIZE: usize = 32;
+const KEY_ID_SIZE: usize = 4;
+const TAG_SIZE: usize = 16;
+const AEAD_NONCE_SIZE: usize = 12;
+const WRAPPED_KEY_SIZE: usize = KEY_SIZE + TAG_SIZE;
+const RECIPIENT_SIZE: usize = KEY_ID_SIZE + WRAPPED_KEY_SIZE;
+const KEK_INFO: &[u8] = b"ipdis answer key wrapping";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CryptoError {
+    #[error("malformed encrypted message")]
+    Malformed,
+    #[error("not encrypted for this key")]
+    NotRecipient,
+    #[error("decryption failed")]
+    Decryption,
+}
+
+/// X25519 public key of a scanner, answers are encrypted to it.
+#[derive(Clone, PartialEq)]
+pub struct PublicKey(x25519_dalek::PublicKey);
+
+/// X25519 private key of a scanner, used to decrypt answers.
+#[derive(Clone)]
+pub struct PrivateKey(StaticSecret);
+
+impl PartialEq for PrivateKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_bytes() == other.0.as_bytes()
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self.to_hex())
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PrivateKey(<redacted>)")
+    }
+}
+
+impl PublicKey {
+    /// Read a hex encoded key from a file.
+    pub fn from_file(path: &Path) -> Result<Self, Report> {
+        info!(?path, "Reading public key from file.");
+        Ok(Self(x25519_dalek::PublicKey::from(read_hex_key(path)?)))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0.as_bytes())
+    }
+
+    /// Short identifier, used to find the recipient entry of an encrypted answer.
+    fn id(&self) -> [u8; KEY_ID_SIZE] {
+        let digest = Sha256::digest(self.0.as_bytes());
+        digest[..KEY_ID_SIZE].try_into().expect("Checked size")
+    }
+}
+
+impl PrivateKey {
+    pub fn generate() -> Result<Self, Report> {
+        Ok(Self(StaticSecret::from(random_bytes::<KEY_SIZE>()?)))
+    }
+
+    /// Read a hex encoded key from a file.
+    pub fn from_file(path: &Path) -> Result<Self, Report> {
+        info!(?path, "Reading private key from file.");
+        Ok(Self(StaticSecret::from(read_hex_key(path)?)))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0.to_bytes())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(x25519_dalek::PublicKey::from(&self.0))
+    }
+}
+
+fn read_hex_key(path: &Path) -> Result<[u8; KEY_SIZE], Report> {
+    let content = std::fs::read_to_string(path)?;
+    let bytes = hex::decode(content.trim())?;
+    bytes
+        .try_into()
+        .map_err(|_| eyre!("Key in {:?} must be {} bytes long", path, KEY_SIZE))
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N], Report> {
+    let mut buf = [0; N];
+    getrandom::getrandom(&mut buf).map_err(|e| eyre!("Random generation failed: {}", e))?;
+    Ok(buf)
+}
+
+/// Key encryption key, derived from the X25519 shared secret between the ephemeral key of the
+/// answer and a recipient key.
+fn derive_kek(
+    shared_secret: &[u8],
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> [u8; KEY_SIZE] {
+    let salt = [ephemeral.0.as_bytes().as_slice(), recipient.0.as_bytes()].concat();
+    let mut kek = [0; KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret)
+        .expand(KEK_INFO, &mut kek)
+        .expect("Valid HKDF output length");
+    kek
+}
+
+/// Encrypt the payload with a random content key, wrapped for each recipient.
+///
+/// Format: ephemeral public key, recipients count, for each recipient (key id, wrapped
+/// content key), nonce, ciphertext.
+pub fn encrypt_answer(payload: &[u8], recipients: &[PublicKey]) -> Result<Bytes, Report> {
+    if recipients.is_empty() || recipients.len() > u8::MAX.into() {
+        return Err(eyre!("Invalid number of recipients: {}", recipients.len()));
+    }
+    let ephemeral_secret = StaticSecret::from(random_bytes::<KEY_SIZE>()?);
+    let ephemeral = PublicKey(x25519_dalek::PublicKey::from(&ephemeral_secret));
+    let content_key = random_bytes::<KEY_SIZE>()?;
+    let nonce = random_bytes::<AEAD_NONCE_SIZE>()?;
+
+    let mut buf = BytesMut::new();
+    buf.put_slice(ephemeral.0.as_bytes());
+    buf.put_u8(recipients.len() as u8);
+    for recipient in recipients {
+        let shared_secret = ephemeral_secret.diffie_hellman(&recipient.0);
+        let kek = derive_kek(shared_secret.as_bytes(), &ephemeral, recipient);
+        let wrapped = ChaCha20Poly1305::new(&kek.into())
+            .encrypt(&[0; AEAD_NONCE_SIZE].into(), content_key.as_slice())
+            .map_err(|_| eyre!("Key wrapping failed"))?;
+        buf.put_slice(&recipient.id());
+        buf.put_slice(&wrapped);
+    }
+    let aad = buf.clone();
+    let ciphertext = ChaCha20Poly1305::new(&content_key.into())
+        .encrypt(
+            &nonce.into(),
+            Payload {
+                msg: payload,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| eyre!("Answer encryption failed"))?;
+    buf.put_slice(&nonce);
+    buf.put_slice(&ciphertext);
+    trace!(
+        recipients = recipients.len(),
+        lenght = buf.len(),
+        "Answer encrypted."
+    );
+    Ok(buf.freeze())
+}
+
+/// Decrypt a payload produced by `encrypt_answer`.
+pub fn decrypt_answer(bytes: &[u8], key: &PrivateKey) -> Result<Bytes, CryptoError> {
+    let header_size = KEY_SIZE + 1;
+    if bytes.len() < header_size {
+        return Err(CryptoError::Malformed);
+    }
+    let ephemeral_bytes: [u8; KEY_SIZE] =
+        bytes[..header_size - 1].try_into().expect("Checked size");
+    let ephemeral = PublicKey(x25519_dalek::PublicKey::from(ephemeral_bytes));
+    let recipients_count = bytes[header_size - 1] as usize;
+    let aad_size = header_size + recipients_count * RECIPIENT_SIZE;
+    if bytes.len() < aad_size + AEAD_NONCE_SIZE + TAG_SIZE {
+        return Err(CryptoError::Malformed);
+    }
+    let (aad, rest) = bytes.split_at(aad_size);
+    let (nonce, ciphertext) = rest.split_at(AEAD_NONCE_SIZE);
+
+    let public_key = key.public_key();
+    let id = public_key.id();
+    let shared_secret = key.0.diffie_hellman(&ephemeral.0);
+    let kek = derive_kek(shared_secret.as_bytes(), &ephemeral, &public_key);
+    let content_key = aad[header_size..]
+        .chunks_exact(RECIPIENT_SIZE)
+        .filter(|recipient| recipient[..KEY_ID_SIZE] == id)
+        .find_map(|recipient| {
+            ChaCha20Poly1305::new(&kek.into())
+                .decrypt(&[0; AEAD_NONCE_SIZE].into(), &recipient[KEY_ID_SIZE..])
+                .ok()
+        })
+        .ok_or(CryptoError::NotRecipient)?;
+    let content_key: [u8; KEY_SIZE] = content_key.try_into().map_err(|_| CryptoError::Malformed)?;
+    let plaintext = ChaCha20Poly1305::new(&content_key.into())
+        .decrypt(
+            nonce.into(),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| CryptoError::Decryption)?;
+    Ok(Bytes::from(plaintext))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_encryption_roundtrip() {
+        let scanner1 = PrivateKey::generate().unwrap();
+        let scanner2 = PrivateKey::generate().unwrap();
+        let other = PrivateKey::generate().unwrap();
+        let payload = br#"{"hostname":"secret-host"}"#;
+        let encrypted =
+            encrypt_answer(payload, &[scanner1.public_key(), scanner2.public_key()]).unwrap();
+        assert!(!encrypted.windows(11).any(|w| w == b"secret-host"));
+        assert_eq!(
+            decrypt_answer(&encrypted, &scanner1).unwrap(),
+            Bytes::from(payload.as_slice())
+        );
+        assert_eq!(
+            decrypt_answer(&encrypted, &scanner2).unwrap(),
+            Bytes::from(payload.as_slice())
+        );
+        assert_eq!(
+            decrypt_answer(&encrypted, &other),
+            Err(CryptoError::NotRecipient)
+        );
+        let mut tampered = encrypted.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            decrypt_answer(&tampered, &scanner1),
+            Err(CryptoError::Decryption)
+        );
+        assert_eq!(
+            decrypt_answer(b"short", &scanner1),
+            Err(CryptoError::Malformed)
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_key_files() {
+        let datadir = std::env::temp_dir();
+        let private_path = datadir.join("rust-ipdisserver-test-crypto-key");
+        let public_path = datadir.join("rust-ipdisserver-test-crypto-key.pub");
+        let key = PrivateKey::generate().unwrap();
+        std::fs::write(&private_path, format!("{}\n", key.to_hex())).unwrap();
+        std::fs::write(&public_path, key.public_key().to_hex()).unwrap();
+        assert_eq!(
+            PrivateKey::from_file(&private_path).unwrap().to_hex(),
+            key.to_hex()
+        );
+        assert_eq!(
+            PublicKey::from_file(&public_path).unwrap(),
+            key.public_key()
+        );
+        std::fs::write(&public_path, "abcd").unwrap();
+        assert!(PublicKey::from_file(&public_path).is_err());
+    }
+}
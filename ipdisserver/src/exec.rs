@@ -1,40 +1,193 @@
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
 use std::path::Path;
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
 use tracing::{error, warn};
 
+pub const SCRIPT_TIMEOUT_DEFAULT: Duration = Duration::from_secs(10);
+pub const MAX_OUTPUT_SIZE_DEFAULT: usize = 64 * 1024;
+const EXIT_POLL_PERIOD: Duration = Duration::from_millis(10);
+const STDERR_WAIT: Duration = Duration::from_millis(100);
+
+#[derive(Error, Debug)]
+pub enum ExecError {
+    #[error("failed executing: {0}")]
+    Spawn(#[from] io::Error),
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("output larger than {0} bytes")]
+    OutputTooLarge(usize),
+    #[error("failed with {0}")]
+    ExitStatus(ExitStatus),
+}
+
 pub struct InventoryCommand {
     cmd: Command,
+    timeout: Duration,
+    max_output_size: usize,
 }
 
 impl InventoryCommand {
-    pub fn new<P>(path: P) -> Self
+    pub fn new<P>(path: P, timeout: Duration, max_output_size: usize) -> Self
     where
         P: AsRef<Path>,
     {
-        let cmd = Command::new(path.as_ref().to_path_buf());
-        Self { cmd }
-    }
-
-    /// Return raw output string, an empty string if the execution is not successful.
-    pub fn output(&mut self) -> String {
-        match self.cmd.output() {
-            Ok(output) => {
-                if !output.stderr.is_empty() {
-                    // warn!(?self.cmd, stderr = String::from_utf8_lossy(&output.stderr), "Inventory file wrote on stderr.");
-                    warn!(?self.cmd, ?output.stderr, "Inventory file wrote on stderr.");
-                }
-                match output.status.success() {
-                    true => String::from_utf8_lossy(&output.stdout).into(),
-                    false => {
-                        error!(?self.cmd, ?output.status, "Inventory file: non-0 exit code.");
-                        String::new()
-                    }
-                }
+        let mut cmd = Command::new(path.as_ref());
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0); // killed as a whole, with any child process
+        Self {
+            cmd,
+            timeout,
+            max_output_size,
+        }
+    }
+
+    /// Return raw output string. The command is killed, with its process group, if it does not
+    /// complete within the timeout or writes more than the maximum output size.
+    pub fn output(&mut self) -> Result<String, ExecError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut child = self.cmd.spawn()?;
+        let stdout = read_capped(child.stdout.take(), self.max_output_size);
+        let stderr = read_capped(child.stderr.take(), self.max_output_size);
+        let result = self.wait_output(&mut child, stdout, deadline);
+        if result.is_err() {
+            kill_group(&mut child);
+        }
+        // Background children may keep stderr open, do not wait for them.
+        if let Ok(stderr) = stderr.recv_timeout(STDERR_WAIT) {
+            if !stderr.is_empty() {
+                warn!(?self.cmd, stderr = %String::from_utf8_lossy(&stderr), "Inventory file wrote on stderr.");
+            }
+        }
+        match &result {
+            Ok(_) => (),
+            Err(error) => error!(?self.cmd, %error, "Inventory file failed."),
+        }
+        result
+    }
+
+    fn wait_output(
+        &self,
+        child: &mut Child,
+        stdout: mpsc::Receiver<Vec<u8>>,
+        deadline: Instant,
+    ) -> Result<String, ExecError> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let stdout = stdout
+            .recv_timeout(timeout)
+            .map_err(|_| ExecError::Timeout(self.timeout))?;
+        if stdout.len() > self.max_output_size {
+            return Err(ExecError::OutputTooLarge(self.max_output_size));
+        }
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
             }
-            Err(error) => {
-                error!(?self.cmd, ?error, "Failed executing inventory file.");
-                String::new()
+            if Instant::now() >= deadline {
+                return Err(ExecError::Timeout(self.timeout));
             }
+            thread::sleep(EXIT_POLL_PERIOD);
+        };
+        match status.success() {
+            true => Ok(String::from_utf8_lossy(&stdout).into()),
+            false => Err(ExecError::ExitStatus(status)),
         }
     }
 }
+
+/// Read the pipe in a thread, up to one byte more than `max_size` (to detect larger outputs).
+fn read_capped<R>(pipe: Option<R>, max_size: usize) -> mpsc::Receiver<Vec<u8>>
+where
+    R: Read + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    if let Some(pipe) = pipe {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = pipe.take(max_size as u64 + 1).read_to_end(&mut buf);
+            let _ = sender.send(buf);
+        });
+    }
+    receiver
+}
+
+/// Kill the process group of the child, then reap the child.
+fn kill_group(child: &mut Child) {
+    // SAFETY: plain system call, the process group id is the child pid (see `process_group(0)`).
+    let res = unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+    if res != 0 {
+        warn!(error = %io::Error::last_os_error(), "Failed killing inventory process group.");
+        let _ = child.kill();
+    }
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    fn write_script(filename: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(filename);
+        std::fs::write(&path, content).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_output() {
+        let path = write_script("rust-ipdisserver-test-exec-ok", "#!/bin/sh\necho 'a=b'");
+        let mut command = InventoryCommand::new(&path, Duration::from_secs(5), 100);
+        assert_eq!(command.output().unwrap(), "a=b\n");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_timeout_kills_group() {
+        let marker = std::env::temp_dir().join("rust-ipdisserver-test-exec-marker");
+        let _ = std::fs::remove_file(&marker);
+        let script = format!(
+            "#!/bin/sh\n(sleep 1; touch {})&\nsleep 10",
+            marker.display()
+        );
+        let path = write_script("rust-ipdisserver-test-exec-hang", &script);
+        let start = Instant::now();
+        let mut command = InventoryCommand::new(&path, Duration::from_millis(200), 100);
+        assert!(matches!(command.output(), Err(ExecError::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!marker.exists()); // the background child was killed too
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_output_too_large() {
+        let path = write_script(
+            "rust-ipdisserver-test-exec-large",
+            "#!/bin/sh\nwhile true; do echo 'key=value'; done",
+        );
+        let mut command = InventoryCommand::new(&path, Duration::from_secs(5), 1000);
+        assert!(matches!(
+            command.output(),
+            Err(ExecError::OutputTooLarge(1000))
+        ));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_exit_status() {
+        let path = write_script("rust-ipdisserver-test-exec-fail", "#!/bin/sh\nexit 3");
+        let mut command = InventoryCommand::new(&path, Duration::from_secs(5), 100);
+        assert!(matches!(command.output(), Err(ExecError::ExitStatus(_))));
+        let mut command = InventoryCommand::new("non-existing-file", Duration::from_secs(5), 100);
+        assert!(matches!(command.output(), Err(ExecError::Spawn(_))));
+    }
+}
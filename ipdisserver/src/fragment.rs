@@ -0,0 +1,252 @@
+use crate::protocol::{self, Flags, Header, MessageType};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{debug, trace, warn};
+
+/// Fits the IPv6 minimum MTU (1280 bytes) with IP and UDP headers.
+pub const MAX_DATAGRAM_SIZE_DEFAULT: usize = 1200;
+const HEADER_SIZE: usize = protocol::HEADER_SIZE + 4 + 2 + 2; // header, answer id, index, count
+const MIN_DATAGRAM_SIZE: usize = HEADER_SIZE + 1;
+/// Bounds the memory used by the reassembly of a single answer.
+pub const MAX_FRAGMENTS: u16 = 128;
+/// Bounds the number of answers being reassembled at the same time.
+const MAX_PENDING_ANSWERS: usize = 256;
+pub const REASSEMBLY_TIMEOUT_DEFAULT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug, PartialEq)]
+#[error("message of {size} bytes, more than {MAX_FRAGMENTS} fragments of {chunk_size} bytes")]
+pub struct TooManyFragments {
+    size: usize,
+    chunk_size: usize,
+}
+
+/// Split a message in `AnswerFragment` datagrams of at most `max_datagram_size` bytes.
+///
+/// Messages fitting in a single datagram are returned as they are. Messages needing more than
+/// `MAX_FRAGMENTS` datagrams are refused, scanners could not reassemble them.
+pub fn fragment(
+    message: &[u8],
+    request_id: u32,
+    answer_id: u32,
+    max_datagram_size: usize,
+) -> Result<Vec<Bytes>, TooManyFragments> {
+    if message.len() <= max_datagram_size {
+        return Ok(vec![Bytes::copy_from_slice(message)]);
+    }
+    let chunk_size = max_datagram_size.max(MIN_DATAGRAM_SIZE) - HEADER_SIZE;
+    let chunks: Vec<&[u8]> = message.chunks(chunk_size).collect();
+    let count = match u16::try_from(chunks.len()) {
+        Ok(count) if count <= MAX_FRAGMENTS => count,
+        _ => {
+            return Err(TooManyFragments {
+                size: message.len(),
+                chunk_size,
+            })
+        }
+    };
+    let header = Header::new(MessageType::AnswerFragment, Flags::empty(), request_id);
+    let fragments = chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut buf = BytesMut::with_capacity(HEADER_SIZE + chunk.len());
+            buf.put_slice(&header.to_bytes());
+            buf.put_u32(answer_id);
+            buf.put_u16(index as u16);
+            buf.put_u16(count);
+            buf.put_slice(chunk);
+            buf.freeze()
+        })
+        .collect();
+    trace!(%answer_id, %count, "Message fragmented.");
+    Ok(fragments)
+}
+
+pub fn is_fragment(datagram: &[u8]) -> bool {
+    matches!(
+        Header::parse(datagram),
+        Ok((header, _)) if header.message_type == MessageType::AnswerFragment
+    )
+}
+
+#[derive(Debug)]
+struct PendingAnswer {
+    first_received: Instant,
+    chunks: Vec<Option<Bytes>>,
+}
+
+/// Collect answer fragments until all of them are received. Incomplete answers are dropped after
+/// the timeout.
+#[derive(Debug)]
+pub struct Reassembler {
+    timeout: Duration,
+    pending: HashMap<(SocketAddr, u32), PendingAnswer>,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(REASSEMBLY_TIMEOUT_DEFAULT)
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Return the complete message if available: datagrams other than fragments are returned as
+    /// they are, fragments when the last missing one is received.
+    pub fn push(&mut self, source: SocketAddr, datagram: &[u8], now: Instant) -> Option<Bytes> {
+        self.expire(now);
+        if !is_fragment(datagram) {
+            return Some(Bytes::copy_from_slice(datagram));
+        }
+        if datagram.len() < HEADER_SIZE {
+            warn!(%source, "Malformed answer fragment.");
+            return None;
+        }
+        let header = &datagram[protocol::HEADER_SIZE..HEADER_SIZE];
+        let answer_id = u32::from_be_bytes(header[0..4].try_into().expect("Checked size"));
+        let index = u16::from_be_bytes(header[4..6].try_into().expect("Checked size"));
+        let count = u16::from_be_bytes(header[6..8].try_into().expect("Checked size"));
+        if count == 0 || count > MAX_FRAGMENTS || index >= count {
+            warn!(%source, %index, %count, "Invalid answer fragment.");
+            return None;
+        }
+        let key = (source, answer_id);
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING_ANSWERS {
+            warn!(%source, "Too many incomplete answers, fragment dropped.");
+            return None;
+        }
+        let pending = self.pending.entry(key).or_insert_with(|| PendingAnswer {
+            first_received: now,
+            chunks: vec![None; count.into()],
+        });
+        if pending.chunks.len() != usize::from(count) {
+            warn!(%source, %answer_id, "Inconsistent fragment count.");
+            return None;
+        }
+        pending.chunks[usize::from(index)] = Some(Bytes::copy_from_slice(&datagram[HEADER_SIZE..]));
+        trace!(%source, %answer_id, %index, %count, "Fragment received.");
+        if pending.chunks.iter().any(Option::is_none) {
+            return None;
+        }
+        let pending = self.pending.remove(&key).expect("Checked presence");
+        let mut message = BytesMut::new();
+        for chunk in pending.chunks.into_iter().flatten() {
+            message.put_slice(&chunk);
+        }
+        debug!(%source, %answer_id, %count, "Answer reassembled.");
+        Some(message.freeze())
+    }
+
+    /// Drop incomplete answers older than the timeout.
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.pending.retain(|(source, answer_id), pending| {
+            let expired = now.duration_since(pending.first_received) > timeout;
+            if expired {
+                let missing = pending.chunks.iter().filter(|c| c.is_none()).count();
+                debug!(%source, %answer_id, %missing, "Incomplete answer expired.");
+            }
+            !expired
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn source() -> SocketAddr {
+        SocketAddr::from(([192, 168, 0, 1], 1901))
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_small_message_unframed() {
+        let fragments = fragment(b"{}", 1, 1, 100).unwrap();
+        assert_eq!(fragments, vec![Bytes::from("{}")]);
+        let mut reassembler = Reassembler::default();
+        assert_eq!(
+            reassembler.push(source(), &fragments[0], Instant::now()),
+            Some(Bytes::from("{}"))
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_reassembly_out_of_order() {
+        let message: Vec<u8> = (0..250u8).collect();
+        let fragments = fragment(&message, 1, 42, 100).unwrap();
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|f| f.len() <= 100));
+        assert!(fragments.iter().all(|f| is_fragment(f)));
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        assert_eq!(reassembler.push(source(), &fragments[2], now), None);
+        assert_eq!(reassembler.push(source(), &fragments[0], now), None);
+        assert_eq!(reassembler.push(source(), &fragments[0], now), None); // duplicate
+        assert_eq!(reassembler.push(source(), &fragments[3], now), None);
+        assert_eq!(
+            reassembler.push(source(), &fragments[1], now),
+            Some(Bytes::from(message))
+        );
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_too_many_fragments() {
+        let chunk_size = 100 - HEADER_SIZE;
+        let largest = vec![b'x'; chunk_size * MAX_FRAGMENTS as usize];
+        let fragments = fragment(&largest, 1, 1, 100).unwrap();
+        assert_eq!(fragments.len(), MAX_FRAGMENTS as usize);
+        let mut reassembler = Reassembler::default();
+        let reassembled = fragments
+            .iter()
+            .find_map(|f| reassembler.push(source(), f, Instant::now()));
+        assert_eq!(reassembled, Some(Bytes::from(largest)));
+        let message = vec![b'x'; chunk_size * MAX_FRAGMENTS as usize + 1];
+        assert_eq!(
+            fragment(&message, 1, 1, 100),
+            Err(TooManyFragments {
+                size: message.len(),
+                chunk_size
+            })
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_reassembly_timeout() {
+        let message = vec![b'x'; 300];
+        let fragments = fragment(&message, 1, 7, 100).unwrap();
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        let now = Instant::now();
+        assert_eq!(reassembler.push(source(), &fragments[0], now), None);
+        let later = now + Duration::from_secs(2);
+        for fragment in &fragments[1..] {
+            assert_eq!(reassembler.push(source(), fragment, later), None);
+        }
+        assert_eq!(reassembler.pending.len(), 1); // restarted, first fragment missing
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_invalid_fragments() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let header = Header::new(MessageType::AnswerFragment, Flags::empty(), 1).to_bytes();
+        assert_eq!(reassembler.push(source(), &header, now), None);
+        let invalid_index = [&header, b"\x00\x00\x00\x01\x00\x02\x00\x02x".as_slice()].concat();
+        assert_eq!(reassembler.push(source(), &invalid_index, now), None);
+        assert!(reassembler.pending.is_empty());
+    }
+}
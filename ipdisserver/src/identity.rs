@@ -0,0 +1,227 @@
+//! Stable device identity, sent in every answer so that scanners recognize a device when its IP
+//! changes.
+use crate::answers::BeaconInfos;
+use crate::inventory::{ExecuteInventory, InventoryOutput, CACHE_TTL_DEFAULT};
+use crate::providers::{MACHINE_ID_PATHS, NET_DIR};
+use color_eyre::eyre::{eyre, Report};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+pub const DEVICE_ID_KEY: &str = "device_id";
+const NULL_MAC: &str = "00:00:00:00:00:00";
+/// The machine-id must not be exposed on the network (see machine-id(5)): the device ID is a
+/// keyed hash of it with this application ID.
+const MACHINE_ID_APP_ID: &[u8] = b"ipdisserver-device-id";
+
+/// Where the device ID comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum DeviceIdSource {
+    /// Derived from the machine-id, or the MAC address if there is none.
+    #[default]
+    Auto,
+    /// Derived from the machine-id, which is not sent.
+    MachineId,
+    /// MAC address of the first network interface (by name), physical interfaces first.
+    Mac,
+    /// A configured UUID.
+    Uuid(String),
+}
+
+impl fmt::Display for DeviceIdSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::MachineId => write!(f, "machine-id"),
+            Self::Mac => write!(f, "mac"),
+            Self::Uuid(uuid) => write!(f, "{}", uuid),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("invalid device ID `{0}`, expected one of: auto, machine-id, mac, or a UUID")]
+pub struct DeviceIdError(String);
+
+impl FromStr for DeviceIdSource {
+    type Err = DeviceIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "machine-id" => Ok(Self::MachineId),
+            "mac" => Ok(Self::Mac),
+            uuid if is_uuid(uuid) => Ok(Self::Uuid(uuid.to_lowercase())),
+            other => Err(DeviceIdError(other.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for DeviceIdSource {
+    type Error = DeviceIdError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`, hexadecimal digits.
+fn is_uuid(s: &str) -> bool {
+    s.len() == 36
+        && s.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+/// Outputs the device ID under the device ID key. Failures are reported under the same key in
+/// the diagnostics.
+pub struct DeviceIdInventory {
+    pub source: DeviceIdSource,
+    /// Root of the /etc and /sys paths, changed by tests.
+    pub root: PathBuf,
+    pub cache_ttl: Duration,
+}
+
+impl From<&DeviceIdSource> for DeviceIdInventory {
+    fn from(source: &DeviceIdSource) -> Self {
+        Self {
+            source: source.clone(),
+            root: PathBuf::from("/"),
+            cache_ttl: CACHE_TTL_DEFAULT,
+        }
+    }
+}
+
+impl ExecuteInventory for DeviceIdInventory {
+    fn execute(&self) -> InventoryOutput {
+        let id = match &self.source {
+            DeviceIdSource::Auto => self.machine_id().or_else(|_| self.mac()),
+            DeviceIdSource::MachineId => self.machine_id(),
+            DeviceIdSource::Mac => self.mac(),
+            DeviceIdSource::Uuid(uuid) => Ok(uuid.clone()),
+        };
+        let mut res = BeaconInfos::new();
+        match id {
+            Ok(id) => {
+                res.insert(DEVICE_ID_KEY.into(), id.into());
+                InventoryOutput {
+                    output: res,
+                    ..InventoryOutput::default()
+                }
+            }
+            Err(error) => {
+                res.insert(DEVICE_ID_KEY.into(), error.to_string().into());
+                InventoryOutput {
+                    diagnostics: res,
+                    ..InventoryOutput::default()
+                }
+            }
+        }
+    }
+
+    fn cache_ttl(&self) -> Duration {
+        self.cache_ttl
+    }
+}
+
+impl DeviceIdInventory {
+    /// HMAC-SHA256 of the application ID keyed with the machine-id, truncated to 128 bits as
+    /// the machine-id.
+    fn machine_id(&self) -> Result<String, Report> {
+        let machine_id = MACHINE_ID_PATHS
+            .iter()
+            .filter_map(|path| std::fs::read_to_string(self.root.join(path)).ok())
+            .map(|id| id.trim().to_string())
+            .find(|id| !id.is_empty())
+            .ok_or_else(|| eyre!("no machine-id found"))?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(machine_id.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(MACHINE_ID_APP_ID);
+        Ok(hex::encode(&mac.finalize().into_bytes()[..16]))
+    }
+
+    fn mac(&self) -> Result<String, Report> {
+        let dir = self.root.join(NET_DIR);
+        let mut interfaces: Vec<(bool, String, String)> = Vec::new();
+        for entry in std::fs::read_dir(&dir).map_err(|e| eyre!("{}: {}", dir.display(), e))? {
+            let path = entry?.path();
+            let mac = match std::fs::read_to_string(path.join("address")) {
+                Ok(mac) => mac.trim().to_lowercase(),
+                Err(_) => continue,
+            };
+            if mac.is_empty() || mac == NULL_MAC {
+                continue; // loopback and tunnels
+            }
+            let is_virtual = !path.join("device").exists();
+            let name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into();
+            interfaces.push((is_virtual, name, mac));
+        }
+        interfaces.sort();
+        interfaces
+            .into_iter()
+            .next()
+            .map(|(_, _, mac)| mac)
+            .ok_or_else(|| eyre!("no MAC address found"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn execute(source: DeviceIdSource, root: &Path) -> InventoryOutput {
+        DeviceIdInventory {
+            root: root.into(),
+            ..DeviceIdInventory::from(&source)
+        }
+        .execute()
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_device_id() {
+        let root = std::env::temp_dir().join("rust-ipdisserver-test-identity-root");
+        let _ = std::fs::remove_dir_all(&root);
+        for (path, content) in [
+            ("sys/class/net/lo/address", "00:00:00:00:00:00\n"),
+            ("sys/class/net/br0/address", "02:00:00:00:00:01\n"),
+            ("sys/class/net/eth1/address", "02:00:00:00:00:03\n"),
+            ("sys/class/net/eth1/device/vendor", "0x8086\n"),
+            ("sys/class/net/eth0/address", "02:00:00:00:00:02\n"),
+            ("sys/class/net/eth0/device/vendor", "0x8086\n"),
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let device_id = |source| execute(source, &root).output[DEVICE_ID_KEY].clone();
+        assert_eq!(device_id(DeviceIdSource::Auto), "02:00:00:00:00:02");
+        let output = execute(DeviceIdSource::MachineId, &root);
+        assert_eq!(output.diagnostics[DEVICE_ID_KEY], "no machine-id found");
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(root.join("etc/machine-id"), "3d1219c7c4c5404a\n").unwrap();
+        let derived = device_id(DeviceIdSource::Auto);
+        assert_eq!(derived.as_str().unwrap().len(), 32);
+        assert_eq!(device_id(DeviceIdSource::MachineId), derived); // stable
+        let answer = serde_json::to_string(&execute(DeviceIdSource::Auto, &root).output).unwrap();
+        assert!(!answer.contains("3d1219c7c4c5404a")); // never sent
+        std::fs::write(root.join("etc/machine-id"), "5e8a0c7f2b1d4c3a\n").unwrap();
+        assert_ne!(device_id(DeviceIdSource::Auto), derived);
+        assert_eq!(device_id(DeviceIdSource::Mac), "02:00:00:00:00:02");
+        let uuid = "0B7F9F2C-6E4A-4D0E-9A54-2F1C3B5D7E90".parse().unwrap();
+        assert_eq!(device_id(uuid), "0b7f9f2c-6e4a-4d0e-9a54-2f1c3b5d7e90");
+        assert!(DeviceIdSource::from_str("machineid").is_err());
+    }
+}
@@ -1,11 +1,38 @@
 use crate::answers::{BeaconInfos, FromCmdOutput};
-use crate::exec::InventoryCommand;
+use crate::exec::{InventoryCommand, MAX_OUTPUT_SIZE_DEFAULT, SCRIPT_TIMEOUT_DEFAULT};
 use crate::hostname::get_hostname;
+use color_eyre::eyre::Report;
+use serde::Deserialize;
+use serde_json::Value;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::warn;
+
+/// Outputs are reused by the server for this long. A zero TTL disables the cache: the inventory
+/// is executed for every answer.
+pub const CACHE_TTL_DEFAULT: Duration = Duration::from_secs(60);
+/// Separates the options (cache TTL and timeout) from the path in inventory file arguments.
+const OPTIONS_SEPARATOR: char = '@';
+/// Stripped from the script names when namespacing, as in Mender inventory directories.
+const MENDER_SCRIPT_PREFIX: &str = "mender-inventory-";
+
+/// Format of the inventory file outputs.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum InventoryFormat {
+    /// Mender `key=value` lines, or JSON if the first line is the JSON marker.
+    #[default]
+    KeyValue,
+    /// A JSON object.
+    Json,
+}
 
 pub struct InternalInventory {
     pub key: String,
-    pub source: Box<dyn Fn() -> String>,
+    pub source: Box<dyn Fn() -> String + Send + Sync>,
+    pub cache_ttl: Duration,
 }
 
 impl Default for InternalInventory {
@@ -13,23 +40,220 @@ impl Default for InternalInventory {
         Self {
             key: "hostname".into(),
             source: Box::from(get_hostname),
+            cache_ttl: CACHE_TTL_DEFAULT,
         }
     }
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct InventoryFile {
     pub path: PathBuf,
+    pub cache_ttl: Duration,
+    /// The script is killed (with its children) if it runs longer.
+    pub timeout: Duration,
+    /// Outputs larger than this (in bytes) are discarded, and the script killed.
+    pub max_output_size: usize,
+    /// If set, outputs are nested under it, e.g. `{"network": {"ipv4": ...}}`.
+    pub namespace: Option<String>,
+    pub format: InventoryFormat,
+}
+
+impl Default for InventoryFile {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::default(),
+            cache_ttl: CACHE_TTL_DEFAULT,
+            timeout: SCRIPT_TIMEOUT_DEFAULT,
+            max_output_size: MAX_OUTPUT_SIZE_DEFAULT,
+            namespace: None,
+            format: InventoryFormat::default(),
+        }
+    }
 }
 
 impl From<&Path> for InventoryFile {
     fn from(path: &Path) -> Self {
-        Self { path: path.into() }
+        Self {
+            path: path.into(),
+            ..Self::default()
+        }
+    }
+}
+
+/// An unknown or invalid option after the `@` of an inventory argument.
+#[derive(Error, Debug, PartialEq)]
+#[error(
+    "invalid option `{option}` in `{arg}`, expected ttl=SECS, timeout=SECS (at least 1), format=key-value|json \
+    or, for directories, namespace"
+)]
+pub struct InventoryArgError {
+    arg: String,
+    option: String,
+}
+
+impl InventoryArgError {
+    fn new(arg: &str, option: &str) -> Self {
+        Self {
+            arg: arg.to_string(),
+            option: option.to_string(),
+        }
+    }
+}
+
+impl InventoryFile {
+    /// Parse a `PATH[@OPTIONS]` argument. Options are comma separated, `ttl=SECS`,
+    /// `timeout=SECS` and `format=key-value|json`; a bare number is the TTL. Unspecified
+    /// options are taken from `defaults`. The `@` is part of the path only if the whole
+    /// argument is an existing file.
+    pub fn from_arg(arg: &str, defaults: &InventoryFile) -> Result<Self, InventoryArgError> {
+        match arg.rsplit_once(OPTIONS_SEPARATOR) {
+            Some((path, options)) if !Path::new(arg).exists() => {
+                Self::with_options(arg, path, options, defaults)
+            }
+            _ => Ok(Self {
+                path: arg.into(),
+                ..defaults.clone()
+            }),
+        }
+    }
+
+    fn with_options(
+        arg: &str,
+        path: &str,
+        options: &str,
+        defaults: &InventoryFile,
+    ) -> Result<Self, InventoryArgError> {
+        let mut file = Self {
+            path: path.into(),
+            ..defaults.clone()
+        };
+        for option in options.split(',') {
+            file.set_option(option)
+                .ok_or_else(|| InventoryArgError::new(arg, option))?;
+        }
+        Ok(file)
+    }
+
+    /// Set a `NAME=VALUE` option, None if not valid.
+    fn set_option(&mut self, option: &str) -> Option<()> {
+        let (name, value) = option.split_once('=').unwrap_or(("ttl", option));
+        let secs = || value.parse().ok().map(Duration::from_secs);
+        match name {
+            "ttl" => self.cache_ttl = secs()?,
+            "timeout" => self.timeout = secs().filter(|timeout| !timeout.is_zero())?,
+            "format" => {
+                self.format = match value {
+                    "key-value" => InventoryFormat::KeyValue,
+                    "json" => InventoryFormat::Json,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// A directory of inventory scripts (e.g. Mender `inventory.d`). Scripts are discovered every
+/// time the directory is scanned, and executed in file name order.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct InventoryDir {
+    pub path: PathBuf,
+    /// Nest the outputs under the script name (without the `mender-inventory-` prefix), so that
+    /// scripts do not override each other keys.
+    pub namespace: bool,
+    /// Options of the discovered scripts, the path is ignored.
+    pub file_options: InventoryFile,
+}
+
+impl InventoryDir {
+    /// Parse a `DIR[@OPTIONS]` argument. Options are the inventory file ones, plus `namespace`.
+    /// The `@` is part of the path only if the whole argument is an existing directory.
+    pub fn from_arg(arg: &str, defaults: &InventoryFile) -> Result<Self, InventoryArgError> {
+        match arg.rsplit_once(OPTIONS_SEPARATOR) {
+            Some((path, options)) if !Path::new(arg).exists() => {
+                Self::with_options(arg, path, options, defaults)
+            }
+            _ => Ok(Self {
+                path: arg.into(),
+                namespace: false,
+                file_options: defaults.clone(),
+            }),
+        }
+    }
+
+    fn with_options(
+        arg: &str,
+        path: &str,
+        options: &str,
+        defaults: &InventoryFile,
+    ) -> Result<Self, InventoryArgError> {
+        let mut dir = Self {
+            path: path.into(),
+            namespace: false,
+            file_options: defaults.clone(),
+        };
+        for option in options.split(',') {
+            match option {
+                "namespace" => dir.namespace = true,
+                other => dir
+                    .file_options
+                    .set_option(other)
+                    .ok_or_else(|| InventoryArgError::new(arg, other))?,
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Executable files of the directory, sorted by name. Hidden files are skipped.
+    pub fn scripts(&self) -> Vec<InventoryFile> {
+        let entries = match std::fs::read_dir(&self.path) {
+            Ok(e) => e,
+            Err(error) => {
+                warn!(path = ?self.path, %error, "Failed reading inventory directory.");
+                return Vec::new();
+            }
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .map(|entry| entry.path())
+            .filter(|path| is_executable(path))
+            .collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .map(|path| InventoryFile {
+                namespace: self.namespace.then(|| script_namespace(&path)),
+                path,
+                ..self.file_options.clone()
+            })
+            .collect()
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    match std::fs::metadata(path) {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+fn script_namespace(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match name.strip_prefix(MENDER_SCRIPT_PREFIX) {
+        Some(stripped) if !stripped.is_empty() => stripped.into(),
+        _ => name,
     }
 }
 
 pub trait ExecuteInventory {
     fn execute(&self) -> InventoryOutput;
+    /// How long the output can be reused.
+    fn cache_ttl(&self) -> Duration;
 }
 
 impl ExecuteInventory for InternalInventory {
@@ -37,16 +261,57 @@ impl ExecuteInventory for InternalInventory {
         let raw_output = (*self.source)();
         let mut output = BeaconInfos::new();
         output.insert(self.key.clone(), raw_output.clone().into());
-        InventoryOutput { raw_output, output }
+        InventoryOutput {
+            raw_output,
+            output,
+            ..InventoryOutput::default()
+        }
+    }
+
+    fn cache_ttl(&self) -> Duration {
+        self.cache_ttl
     }
 }
 
 impl ExecuteInventory for InventoryFile {
     fn execute(&self) -> InventoryOutput {
-        let mut command = InventoryCommand::new(&self.path);
-        let raw_output = command.output();
-        let output = BeaconInfos::from_cmd_output(&raw_output).unwrap_or_default();
-        InventoryOutput { raw_output, output }
+        let mut command = InventoryCommand::new(&self.path, self.timeout, self.max_output_size);
+        let parsed = command
+            .output()
+            .map_err(Report::from)
+            .and_then(|raw_output| {
+                let output = match self.format {
+                    InventoryFormat::KeyValue => BeaconInfos::from_cmd_output(&raw_output),
+                    InventoryFormat::Json => BeaconInfos::from_json_output(&raw_output),
+                }?;
+                Ok((raw_output, output))
+            });
+        match parsed {
+            Ok((raw_output, mut output)) => {
+                if let Some(namespace) = &self.namespace {
+                    let mut nested = BeaconInfos::new();
+                    nested.insert(namespace.clone(), Value::Object(output));
+                    output = nested;
+                }
+                InventoryOutput {
+                    raw_output,
+                    output,
+                    ..InventoryOutput::default()
+                }
+            }
+            Err(error) => {
+                let mut diagnostics = BeaconInfos::new();
+                diagnostics.insert(self.path.display().to_string(), error.to_string().into());
+                InventoryOutput {
+                    diagnostics,
+                    ..InventoryOutput::default()
+                }
+            }
+        }
+    }
+
+    fn cache_ttl(&self) -> Duration {
+        self.cache_ttl
     }
 }
 
@@ -54,4 +319,125 @@ impl ExecuteInventory for InventoryFile {
 pub struct InventoryOutput {
     pub raw_output: String,
     pub output: BeaconInfos,
+    /// Failures of the inventory, reported to the scanner under the diagnostics key.
+    pub diagnostics: BeaconInfos,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_inventory_file_from_arg() {
+        let defaults = InventoryFile {
+            cache_ttl: Duration::from_secs(5),
+            ..InventoryFile::default()
+        };
+        assert_eq!(
+            InventoryFile::from_arg("/usr/bin/inventory", &defaults).unwrap(),
+            InventoryFile {
+                path: "/usr/bin/inventory".into(),
+                ..defaults.clone()
+            }
+        );
+        assert_eq!(
+            InventoryFile::from_arg("/usr/bin/inventory@0", &defaults)
+                .unwrap()
+                .cache_ttl,
+            Duration::ZERO
+        );
+        assert_eq!(
+            InventoryFile::from_arg("/opt/v@2/inventory@300", &defaults).unwrap(),
+            InventoryFile {
+                path: "/opt/v@2/inventory".into(),
+                cache_ttl: Duration::from_secs(300),
+                ..defaults.clone()
+            }
+        );
+        assert_eq!(
+            InventoryFile::from_arg("/usr/bin/inventory@timeout=3,ttl=30", &defaults).unwrap(),
+            InventoryFile {
+                path: "/usr/bin/inventory".into(),
+                cache_ttl: Duration::from_secs(30),
+                timeout: Duration::from_secs(3),
+                ..defaults.clone()
+            }
+        );
+        assert_eq!(
+            InventoryFile::from_arg("/usr/bin/inventory@format=json", &defaults)
+                .unwrap()
+                .format,
+            InventoryFormat::Json
+        );
+        assert_eq!(
+            InventoryFile::from_arg("/usr/bin/inventory@tll=30", &defaults),
+            Err(InventoryArgError::new(
+                "/usr/bin/inventory@tll=30",
+                "tll=30"
+            ))
+        );
+        assert_eq!(
+            InventoryFile::from_arg("/usr/bin/inventory@ttl=30,timeout=abc", &defaults),
+            Err(InventoryArgError::new(
+                "/usr/bin/inventory@ttl=30,timeout=abc",
+                "timeout=abc"
+            ))
+        );
+        assert_eq!(
+            InventoryFile::from_arg("/usr/bin/inventory@timeout=0", &defaults),
+            Err(InventoryArgError::new(
+                "/usr/bin/inventory@timeout=0",
+                "timeout=0"
+            ))
+        );
+        let file = std::env::temp_dir().join("rust-ipdisserver-test-inventory@host");
+        std::fs::write(&file, "").unwrap();
+        let arg = file.to_str().unwrap();
+        assert_eq!(InventoryFile::from_arg(arg, &defaults).unwrap().path, file);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_inventory_file_diagnostics() {
+        let file = InventoryFile::from(Path::new("non-existing-file"));
+        let output = file.execute();
+        assert!(output.output.is_empty());
+        assert!(output.diagnostics["non-existing-file"]
+            .as_str()
+            .unwrap()
+            .starts_with("failed executing"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_inventory_dir_scripts() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join("rust-ipdisserver-test-inventory-dir");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        for (name, mode) in [
+            ("mender-inventory-os", 0o755),
+            ("10-network", 0o755),
+            (".hidden", 0o755),
+            ("README", 0o644),
+        ] {
+            let path = dir.join(name);
+            std::fs::write(&path, "#!/bin/sh\necho 'key=value'").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let arg = format!("{}@namespace,timeout=3", dir.display());
+        let inventory_dir = InventoryDir::from_arg(&arg, &InventoryFile::default()).unwrap();
+        assert!(inventory_dir.namespace);
+        let scripts = inventory_dir.scripts();
+        assert_eq!(
+            scripts
+                .iter()
+                .map(|s| s.namespace.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["10-network", "os"]
+        );
+        assert_eq!(scripts[0].timeout, Duration::from_secs(3));
+        assert_eq!(scripts[1].execute().output["os"]["key"], "value");
+    }
 }
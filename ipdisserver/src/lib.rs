@@ -1,9 +1,21 @@
+pub mod addresses;
 pub mod answers;
+pub mod audit;
+pub mod auth;
 pub mod bytes;
+pub mod cache;
 pub mod conf;
+pub mod conf_file;
+pub mod crypto;
 pub mod exec;
+pub mod fragment;
 pub mod hostname;
+pub mod identity;
 pub mod inventory;
+pub mod net;
+pub mod protocol;
+pub mod providers;
 pub mod server;
 pub mod setup;
 pub mod signature;
+pub mod sources;
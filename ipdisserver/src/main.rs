@@ -1,22 +1,63 @@
 use clap::{App, Arg};
-use color_eyre::{eyre::Report, eyre::WrapErr};
+use color_eyre::{eyre::eyre, eyre::Report, eyre::WrapErr};
+use ipdisserver::audit::AuditLog;
+use ipdisserver::auth::SharedKey;
 use ipdisserver::conf::ServerConfig;
+use ipdisserver::conf_file::ConfigFile;
+use ipdisserver::crypto::PublicKey;
+use ipdisserver::inventory::{InventoryDir, InventoryFile};
+use ipdisserver::providers::Provider;
 use ipdisserver::server;
 use ipdisserver::setup::setup;
-use std::net::Ipv4Addr;
-use std::path::Path;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use tracing::{debug, info, trace};
 
 fn main() -> Result<(), Report> {
+    const CONFIG_OPT: &str = "config";
     const PORT_OPT: &str = "port";
     const ADDR_OPT: &str = "addr";
+    const INTERFACE_OPT: &str = "interface";
+    const MULTICAST_ADDR_OPT: &str = "multicast_addr";
     const SIGNATURES_OPT: &str = "signatures";
     const INVENTORY_OPT: &str = "inventory";
+    const INVENTORY_DIR_OPT: &str = "inventory_dir";
+    const PROVIDERS_OPT: &str = "providers";
+    const DEVICE_ID_OPT: &str = "device_id";
+    const CACHE_TTL_OPT: &str = "cache_ttl";
+    const SCRIPT_TIMEOUT_OPT: &str = "script_timeout";
+    const MAX_OUTPUT_SIZE_OPT: &str = "max_output_size";
     const JOURNALD_OPT: &str = "journald";
+    const SHARED_KEY_OPT: &str = "shared_key";
+    const REPLAY_WINDOW_OPT: &str = "replay_window";
+    const ENCRYPT_TO_OPT: &str = "encrypt_to";
+    const MAX_DATAGRAM_SIZE_OPT: &str = "max_datagram_size";
+    const SOURCE_RULE_OPT: &str = "source_rule";
+    const ALLOW_SOURCE_OPT: &str = "allow_source";
+    const MAX_AMPLIFICATION_OPT: &str = "max_amplification";
+    const ONLY_BROADCAST_OPT: &str = "only_broadcast";
+    const RATE_LIMIT_PERIOD_OPT: &str = "rate_limit_period";
+    const RATE_LIMIT_BURST_OPT: &str = "rate_limit_burst";
+    const GLOBAL_RATE_LIMIT_OPT: &str = "global_rate_limit";
+    const RATE_LIMITED_SOURCES_OPT: &str = "rate_limited_sources";
+    const WORKERS_OPT: &str = "workers";
+    const QUEUE_SIZE_OPT: &str = "queue_size";
+    const AUDIT_LOG_OPT: &str = "audit_log";
+    const AUDIT_JOURNALD_OPT: &str = "audit_journald";
+    const ALERT_HOOK_OPT: &str = "alert_hook";
     let matches = App::new("ipdisserver")
         .version("0.1.1")
         .about("Answer with system info to ipdisscan broadcasts.")
+        .arg(
+            Arg::with_name(CONFIG_OPT)
+                .short("c")
+                .long("config")
+                .value_name("CONFIG_FILE")
+                .help("Path of a TOML configuration file. Options given on the command line take precedence. The file is read again on SIGHUP (except the listening port and addresses, and the logging settings).")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name(PORT_OPT)
                 .short("p")
@@ -30,7 +71,27 @@ fn main() -> Result<(), Report> {
                 .short("a")
                 .long("listening-addr")
                 .value_name("ADDR")
-                .help("Default: 0.0.0.0")
+                .help("IPv4 or IPv6 listening address. Repeat the option for each address. Default: 0.0.0.0 and :: (dual-stack).")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(INTERFACE_OPT)
+                .short("i")
+                .long("interface")
+                .value_name("INTERFACE")
+                .help("Receive requests only on this interface (e.g. the LAN side of a gateway), binding a socket to it for every listening address. Interfaces appearing and disappearing while running are followed. Repeat the option for each interface. Linux only. Default: every interface.")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MULTICAST_ADDR_OPT)
+                .short("m")
+                .long("multicast-addr")
+                .value_name("MULTICAST_ADDR")
+                .help("IPv6 multicast group joined on every interface when listening on `::`. Default: ff02::1901.")
                 .takes_value(true),
         )
         .arg(
@@ -46,11 +107,184 @@ fn main() -> Result<(), Report> {
                 .short("f")
                 .long("answer-file")
                 .value_name("ANSWER_FILE")
-                .help(r#"Specify a list of files to execute, the output will be added to the answer. The output must be in the format `key0=value0\nkey1=value1\n...`. Repeat the option for each file."#)
+                .help(r#"Specify a list of files to execute, the output will be added to the answer. The output must be in the format `key0=value0\nkey1=value1\n...`, keys may end with a type hint (`:int`, `:float`, `:bool`, `:json`, `:str`) and dotted keys build nested objects (`net.eth0.ipv4=...`). The output is parsed as a JSON object if its first line is `#ipdis:json`, or with the `format=json` option. Repeat the option for each file. The output is cached for --cache-ttl seconds, or for the seconds given after `@` (e.g. `/usr/bin/inventory-network@10`, `@0` disables the cache). The timeout can be overridden too, comma separated options are accepted after `@` (e.g. `/usr/bin/inventory-network@ttl=10,timeout=2,format=json`), invalid options are refused unless the whole argument is an existing file. Failures are reported in the answer under the `diagnostics` key."#)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(INVENTORY_DIR_OPT)
+                .short("D")
+                .long("inventory-dir")
+                .value_name("DIR")
+                .help("Directory of answer files (e.g. Mender `inventory.d`): every executable file is executed, in file name order, after the --answer-file ones. Added and removed files are picked up while running. Options are accepted after `@` as for --answer-file, plus `namespace` to nest the outputs under the file name, without the `mender-inventory-` prefix (e.g. `/usr/share/mender/inventory@namespace,ttl=30` gives `{\"network\": {\"ipv4\": ...}}`). Repeat the option for each directory.")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(PROVIDERS_OPT)
+                .short("P")
+                .long("providers")
+                .value_name("PROVIDERS")
+                .use_delimiter(true)
+                .help("Comma separated built-in inventories, reading /proc, /sys and /etc without executing any file: interfaces, os-release, kernel, uptime, cpu, memory, disk, machine-id, dmi-serial, or `all`. Their output is cached as the hostname. Default: none.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(DEVICE_ID_OPT)
+                .long("device-id")
+                .value_name("SOURCE")
+                .help("Stable identity sent in every answer under `device_id`, so that scanners recognize the device when its IP changes: `machine-id` (a keyed hash of the machine-id, not sent itself), `mac` (MAC address of the first network interface), `auto` (machine-id, or mac if there is no machine-id) or a UUID. Default: auto.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(CACHE_TTL_OPT)
+                .short("t")
+                .long("cache-ttl")
+                .value_name("SECS")
+                .help("Answer files output and hostname are reused for this long, and refreshed in background. 0 disables the cache: answer files are executed for every answer. Default: 60.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(SCRIPT_TIMEOUT_OPT)
+                .long("script-timeout")
+                .value_name("SECS")
+                .help("Answer files running longer are killed, with their child processes. At least 1. Default: 10.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MAX_OUTPUT_SIZE_OPT)
+                .long("max-output-size")
+                .value_name("BYTES")
+                .help("Answer files writing more than this on stdout are killed, and their output discarded. Default: 65536.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(SHARED_KEY_OPT)
+                .short("k")
+                .long("shared-key-file")
+                .value_name("KEY_FILE")
+                .help("Path of a file containing a secret shared with the scanners. If specified, only requests authenticated with the key (HMAC-SHA256) are answered, signatures are ignored, and answers are authenticated too.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(REPLAY_WINDOW_OPT)
+                .long("replay-window")
+                .value_name("SECS")
+                .help("Maximum clock difference accepted for authenticated requests. Default: 30.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(ENCRYPT_TO_OPT)
+                .short("e")
+                .long("encrypt-to")
+                .value_name("PUBLIC_KEY_FILE")
+                .help("Path of a scanner public key file (see `ipdisscan --generate-key`). If specified, answers are encrypted and only the scanners owning the matching private keys can read them. Repeat the option for each scanner key.")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MAX_DATAGRAM_SIZE_OPT)
+                .long("max-datagram-size")
+                .value_name("BYTES")
+                .help("Answers larger than this are split in more datagrams, reassembled by ipdisscan. Default: 1200.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(SOURCE_RULE_OPT)
+                .long("source-rule")
+                .value_name("RULE")
+                .help("Allow or deny the requests from a source range, e.g. `deny 10.0.5.0/24`, optionally only those with a signature, e.g. `allow 203.0.113.0/24@lab-beacon`. Rules are evaluated in order before the allowed sources, the first matching one applies. Repeat the option for each rule.")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(ALLOW_SOURCE_OPT)
+                .long("allow-source")
+                .value_name("RANGE")
+                .help("Answer only sources in this range: `local` (the interface subnets and loopback), `private` (private, loopback and link-local addresses), `any`, an address or a network (e.g. 203.0.113.0/24). Repeat the option for each range. Default: local and private.")
                 .multiple(true)
                 .number_of_values(1)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name(MAX_AMPLIFICATION_OPT)
+                .long("max-amplification")
+                .value_name("RATIO")
+                .help("Do not send answers larger than this many times the request, against reflection attacks. ipdisscan pads its requests; requests of older scanners cannot be padded, they are answered only if the check is disabled. 0 disables the check. Default: 8.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(ONLY_BROADCAST_OPT)
+                .long("only-broadcast")
+                .takes_value(false)
+                .help("Answer only requests sent to a broadcast or multicast address, not unicast ones (e.g. ipdisscan --sweep). Linux only.")
+        )
+        .arg(
+            Arg::with_name(RATE_LIMIT_PERIOD_OPT)
+                .long("rate-limit-period")
+                .value_name("SECS")
+                .help("Each source IP is answered at most once in this many seconds, after using up the burst. At least 1. Default: 10.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(RATE_LIMIT_BURST_OPT)
+                .long("rate-limit-burst")
+                .value_name("N")
+                .help("Answers to a same source IP without waiting for the rate limit period. Default: 1.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(GLOBAL_RATE_LIMIT_OPT)
+                .long("global-rate-limit")
+                .value_name("RATE")
+                .help("Maximum number of answers per second, to all the sources. At least 0.001. Default: 100.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(RATE_LIMITED_SOURCES_OPT)
+                .long("rate-limited-sources")
+                .value_name("N")
+                .help("Source IPs tracked by the rate limiter, the least recently seen are forgotten. Default: 4096.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(WORKERS_OPT)
+                .long("workers")
+                .value_name("N")
+                .help("Threads answering the requests. Default: 4.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(QUEUE_SIZE_OPT)
+                .long("queue-size")
+                .value_name("N")
+                .help("Requests waiting for a worker, more are dropped until the workers catch up. Default: 64.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(AUDIT_LOG_OPT)
+                .long("audit-log")
+                .value_name("FILE")
+                .help("Record every request (source, signature check, outcome, answer size) in this file, as JSON lines. The file is rotated when larger than 10 MiB, keeping 5 previous files.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(AUDIT_JOURNALD_OPT)
+                .long("audit-to-journald")
+                .conflicts_with(AUDIT_LOG_OPT)
+                .help("Record every request in systemd-journald, with IPDIS_* fields.")
+        )
+        .arg(
+            Arg::with_name(ALERT_HOOK_OPT)
+                .long("alert-hook")
+                .value_name("PATH")
+                .help("Executed when a source sends too many invalid signatures (20 in 60 seconds by default), with IPDIS_SOURCE, IPDIS_INVALID_SIGNATURES and IPDIS_PERIOD in the environment.")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name(JOURNALD_OPT)
                 .short("j")
@@ -61,38 +295,237 @@ fn main() -> Result<(), Report> {
         )
         .get_matches();
 
-    let do_log_to_journald = matches.is_present(JOURNALD_OPT);
-    setup(do_log_to_journald)?;
+    let config_path = matches.value_of(CONFIG_OPT).map(PathBuf::from);
+    let config_file = config_path.as_deref().map(ConfigFile::read).transpose();
+    let log = match &config_file {
+        Ok(Some(file)) => file.log.clone(),
+        _ => Default::default(),
+    };
+    let do_log_to_journald = matches.is_present(JOURNALD_OPT) || log.journald;
+    setup(do_log_to_journald, log.level.as_deref())?;
     debug!("Tracing setup complete, starting IP discovery server.");
     trace!(?matches);
 
-    let mut conf = ServerConfig::default();
-    if matches.is_present(PORT_OPT) {
-        conf.port = matches
-            .value_of(PORT_OPT)
-            .unwrap()
-            .parse()
-            .wrap_err("Invalid port given")?;
-    }
-    if matches.is_present(ADDR_OPT) {
-        let addr = matches.value_of(ADDR_OPT).unwrap().parse::<String>()?;
-        conf.listening_addr = Ipv4Addr::from_str(&addr).wrap_err("Invalid IP v4 given")?;
-    }
-    if matches.is_present(SIGNATURES_OPT) {
-        conf.signatures = ServerConfig::parse_signatures_file(Path::new(
-            matches.value_of(SIGNATURES_OPT).unwrap(),
-        ))?;
-        info!("Accepted signatures: {:?}", conf.signatures);
-    }
-    if matches.is_present(INVENTORY_OPT) {
-        conf.inventory_files = matches
-            .values_of(INVENTORY_OPT)
-            .unwrap()
-            .into_iter()
-            .map(Path::new)
-            .collect();
-    }
+    // Configuration file values, overridden by command line options.
+    let build_conf = |config_file: Option<ConfigFile>| -> Result<ServerConfig, Report> {
+        let mut cache_ttl: Option<u64> = matches
+            .value_of(CACHE_TTL_OPT)
+            .map(str::parse)
+            .transpose()
+            .wrap_err("Invalid cache TTL given")?;
+        let mut script_timeout = match matches.value_of(SCRIPT_TIMEOUT_OPT).map(str::parse) {
+            Some(Ok(0)) => return Err(eyre!("The script timeout must be at least 1")),
+            secs => secs.transpose().wrap_err("Invalid script timeout given")?,
+        };
+        let mut max_output_size: Option<usize> = matches
+            .value_of(MAX_OUTPUT_SIZE_OPT)
+            .map(str::parse)
+            .transpose()
+            .wrap_err("Invalid maximum output size given")?;
+        let mut conf = match config_file {
+            // The inventory options override the top level keys, so that they are the defaults
+            // of the file entries too.
+            Some(mut file) => {
+                cache_ttl = cache_ttl.or(file.cache_ttl);
+                script_timeout = script_timeout.or(file.script_timeout);
+                max_output_size = max_output_size.or(file.max_output_size);
+                file.cache_ttl = cache_ttl;
+                file.script_timeout = script_timeout;
+                file.max_output_size = max_output_size;
+                file.to_server_config()?
+            }
+            None => ServerConfig::default(),
+        };
+        if matches.is_present(PORT_OPT) {
+            conf.port = matches
+                .value_of(PORT_OPT)
+                .unwrap()
+                .parse()
+                .wrap_err("Invalid port given")?;
+        }
+        if matches.is_present(ADDR_OPT) {
+            conf.listening_addrs = matches
+                .values_of(ADDR_OPT)
+                .unwrap()
+                .map(IpAddr::from_str)
+                .collect::<Result<_, _>>()
+                .wrap_err("Invalid IP given")?;
+        }
+        if matches.is_present(INTERFACE_OPT) {
+            conf.interfaces = matches
+                .values_of(INTERFACE_OPT)
+                .unwrap()
+                .map(String::from)
+                .collect();
+        }
+        if matches.is_present(MULTICAST_ADDR_OPT) {
+            let addr = matches.value_of(MULTICAST_ADDR_OPT).unwrap();
+            conf.multicast_addr_v6 = Ipv6Addr::from_str(addr).wrap_err("Invalid IP v6 given")?;
+        }
+        if matches.is_present(SIGNATURES_OPT) {
+            conf.signatures = ServerConfig::parse_signatures_file(Path::new(
+                matches.value_of(SIGNATURES_OPT).unwrap(),
+            ))?;
+            info!("Accepted signatures: {:?}", conf.signatures);
+        }
+        if let Some(secs) = cache_ttl {
+            conf.hostname_cache_ttl = Duration::from_secs(secs);
+        }
+        let mut inventory_defaults = InventoryFile {
+            cache_ttl: conf.hostname_cache_ttl,
+            ..InventoryFile::default()
+        };
+        if let Some(secs) = script_timeout {
+            inventory_defaults.timeout = Duration::from_secs(secs);
+        }
+        if let Some(size) = max_output_size {
+            inventory_defaults.max_output_size = size;
+        }
+        if matches.is_present(INVENTORY_OPT) {
+            conf.inventory_files = matches
+                .values_of(INVENTORY_OPT)
+                .unwrap()
+                .map(|arg| InventoryFile::from_arg(arg, &inventory_defaults))
+                .collect::<Result<_, _>>()?;
+        }
+        if matches.is_present(PROVIDERS_OPT) {
+            conf.providers = Vec::new();
+            for name in matches.values_of(PROVIDERS_OPT).unwrap() {
+                match name {
+                    "all" => conf.providers.extend(Provider::ALL),
+                    name => conf.providers.push(name.parse()?),
+                }
+            }
+        }
+        if matches.is_present(DEVICE_ID_OPT) {
+            conf.device_id = matches.value_of(DEVICE_ID_OPT).unwrap().parse()?;
+        }
+        if matches.is_present(INVENTORY_DIR_OPT) {
+            conf.inventory_dirs = matches
+                .values_of(INVENTORY_DIR_OPT)
+                .unwrap()
+                .map(|arg| InventoryDir::from_arg(arg, &inventory_defaults))
+                .collect::<Result<_, _>>()?;
+        }
+        if matches.is_present(SHARED_KEY_OPT) {
+            conf.shared_key = Some(SharedKey::from_file(Path::new(
+                matches.value_of(SHARED_KEY_OPT).unwrap(),
+            ))?);
+        }
+        if matches.is_present(REPLAY_WINDOW_OPT) {
+            conf.replay_window = Duration::from_secs(
+                matches
+                    .value_of(REPLAY_WINDOW_OPT)
+                    .unwrap()
+                    .parse()
+                    .wrap_err("Invalid replay window given")?,
+            );
+        }
+        if matches.is_present(ENCRYPT_TO_OPT) {
+            conf.encryption_keys = matches
+                .values_of(ENCRYPT_TO_OPT)
+                .unwrap()
+                .map(|path| PublicKey::from_file(Path::new(path)))
+                .collect::<Result<_, _>>()?;
+        }
+        if matches.is_present(MAX_DATAGRAM_SIZE_OPT) {
+            conf.max_datagram_size = matches
+                .value_of(MAX_DATAGRAM_SIZE_OPT)
+                .unwrap()
+                .parse()
+                .wrap_err("Invalid datagram size given")?;
+        }
+        if matches.is_present(SOURCE_RULE_OPT) {
+            conf.source_rules = Vec::new();
+            for rule in matches.values_of(SOURCE_RULE_OPT).unwrap() {
+                conf.source_rules.push(rule.parse()?);
+            }
+        }
+        if matches.is_present(ALLOW_SOURCE_OPT) {
+            conf.allowed_sources = Vec::new();
+            for range in matches.values_of(ALLOW_SOURCE_OPT).unwrap() {
+                conf.allowed_sources.push(range.parse()?);
+            }
+        }
+        if matches.is_present(MAX_AMPLIFICATION_OPT) {
+            conf.max_amplification = match matches.value_of(MAX_AMPLIFICATION_OPT).unwrap().parse()
+            {
+                Ok(ratio) if ratio >= 0.0 && f64::is_finite(ratio) => ratio,
+                _ => return Err(eyre!("Invalid amplification ratio given")),
+            };
+        }
+        if matches.is_present(ONLY_BROADCAST_OPT) {
+            conf.only_broadcast = true;
+        }
+        if matches.is_present(RATE_LIMIT_PERIOD_OPT) {
+            conf.rate_limit_period = match matches.value_of(RATE_LIMIT_PERIOD_OPT).unwrap().parse()
+            {
+                Ok(0) => return Err(eyre!("The rate limit period must be at least 1")),
+                secs => Duration::from_secs(secs.wrap_err("Invalid rate limit period given")?),
+            };
+        }
+        if matches.is_present(RATE_LIMIT_BURST_OPT) {
+            conf.rate_limit_burst = match matches.value_of(RATE_LIMIT_BURST_OPT).unwrap().parse() {
+                Ok(0) => return Err(eyre!("The rate limit burst must be at least 1")),
+                burst => burst.wrap_err("Invalid rate limit burst given")?,
+            };
+        }
+        if matches.is_present(GLOBAL_RATE_LIMIT_OPT) {
+            conf.global_rate_limit = match matches.value_of(GLOBAL_RATE_LIMIT_OPT).unwrap().parse()
+            {
+                Ok(rate) if f64::is_finite(rate) && rate >= server::GLOBAL_RATE_LIMIT_MIN => rate,
+                _ => return Err(eyre!("Invalid global rate limit given")),
+            };
+        }
+        if matches.is_present(RATE_LIMITED_SOURCES_OPT) {
+            conf.rate_limited_sources =
+                match matches.value_of(RATE_LIMITED_SOURCES_OPT).unwrap().parse() {
+                    Ok(0) => return Err(eyre!("At least one rate limited source is needed")),
+                    max => max.wrap_err("Invalid number of rate limited sources given")?,
+                };
+        }
+        if matches.is_present(WORKERS_OPT) {
+            conf.workers = match matches.value_of(WORKERS_OPT).unwrap().parse() {
+                Ok(0) => return Err(eyre!("At least one worker is needed")),
+                workers => workers.wrap_err("Invalid number of workers given")?,
+            };
+        }
+        if matches.is_present(QUEUE_SIZE_OPT) {
+            conf.queue_size = match matches.value_of(QUEUE_SIZE_OPT).unwrap().parse() {
+                Ok(0) => return Err(eyre!("The queue size must be at least 1")),
+                size => size.wrap_err("Invalid queue size given")?,
+            };
+        }
+        if let Some(path) = matches.value_of(AUDIT_LOG_OPT) {
+            conf.audit_log = match conf.audit_log {
+                Some(AuditLog::File {
+                    max_size,
+                    rotated_files,
+                    ..
+                }) => Some(AuditLog::File {
+                    path: path.into(),
+                    max_size,
+                    rotated_files,
+                }),
+                _ => Some(AuditLog::file(path.into())),
+            };
+        }
+        if matches.is_present(AUDIT_JOURNALD_OPT) {
+            conf.audit_log = Some(AuditLog::Journald);
+        }
+        if let Some(path) = matches.value_of(ALERT_HOOK_OPT) {
+            conf.alert_hook = Some(path.into());
+        }
+        Ok(conf)
+    };
 
-    server::run(&conf)?;
+    let conf = build_conf(config_file?)?;
+    match &config_path {
+        Some(path) => {
+            let reload = || build_conf(Some(ConfigFile::read(path)?));
+            server::run(&conf, Some(&reload))?;
+        }
+        None => server::run(&conf, None)?,
+    }
     Ok(())
 }
@@ -0,0 +1,507 @@
+use color_eyre::eyre::{eyre, Report};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace, warn};
+
+/// Bind an UDP socket. IPv6 sockets are bound as IPv6-only, so that an IPv4 and an IPv6 socket
+/// can share the same port (dual-stack).
+pub fn bind_udp(addr: SocketAddr) -> Result<UdpSocket, Report> {
+    bind_udp_on(addr, None)
+}
+
+/// Bind an UDP socket as `bind_udp`. If an interface is given, the socket receives only the
+/// datagrams arriving on it (SO_BINDTODEVICE, Linux only), so that more sockets bound to
+/// different interfaces can share the same address and port.
+pub fn bind_udp_on(addr: SocketAddr, interface: Option<&str>) -> Result<UdpSocket, Report> {
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::IPV4,
+        SocketAddr::V6(_) => Domain::IPV6,
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    socket.bind(&addr.into())?;
+    trace!(?socket, "UDP socket bound.");
+    Ok(socket.into())
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, interface: &str) -> Result<(), Report> {
+    socket
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|e| eyre!("Failed binding to interface {}: {}", interface, e))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, interface: &str) -> Result<(), Report> {
+    Err(eyre!(
+        "Binding to interface {} not supported on this platform",
+        interface
+    ))
+}
+
+/// Names of the interfaces with at least an address, loopback included, with their index.
+pub fn interface_indexes() -> Result<BTreeMap<String, Option<u32>>, Report> {
+    let indexes = if_addrs::get_if_addrs()?
+        .into_iter()
+        .map(|interface| (interface.name, interface.index))
+        .collect();
+    trace!(?indexes, "Interfaces.");
+    Ok(indexes)
+}
+
+/// Where a datagram was received, from the IP_PKTINFO and IPV6_PKTINFO control messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketInfo {
+    /// Local address the datagram was received on. For IPv4 broadcasts this is the address of the
+    /// receiving interface, for IPv6 multicasts the group address.
+    pub local_ip: Option<IpAddr>,
+    /// Destination address of the datagram, e.g. a broadcast address.
+    pub destination: Option<IpAddr>,
+    pub interface_index: Option<u32>,
+}
+
+/// Ask the kernel for the packet informations of the received datagrams, see `recv_with_info`.
+#[cfg(target_os = "linux")]
+pub fn enable_packet_info(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let (level, option) = match socket.local_addr()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_PKTINFO),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO),
+    };
+    let enable: libc::c_int = 1;
+    // SAFETY: the option value is a valid c_int, with its size.
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_packet_info(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+/// Receive a datagram as `UdpSocket::recv_from`, with its packet informations if enabled with
+/// `enable_packet_info`.
+#[cfg(target_os = "linux")]
+pub fn recv_with_info(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, PacketInfo)> {
+    use std::os::unix::io::AsRawFd;
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // SAFETY: all-zero is a valid value of these C structs.
+    let mut source: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut control = [0u64; 16]; // aligned for cmsghdr, room for an in6_pktinfo message
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut source as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    // SAFETY: msg points to buffers living until the end of the function, with their sizes.
+    let lenght = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if lenght < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut info = PacketInfo::default();
+    // SAFETY: the control messages were written by the kernel, within msg_controllen.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let pktinfo = std::ptr::read_unaligned(data as *const libc::in_pktinfo);
+                    let local = Ipv4Addr::from(u32::from_be(pktinfo.ipi_spec_dst.s_addr));
+                    info.local_ip = Some(IpAddr::V4(local));
+                    let destination = Ipv4Addr::from(u32::from_be(pktinfo.ipi_addr.s_addr));
+                    info.destination = Some(IpAddr::V4(destination));
+                    info.interface_index = Some(pktinfo.ipi_ifindex as u32);
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let pktinfo = std::ptr::read_unaligned(data as *const libc::in6_pktinfo);
+                    info.local_ip = Some(IpAddr::V6(Ipv6Addr::from(pktinfo.ipi6_addr.s6_addr)));
+                    info.destination = info.local_ip;
+                    info.interface_index = Some(pktinfo.ipi6_ifindex);
+                }
+                _ => (),
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    // SAFETY: the kernel wrote a socket address of msg_namelen bytes.
+    let source = unsafe { socket2::SockAddr::new(source, msg.msg_namelen) };
+    let source = source
+        .as_socket()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an IP source address"))?;
+    Ok((lenght as usize, source, info))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv_with_info(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, PacketInfo)> {
+    let (lenght, source) = socket.recv_from(buf)?;
+    Ok((lenght, source, PacketInfo::default()))
+}
+
+/// Indexes of the non-loopback interfaces with at least an IPv6 address.
+pub fn multicast_interfaces_v6() -> Result<Vec<u32>, Report> {
+    let mut indexes: Vec<u32> = if_addrs::get_if_addrs()?
+        .iter()
+        .filter(|interface| interface.ip().is_ipv6() && !interface.is_loopback())
+        .filter_map(|interface| interface.index)
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    trace!(?indexes, "IPv6 multicast interfaces.");
+    Ok(indexes)
+}
+
+/// An address of a network interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddr {
+    pub name: String,
+    pub index: Option<u32>,
+    pub ip: IpAddr,
+    pub prefix_len: u8,
+    /// Subnet-directed broadcast address, for IPv4 interfaces supporting broadcast.
+    pub broadcast: Option<Ipv4Addr>,
+}
+
+impl InterfaceAddr {
+    /// Whether the IP is in the subnet of this address.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        in_network(ip, &self.ip, self.prefix_len)
+    }
+}
+
+/// Whether the IP is in the network, IPv4 and IPv6 networks never contain each other's addresses.
+pub fn in_network(ip: &IpAddr, network: &IpAddr, prefix_len: u8) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            u32::from(*network) & mask == u32::from(*ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            u128::from(*network) & mask == u128::from(*ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Addresses of the non-loopback interfaces.
+pub fn interface_addrs() -> Result<Vec<InterfaceAddr>, Report> {
+    let addrs = if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|interface| !interface.is_loopback())
+        .map(|interface| {
+            let (prefix_len, broadcast) = match &interface.addr {
+                if_addrs::IfAddr::V4(addr) => (addr.prefixlen, addr.broadcast),
+                if_addrs::IfAddr::V6(addr) => (addr.prefixlen, None),
+            };
+            InterfaceAddr {
+                ip: interface.ip(),
+                name: interface.name,
+                index: interface.index,
+                prefix_len,
+                broadcast,
+            }
+        })
+        .collect();
+    trace!(?addrs, "Interface addresses.");
+    Ok(addrs)
+}
+
+/// Addresses of the non-loopback interfaces, listed again at most every `period` (2s by default),
+/// for callers needing them often (e.g. for every datagram received).
+#[derive(Debug, Clone)]
+pub struct CachedInterfaceAddrs {
+    period: Duration,
+    listed: Option<(Instant, Vec<InterfaceAddr>)>,
+}
+
+impl Default for CachedInterfaceAddrs {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(2))
+    }
+}
+
+impl CachedInterfaceAddrs {
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period,
+            listed: None,
+        }
+    }
+
+    /// The addresses listed last, listed again if older than the period. Empty if listing fails.
+    pub fn get(&mut self) -> &[InterfaceAddr] {
+        let now = Instant::now();
+        if !matches!(&self.listed, Some((at, _)) if now.duration_since(*at) < self.period) {
+            let addrs = interface_addrs().unwrap_or_else(|error| {
+                debug!(?error, "Failed listing the network interfaces.");
+                Vec::new()
+            });
+            self.listed = Some((now, addrs));
+        }
+        self.listed
+            .as_ref()
+            .map_or(&[], |(_, addrs)| addrs.as_slice())
+    }
+}
+
+/// Name of the interface a peer is reachable on: the interface of the IPv6 scope, or the one with
+/// the most specific subnet containing the peer IP.
+pub fn interface_of(peer: &SocketAddr, interfaces: &[InterfaceAddr]) -> Option<String> {
+    interface_addr_of(peer, interfaces).map(|i| i.name.clone())
+}
+
+/// Address of the interface a peer is reachable on, see `interface_of`.
+pub fn interface_addr_of<'a>(
+    peer: &SocketAddr,
+    interfaces: &'a [InterfaceAddr],
+) -> Option<&'a InterfaceAddr> {
+    if let SocketAddr::V6(addr) = peer {
+        if addr.scope_id() != 0 {
+            return interfaces
+                .iter()
+                .find(|i| i.index == Some(addr.scope_id()) && i.ip.is_ipv6());
+        }
+    }
+    interfaces
+        .iter()
+        .filter(|i| i.contains(&peer.ip()))
+        .max_by_key(|i| i.prefix_len)
+}
+
+/// Broadcast address of the IPv4 network, e.g. `192.168.1.255` for `192.168.1.0/24`.
+pub fn directed_broadcast(ip: Ipv4Addr, prefix_len: u8) -> Ipv4Addr {
+    let host_mask = u32::MAX.checked_shr(u32::from(prefix_len)).unwrap_or(0);
+    Ipv4Addr::from(u32::from(ip) | host_mask)
+}
+
+/// Join the multicast group on the IPv6 interfaces not in `joined`, adding them. Failures on single
+/// interfaces are only logged, and retried at the next call. Interfaces that disappeared are
+/// removed from `joined`, so that the group is joined again if they come back.
+pub fn join_multicast_v6(
+    socket: &UdpSocket,
+    group: &Ipv6Addr,
+    joined: &mut BTreeSet<u32>,
+) -> Result<(), Report> {
+    let present = multicast_interfaces_v6()?;
+    joined.retain(|index| present.contains(index));
+    for index in present {
+        if joined.contains(&index) {
+            continue;
+        }
+        match socket.join_multicast_v6(group, index) {
+            Ok(()) => debug!(%group, %index, "Joined multicast group."),
+            Err(error) if error.kind() == io::ErrorKind::AddrInUse => (), // still a member
+            Err(error) => {
+                warn!(%group, %index, ?error, "Failed joining multicast group.");
+                continue;
+            }
+        }
+        joined.insert(index);
+    }
+    Ok(())
+}
+
+/// Format the IP of an address, with the scope (interface index) of IPv6 addresses when present,
+/// e.g. `fe80::1%2`.
+pub fn format_scoped_ip(addr: &SocketAddr) -> String {
+    match addr {
+        SocketAddr::V6(addr) if addr.scope_id() != 0 => {
+            format!("{}%{}", addr.ip(), addr.scope_id())
+        }
+        _ => addr.ip().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV6};
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_format_scoped_ip() {
+        let link_local = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+        assert_eq!(
+            format_scoped_ip(&SocketAddr::V6(SocketAddrV6::new(link_local, 1901, 0, 2))),
+            "fe80::1%2"
+        );
+        assert_eq!(
+            format_scoped_ip(&SocketAddr::V6(SocketAddrV6::new(link_local, 1901, 0, 0))),
+            "fe80::1"
+        );
+        assert_eq!(
+            format_scoped_ip(&SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 1901))),
+            "192.168.0.1"
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_interface_of() {
+        let interface = |name: &str, index, ip: &str, prefix_len| InterfaceAddr {
+            name: name.into(),
+            index: Some(index),
+            ip: ip.parse().unwrap(),
+            prefix_len,
+            broadcast: None,
+        };
+        let interfaces = vec![
+            interface("eth0", 2, "192.168.1.10", 16),
+            interface("wlan0", 3, "192.168.5.10", 24),
+            interface("wlan0", 3, "fe80::1", 64),
+        ];
+        let peer = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 1901);
+        assert_eq!(
+            interface_of(&peer("192.168.5.7"), &interfaces).unwrap(),
+            "wlan0"
+        );
+        assert_eq!(
+            interface_of(&peer("192.168.9.7"), &interfaces).unwrap(),
+            "eth0"
+        );
+        assert_eq!(interface_of(&peer("10.0.0.1"), &interfaces), None);
+        let scoped = SocketAddr::V6(SocketAddrV6::new("fe80::2".parse().unwrap(), 1901, 0, 3));
+        assert_eq!(interface_of(&scoped, &interfaces).unwrap(), "wlan0");
+        assert_eq!(
+            interface_addr_of(&scoped, &interfaces).unwrap().ip,
+            "fe80::1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            interface_addr_of(&peer("192.168.5.7"), &interfaces)
+                .unwrap()
+                .ip,
+            "192.168.5.10".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            directed_broadcast(Ipv4Addr::new(192, 168, 1, 0), 24),
+            Ipv4Addr::new(192, 168, 1, 255)
+        );
+        assert_eq!(
+            directed_broadcast(Ipv4Addr::new(10, 1, 2, 3), 0),
+            Ipv4Addr::BROADCAST
+        );
+        assert_eq!(
+            directed_broadcast(Ipv4Addr::new(10, 1, 2, 3), 32),
+            Ipv4Addr::new(10, 1, 2, 3)
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_bind_dual_stack() {
+        let socket_v4 = bind_udp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).unwrap();
+        let port = socket_v4.local_addr().unwrap().port();
+        if let Ok(socket_v6) = bind_udp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))) {
+            assert_eq!(socket_v6.local_addr().unwrap().port(), port);
+        }
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_bind_device() {
+        let socket = match bind_udp_on(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), Some("lo")) {
+            Ok(s) => s,
+            Err(_) => return, // not supported, or not permitted
+        };
+        let port = socket.local_addr().unwrap().port();
+        let sender = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        sender
+            .send_to(b"ping", SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+            .unwrap();
+        let mut buf = [0; 4];
+        socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(bind_udp_on(
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            Some("nonexistent0")
+        )
+        .is_err());
+        assert!(interface_indexes().unwrap().contains_key("lo"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_recv_with_info() {
+        let socket = bind_udp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).unwrap();
+        enable_packet_info(&socket).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let sender = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        sender
+            .send_to(b"ping", SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+            .unwrap();
+        let mut buf = [0; 16];
+        let (lenght, source, info) = recv_with_info(&socket, &mut buf).unwrap();
+        assert_eq!(&buf[..lenght], b"ping");
+        assert_eq!(source, sender.local_addr().unwrap());
+        if cfg!(target_os = "linux") {
+            assert_eq!(info.local_ip, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+            assert_eq!(info.destination, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+            assert_eq!(info.interface_index, interface_indexes().unwrap()["lo"]);
+        }
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_cached_interface_addrs() {
+        let mut cached = CachedInterfaceAddrs::new(Duration::from_secs(60));
+        cached.get();
+        let listed_at = cached.listed.as_ref().unwrap().0;
+        cached.get();
+        assert_eq!(cached.listed.as_ref().unwrap().0, listed_at); // not listed again
+        cached.period = Duration::ZERO;
+        cached.get();
+        assert!(cached.listed.as_ref().unwrap().0 > listed_at);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_join_multicast_v6() {
+        let socket = match bind_udp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))) {
+            Ok(s) => s,
+            Err(_) => return, // no IPv6 support
+        };
+        let group = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x1998);
+        let mut joined = BTreeSet::from([u32::MAX]); // disappeared interface
+        join_multicast_v6(&socket, &group, &mut joined).unwrap();
+        let present: BTreeSet<u32> = multicast_interfaces_v6().unwrap().into_iter().collect();
+        assert!(joined.is_subset(&present));
+        let first = joined.clone();
+        join_multicast_v6(&socket, &group, &mut joined).unwrap(); // nothing new to join
+        assert_eq!(joined, first);
+    }
+}
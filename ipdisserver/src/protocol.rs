@@ -0,0 +1,282 @@
+//! Wire protocol shared by ipdisserver and ipdisscan.
+//!
+//! Every message starts with a fixed size header:
+//!
+//! | bytes | field                                 |
+//! |-------|---------------------------------------|
+//! | 4     | magic, `IPDP`                         |
+//! | 1     | protocol version                      |
+//! | 1     | message type                          |
+//! | 2     | flags (big endian)                    |
+//! | 4     | request id (big endian)               |
+//!
+//! The header layout will not change in future versions, so that a peer can always negotiate
+//! the highest version supported by both. Unknown flags are ignored.
+//!
+//! Datagrams not starting with the magic are legacy requests (a bare signature) or legacy
+//! answers (bare JSON).
+//!
+//! Request bodies with the `PADDED` flag end with zeros and the padding lenght (2 bytes, big
+//! endian, including itself), see `pad`.
+use bytes::{BufMut, Bytes, BytesMut};
+use color_eyre::eyre::{eyre, Report};
+use std::fmt;
+use std::ops::BitOr;
+use thiserror::Error;
+
+pub const MAGIC: &[u8; 4] = b"IPDP";
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = MAGIC.len() + 1 + 1 + 2 + 4;
+/// Requests are padded up to this size at most.
+pub const REQUEST_MAX_SIZE: usize = 1200;
+const PADDING_LENGHT_SIZE: usize = 2;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ProtocolError {
+    #[error("legacy message, no protocol header")]
+    Legacy,
+    #[error("truncated header")]
+    Truncated,
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown message type {0}")]
+    UnknownMessageType(u8),
+    #[error("bad padding")]
+    BadPadding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    /// Sent by scanners, the body is the signature or an authenticated request.
+    Request = 1,
+    /// Sent by servers, the body is the (optionally authenticated and encrypted) answer.
+    Answer = 2,
+    /// Part of an answer too large for a single datagram.
+    AnswerFragment = 3,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::Request),
+            2 => Ok(Self::Answer),
+            3 => Ok(Self::AnswerFragment),
+            other => Err(ProtocolError::UnknownMessageType(other)),
+        }
+    }
+}
+
+/// Message flags. In requests, the capability flags announce what the scanner supports.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags(pub u16);
+
+impl Flags {
+    /// The body is authenticated with the shared key.
+    pub const AUTHENTICATED: Flags = Flags(1 << 0);
+    /// The answer payload is encrypted.
+    pub const ENCRYPTED: Flags = Flags(1 << 1);
+    /// The request body is padded, see `pad`.
+    pub const PADDED: Flags = Flags(1 << 2);
+    /// Capability: the scanner reassembles fragmented answers.
+    pub const CAN_REASSEMBLE: Flags = Flags(1 << 8);
+    /// Capability: the scanner understands encrypted answers.
+    pub const CAN_DECRYPT: Flags = Flags(1 << 9);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Debug for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Flags({:#06x})", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub message_type: MessageType,
+    pub flags: Flags,
+    pub request_id: u32,
+}
+
+impl Header {
+    pub fn new(message_type: MessageType, flags: Flags, request_id: u32) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            message_type,
+            flags,
+            request_id,
+        }
+    }
+
+    /// Header of the answer to a request, with the highest version supported by both peers.
+    pub fn answer_to(request: &Header, flags: Flags) -> Self {
+        Self {
+            version: request.version.min(PROTOCOL_VERSION),
+            ..Self::new(MessageType::Answer, flags, request.request_id)
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0; HEADER_SIZE];
+        buf[..MAGIC.len()].copy_from_slice(MAGIC);
+        buf[4] = self.version;
+        buf[5] = self.message_type as u8;
+        buf[6..8].copy_from_slice(&self.flags.0.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.request_id.to_be_bytes());
+        buf
+    }
+
+    /// Split a datagram in header and body.
+    pub fn parse(datagram: &[u8]) -> Result<(Self, &[u8]), ProtocolError> {
+        if !is_versioned(datagram) {
+            return Err(ProtocolError::Legacy);
+        }
+        if datagram.len() < HEADER_SIZE {
+            return Err(ProtocolError::Truncated);
+        }
+        let version = datagram[4];
+        if version == 0 {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+        let header = Self {
+            version,
+            message_type: MessageType::try_from(datagram[5])?,
+            flags: Flags(u16::from_be_bytes([datagram[6], datagram[7]])),
+            request_id: u32::from_be_bytes(datagram[8..12].try_into().expect("Checked size")),
+        };
+        Ok((header, &datagram[HEADER_SIZE..]))
+    }
+
+    /// Header followed by the body.
+    pub fn encode(&self, body: &[u8]) -> Bytes {
+        let mut buf = BytesMut::with_capacity(HEADER_SIZE + body.len());
+        buf.put_slice(&self.to_bytes());
+        buf.put_slice(body);
+        buf.freeze()
+    }
+}
+
+/// Random request id, scanners use a new one for every broadcast round.
+pub fn new_request_id() -> Result<u32, Report> {
+    let mut buf = [0; 4];
+    getrandom::getrandom(&mut buf).map_err(|e| eyre!("Random generation failed: {}", e))?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// Pad a request body so that the request is `size` bytes long, or just long enough for the
+/// padding lenght. Servers limit the answer size to a multiple of the request size, against
+/// reflection attacks.
+pub fn pad(body: &[u8], size: usize) -> Bytes {
+    let size = size.min(REQUEST_MAX_SIZE).saturating_sub(HEADER_SIZE);
+    let padding = size
+        .saturating_sub(body.len())
+        .clamp(PADDING_LENGHT_SIZE, u16::MAX.into());
+    let mut buf = BytesMut::with_capacity(body.len() + padding);
+    buf.put_slice(body);
+    buf.put_bytes(0, padding - PADDING_LENGHT_SIZE);
+    buf.put_u16(padding as u16);
+    buf.freeze()
+}
+
+/// Remove the padding added by `pad`.
+pub fn unpad(body: &[u8]) -> Result<&[u8], ProtocolError> {
+    let lenght_start = body
+        .len()
+        .checked_sub(PADDING_LENGHT_SIZE)
+        .ok_or(ProtocolError::BadPadding)?;
+    let padding = usize::from(u16::from_be_bytes([
+        body[lenght_start],
+        body[lenght_start + 1],
+    ]));
+    let unpadded = body
+        .len()
+        .checked_sub(padding)
+        .filter(|_| padding >= PADDING_LENGHT_SIZE)
+        .ok_or(ProtocolError::BadPadding)?;
+    match body[unpadded..lenght_start].iter().all(|b| *b == 0) {
+        true => Ok(&body[..unpadded]),
+        false => Err(ProtocolError::BadPadding),
+    }
+}
+
+/// True if the datagram starts with the protocol header magic.
+pub fn is_versioned(datagram: &[u8]) -> bool {
+    datagram.starts_with(MAGIC)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_header_roundtrip() {
+        let header = Header::new(
+            MessageType::Request,
+            Flags::AUTHENTICATED | Flags::CAN_REASSEMBLE,
+            0xdeadbeef,
+        );
+        let datagram = header.encode(b"body");
+        assert_eq!(&datagram[..4], b"IPDP");
+        assert_eq!(Header::parse(&datagram), Ok((header, b"body".as_slice())));
+        assert!(header.flags.contains(Flags::CAN_REASSEMBLE));
+        assert!(!header.flags.contains(Flags::CAN_DECRYPT));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_header_errors() {
+        assert_eq!(Header::parse(b"ipdisbeacon"), Err(ProtocolError::Legacy));
+        assert_eq!(Header::parse(b"IPDP\x01"), Err(ProtocolError::Truncated));
+        let mut datagram = Header::new(MessageType::Answer, Flags::empty(), 1).to_bytes();
+        datagram[5] = 42;
+        assert_eq!(
+            Header::parse(&datagram),
+            Err(ProtocolError::UnknownMessageType(42))
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_padding() {
+        let padded = pad(b"ipdisbeacon", 512);
+        assert_eq!(HEADER_SIZE + padded.len(), 512);
+        assert_eq!(unpad(&padded), Ok(b"ipdisbeacon".as_slice()));
+        assert_eq!(pad(b"ipdisbeacon", 0).len(), 13);
+        assert_eq!(
+            pad(b"ipdisbeacon", 5000).len(),
+            REQUEST_MAX_SIZE - HEADER_SIZE
+        );
+        assert_eq!(unpad(b"\x00"), Err(ProtocolError::BadPadding));
+        assert_eq!(unpad(b"ab\x00\x05"), Err(ProtocolError::BadPadding));
+        assert_eq!(unpad(b"ab\x01\x00\x03"), Err(ProtocolError::BadPadding));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_version_negotiation() {
+        let mut request = Header::new(MessageType::Request, Flags::empty(), 7);
+        request.version = PROTOCOL_VERSION + 1; // newer scanner
+        let answer = Header::answer_to(&request, Flags::ENCRYPTED);
+        assert_eq!(answer.version, PROTOCOL_VERSION);
+        assert_eq!(answer.request_id, 7);
+        assert_eq!(answer.message_type, MessageType::Answer);
+    }
+}
@@ -0,0 +1,421 @@
+//! Built-in inventories, reading /proc, /sys and /etc directly: they work without a shell.
+use crate::answers::BeaconInfos;
+use crate::inventory::{ExecuteInventory, InventoryOutput, CACHE_TTL_DEFAULT};
+use color_eyre::eyre::{eyre, Report};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fmt;
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::trace;
+
+const OS_RELEASE_PATHS: [&str; 2] = ["etc/os-release", "usr/lib/os-release"];
+pub const MACHINE_ID_PATHS: [&str; 2] = ["etc/machine-id", "var/lib/dbus/machine-id"];
+const DMI_DIR: &str = "sys/class/dmi/id";
+pub const NET_DIR: &str = "sys/class/net";
+/// Disk usage is reported for the filesystem of this path.
+const DISK_PATH: &str = "/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Provider {
+    /// `mac_<iface>`, `ipv4_<iface>` and `ipv6_<iface>` (addresses with prefix length).
+    Interfaces,
+    /// `os` (the pretty name), `os_id` and `os_version_id`.
+    OsRelease,
+    /// `kernel`, the kernel release.
+    Kernel,
+    /// `uptime_secs`.
+    Uptime,
+    /// `cpu_model` and `cpu_count`.
+    Cpu,
+    /// `mem_total_kb` and `mem_available_kb`.
+    Memory,
+    /// `disk_total_kb` and `disk_available_kb`, of the root filesystem.
+    Disk,
+    /// `machine_id`.
+    MachineId,
+    /// `dmi_serial`, `dmi_product_name` and `dmi_sys_vendor`. The serial is usually readable
+    /// only by root.
+    DmiSerial,
+}
+
+impl Provider {
+    pub const ALL: [Provider; 9] = [
+        Self::Interfaces,
+        Self::OsRelease,
+        Self::Kernel,
+        Self::Uptime,
+        Self::Cpu,
+        Self::Memory,
+        Self::Disk,
+        Self::MachineId,
+        Self::DmiSerial,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Interfaces => "interfaces",
+            Self::OsRelease => "os-release",
+            Self::Kernel => "kernel",
+            Self::Uptime => "uptime",
+            Self::Cpu => "cpu",
+            Self::Memory => "memory",
+            Self::Disk => "disk",
+            Self::MachineId => "machine-id",
+            Self::DmiSerial => "dmi-serial",
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("unknown provider `{0}`, expected one of: interfaces, os-release, kernel, uptime, cpu, memory, disk, machine-id, dmi-serial")]
+pub struct ProviderError(String);
+
+impl FromStr for Provider {
+    type Err = ProviderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|provider| provider.name() == s)
+            .ok_or_else(|| ProviderError(s.to_string()))
+    }
+}
+
+/// A provider, executed as the other inventories. Failures are reported under the provider name
+/// in the diagnostics.
+pub struct NativeInventory {
+    pub provider: Provider,
+    /// Root of the /proc, /sys and /etc paths, changed by tests.
+    pub root: PathBuf,
+    pub cache_ttl: Duration,
+}
+
+impl From<Provider> for NativeInventory {
+    fn from(provider: Provider) -> Self {
+        Self {
+            provider,
+            root: PathBuf::from("/"),
+            cache_ttl: CACHE_TTL_DEFAULT,
+        }
+    }
+}
+
+impl ExecuteInventory for NativeInventory {
+    fn execute(&self) -> InventoryOutput {
+        let result = match self.provider {
+            Provider::Interfaces => self.interfaces(),
+            Provider::OsRelease => self.os_release(),
+            Provider::Kernel => self.kernel(),
+            Provider::Uptime => self.uptime(),
+            Provider::Cpu => self.cpu(),
+            Provider::Memory => self.memory(),
+            Provider::Disk => disk(Path::new(DISK_PATH)),
+            Provider::MachineId => self.machine_id(),
+            Provider::DmiSerial => self.dmi(),
+        };
+        trace!(provider = %self.provider, ?result, "Provider executed.");
+        match result {
+            Ok(output) => InventoryOutput {
+                output,
+                ..InventoryOutput::default()
+            },
+            Err(error) => {
+                let mut diagnostics = BeaconInfos::new();
+                diagnostics.insert(self.provider.to_string(), error.to_string().into());
+                InventoryOutput {
+                    diagnostics,
+                    ..InventoryOutput::default()
+                }
+            }
+        }
+    }
+
+    fn cache_ttl(&self) -> Duration {
+        self.cache_ttl
+    }
+}
+
+impl NativeInventory {
+    fn read(&self, path: &str) -> Result<String, Report> {
+        let path = self.root.join(path);
+        std::fs::read_to_string(&path).map_err(|e| eyre!("{}: {}", path.display(), e))
+    }
+
+    /// Content of the first readable path.
+    fn read_first(&self, paths: &[&str]) -> Result<String, Report> {
+        let mut error = eyre!("no path given");
+        for path in paths {
+            match self.read(path) {
+                Ok(content) => return Ok(content),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    fn interfaces(&self) -> Result<BeaconInfos, Report> {
+        let mut addrs: BTreeMap<String, (Vec<String>, Vec<String>)> = BTreeMap::new();
+        for interface in if_addrs::get_if_addrs()? {
+            if interface.is_loopback() {
+                continue;
+            }
+            let (ipv4, ipv6) = addrs.entry(interface.name.clone()).or_default();
+            let prefix = prefix_len(&interface.addr);
+            match interface.ip() {
+                IpAddr::V4(ip) => ipv4.push(format!("{}/{}", ip, prefix)),
+                IpAddr::V6(ip) => ipv6.push(format!("{}/{}", ip, prefix)),
+            }
+        }
+        let mut res = BeaconInfos::new();
+        for (name, (ipv4, ipv6)) in addrs {
+            if let Ok(mac) = self.read(&format!("{}/{}/address", NET_DIR, name)) {
+                res.insert(format!("mac_{}", name), mac.trim().into());
+            }
+            for (key, ips) in [("ipv4", ipv4), ("ipv6", ipv6)] {
+                match ips.len() {
+                    0 => (),
+                    1 => {
+                        res.insert(format!("{}_{}", key, name), ips[0].clone().into());
+                    }
+                    _ => {
+                        res.insert(format!("{}_{}", key, name), ips.into());
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    fn os_release(&self) -> Result<BeaconInfos, Report> {
+        let content = self.read_first(&OS_RELEASE_PATHS)?;
+        let fields: BTreeMap<&str, String> = content
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim(), unquote(value.trim())))
+            .collect();
+        let mut res = BeaconInfos::new();
+        for (field, key) in [
+            ("PRETTY_NAME", "os"),
+            ("ID", "os_id"),
+            ("VERSION_ID", "os_version_id"),
+        ] {
+            if let Some(value) = fields.get(field) {
+                res.insert(key.into(), value.clone().into());
+            }
+        }
+        Ok(res)
+    }
+
+    fn kernel(&self) -> Result<BeaconInfos, Report> {
+        let release = self.read("proc/sys/kernel/osrelease")?;
+        Ok(single("kernel", release.trim()))
+    }
+
+    fn uptime(&self) -> Result<BeaconInfos, Report> {
+        let content = self.read("proc/uptime")?;
+        let secs: f64 = content
+            .split_whitespace()
+            .next()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| eyre!("unexpected /proc/uptime format"))?;
+        Ok(single("uptime_secs", secs as u64))
+    }
+
+    fn cpu(&self) -> Result<BeaconInfos, Report> {
+        let content = self.read("proc/cpuinfo")?;
+        let field = |name: &str| {
+            content
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(key, _)| key.trim() == name)
+                .map(|(_, value)| value.trim().to_string())
+        };
+        let count = content
+            .lines()
+            .filter(|line| line.split(':').next().map(str::trim) == Some("processor"))
+            .count();
+        let mut res = BeaconInfos::new();
+        // ARM kernels have no model name
+        if let Some(model) = field("model name").or_else(|| field("Hardware")) {
+            res.insert("cpu_model".into(), model.into());
+        }
+        res.insert("cpu_count".into(), Value::from(count as u64));
+        Ok(res)
+    }
+
+    fn memory(&self) -> Result<BeaconInfos, Report> {
+        let content = self.read("proc/meminfo")?;
+        let mut res = BeaconInfos::new();
+        for line in content.lines() {
+            let key = match line.split(':').next() {
+                Some("MemTotal") => "mem_total_kb",
+                Some("MemAvailable") => "mem_available_kb",
+                _ => continue,
+            };
+            if let Some(kb) = line
+                .split_whitespace()
+                .nth(1)
+                .and_then(|kb| kb.parse::<u64>().ok())
+            {
+                res.insert(key.into(), Value::from(kb));
+            }
+        }
+        Ok(res)
+    }
+
+    fn machine_id(&self) -> Result<BeaconInfos, Report> {
+        let id = self.read_first(&MACHINE_ID_PATHS)?;
+        Ok(single("machine_id", id.trim()))
+    }
+
+    fn dmi(&self) -> Result<BeaconInfos, Report> {
+        let mut res = BeaconInfos::new();
+        let mut error = None;
+        for (file, key) in [
+            ("product_serial", "dmi_serial"),
+            ("product_name", "dmi_product_name"),
+            ("sys_vendor", "dmi_sys_vendor"),
+        ] {
+            match self.read(&format!("{}/{}", DMI_DIR, file)) {
+                Ok(value) => {
+                    res.insert(key.into(), value.trim().into());
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        match (res.is_empty(), error) {
+            (true, Some(error)) => Err(error),
+            _ => Ok(res),
+        }
+    }
+}
+
+fn disk(path: &Path) -> Result<BeaconInfos, Report> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: valid NUL terminated path and a statvfs struct to fill.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    #[allow(clippy::useless_conversion)] // u32 on some targets
+    let kb =
+        |blocks: libc::fsblkcnt_t| Value::from(u64::from(blocks) * u64::from(stat.f_frsize) / 1024);
+    let mut res = BeaconInfos::new();
+    res.insert("disk_total_kb".into(), kb(stat.f_blocks));
+    res.insert("disk_available_kb".into(), kb(stat.f_bavail));
+    Ok(res)
+}
+
+fn prefix_len(addr: &if_addrs::IfAddr) -> u32 {
+    match addr {
+        if_addrs::IfAddr::V4(addr) => u32::from(addr.netmask).leading_ones(),
+        if_addrs::IfAddr::V6(addr) => u128::from(addr.netmask).leading_ones(),
+    }
+}
+
+/// Remove the quotes of an os-release value.
+fn unquote(value: &str) -> String {
+    let quoted = value.len() >= 2
+        && (value.starts_with('"') && value.ends_with('"')
+            || value.starts_with('\'') && value.ends_with('\''));
+    match quoted {
+        true => value[1..value.len() - 1].to_string(),
+        false => value.to_string(),
+    }
+}
+
+fn single(key: &str, value: impl Into<Value>) -> BeaconInfos {
+    let mut res = BeaconInfos::new();
+    res.insert(key.into(), value.into());
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A fake root with the files read by the providers.
+    fn fake_root() -> PathBuf {
+        let root = std::env::temp_dir().join("rust-ipdisserver-test-providers-root");
+        let _ = std::fs::remove_dir_all(&root);
+        let files = [
+            ("etc/os-release", "NAME=\"Debian GNU/Linux\"\nPRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"\nID=debian\nVERSION_ID='12'\n"),
+            ("proc/sys/kernel/osrelease", "6.1.0-13-amd64\n"),
+            ("proc/uptime", "12345.67 54321.00\n"),
+            ("proc/cpuinfo", "processor\t: 0\nmodel name\t: Test CPU\n\nprocessor\t: 1\nmodel name\t: Test CPU\n"),
+            ("proc/meminfo", "MemTotal:       16318412 kB\nMemFree:         1000000 kB\nMemAvailable:   8000000 kB\n"),
+            ("var/lib/dbus/machine-id", "3d1219c7c4c5404aaa1f6d2a48adfda4\n"),
+            ("sys/class/dmi/id/product_name", "Test Machine\n"),
+        ];
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    fn execute(provider: Provider, root: &Path) -> InventoryOutput {
+        NativeInventory {
+            root: root.into(),
+            ..NativeInventory::from(provider)
+        }
+        .execute()
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_providers() {
+        let root = fake_root();
+        let output = |provider| serde_json::to_string(&execute(provider, &root).output).unwrap();
+        assert_eq!(
+            output(Provider::OsRelease),
+            r#"{"os":"Debian GNU/Linux 12 (bookworm)","os_id":"debian","os_version_id":"12"}"#
+        );
+        assert_eq!(output(Provider::Kernel), r#"{"kernel":"6.1.0-13-amd64"}"#);
+        assert_eq!(output(Provider::Uptime), r#"{"uptime_secs":12345}"#);
+        assert_eq!(
+            output(Provider::Cpu),
+            r#"{"cpu_count":2,"cpu_model":"Test CPU"}"#
+        );
+        assert_eq!(
+            output(Provider::Memory),
+            r#"{"mem_available_kb":8000000,"mem_total_kb":16318412}"#
+        );
+        assert_eq!(
+            output(Provider::MachineId),
+            r#"{"machine_id":"3d1219c7c4c5404aaa1f6d2a48adfda4"}"#
+        );
+        assert_eq!(
+            output(Provider::DmiSerial),
+            r#"{"dmi_product_name":"Test Machine"}"#
+        );
+        assert!(execute(Provider::Disk, &root).output["disk_total_kb"].is_u64());
+        assert!(execute(Provider::Interfaces, &root).diagnostics.is_empty());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_provider_diagnostics() {
+        let root = std::env::temp_dir().join("rust-ipdisserver-test-providers-empty");
+        let output = execute(Provider::Kernel, &root);
+        assert!(output.output.is_empty());
+        assert!(output.diagnostics.contains_key("kernel"));
+        assert_eq!(Provider::from_str("os-release"), Ok(Provider::OsRelease));
+        assert!(Provider::from_str("gpu").is_err());
+    }
+}
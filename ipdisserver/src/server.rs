@@ -1,76 +1,746 @@
-use crate::answers::get_answer;
-use crate::answers::Answer;
+use crate::answers::{Answer, BeaconInfos};
+use crate::audit::{AlertConfig, Audit, AuditRecord, SignatureAlerts, SignatureCheck};
+use crate::auth::{sign_answer, AuthError, AuthenticatedRequest, Nonce, ReplayGuard};
+use crate::bytes::safe_format_bytes;
+use crate::cache::AnswerCache;
 use crate::conf::ServerConfig;
+use crate::crypto::encrypt_answer;
+use crate::fragment::fragment;
+use crate::net::{
+    bind_udp_on, enable_packet_info, interface_addr_of, interface_addrs, interface_indexes,
+    join_multicast_v6, recv_with_info, InterfaceAddr, PacketInfo,
+};
+use crate::protocol::{self, Flags, Header, MessageType, ProtocolError};
 use crate::signature::Signature;
-use color_eyre::eyre::Report;
-use std::collections::HashSet;
-use std::net::SocketAddr;
+use crate::sources::{first_match, RuleAction, SourceRange};
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Report};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use lru::LruCache;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
 use std::net::UdpSocket;
-use std::path::Path;
-use std::time::{Duration, SystemTime};
-use tracing::{info, instrument, trace};
-
-const RECV_BUFFER_LENGHT: usize = 128; // update ipdisserver and ipdisscan CLI documentation if changed
-const RATE_LIMIT_TIMEOUT: Duration = Duration::from_secs(10); // do not accept more than a request every 10 s from each IP
-
-#[instrument]
-pub fn run(conf: &ServerConfig) -> Result<(), Report> {
-    let socket = UdpSocket::bind(format!("{}:{}", conf.listening_addr, conf.port))?;
-    info!(?socket, "Listening for scanner requests.");
-    let clock = Clock::default();
-    let mut rate_limiter = RateLimiter::new(&clock);
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use tracing::{debug, error, info, instrument, trace, warn};
+
+pub const SIGNATURE_MAX_LENGHT: usize = 128; // update ipdisserver and ipdisscan CLI documentation if changed
+const RECV_BUFFER_LENGHT: usize = protocol::REQUEST_MAX_SIZE; // padded requests, longer than the header and a signature
+pub const RATE_LIMIT_PERIOD_DEFAULT: Duration = Duration::from_secs(10); // do not accept more than a request every 10 s from each IP
+pub const RATE_LIMIT_BURST_DEFAULT: u32 = 1;
+pub const GLOBAL_RATE_LIMIT_DEFAULT: f64 = 100.0; // answers per second
+/// An answer every 1000 s, lower rates are refused.
+pub const GLOBAL_RATE_LIMIT_MIN: f64 = 0.001;
+pub const RATE_LIMITED_SOURCES_DEFAULT: usize = 4096;
+/// Answers are at most this many times larger than the request, scanners pad their requests.
+pub const MAX_AMPLIFICATION_DEFAULT: f64 = 8.0;
+/// Dropped requests counters are logged at most this often.
+const DROPS_REPORT_PERIOD: Duration = Duration::from_secs(60);
+/// Requests denied by a source rule, and answers too large for the request or to be
+/// reassembled, are logged at most this often.
+const DENIED_LOG_PERIOD: Duration = Duration::from_secs(10);
+pub const WORKERS_DEFAULT: usize = 4;
+pub const QUEUE_SIZE_DEFAULT: usize = 64;
+/// Key of the interface and address the request was received on, in the answer.
+pub const RECEIVED_ON_KEY: &str = "received_on";
+/// Interfaces appearing and disappearing are noticed within this period.
+const INTERFACE_POLL_PERIOD: Duration = Duration::from_secs(2);
+
+/// Reads the configuration again, called on SIGHUP.
+pub type ReloadConfig<'a> = dyn Fn() -> Result<ServerConfig, Report> + Sync + 'a;
+
+/// Configuration and answer cache in use, replaced as a whole on reload.
+struct State {
+    conf: ServerConfig,
+    cache: AnswerCache,
+}
+
+impl State {
+    fn new(conf: ServerConfig) -> Arc<Self> {
+        let cache = AnswerCache::new(&conf);
+        cache.refresh_stale(Instant::now());
+        Arc::new(Self { conf, cache })
+    }
+}
+
+type SharedState = RwLock<Arc<State>>;
+
+fn current(state: &SharedState) -> Arc<State> {
+    state.read().expect("Poisoned state lock").clone()
+}
+
+/// Serve on every listening address until an error occurs. If `reload` is given, the
+/// configuration is reloaded on SIGHUP, keeping the listening sockets.
+#[instrument(skip(reload))]
+pub fn run(conf: &ServerConfig, reload: Option<&ReloadConfig<'_>>) -> Result<(), Report> {
+    let mut listeners = Vec::new();
+    if conf.interfaces.is_empty() {
+        for addr in &conf.listening_addrs {
+            let addr = SocketAddr::new(*addr, conf.port);
+            match socket_setup(addr, &conf.multicast_addr_v6, None) {
+                Ok(listener) => listeners.push(listener),
+                Err(error) => error!(%addr, ?error, "Failed to listen on address."),
+            }
+        }
+        if listeners.is_empty() {
+            return Err(eyre!("No listening address available"));
+        }
+    }
+    let state = RwLock::new(State::new(conf.clone()));
+    let limits = Limits::new(&Clock, conf);
+    limits.audit.configure(conf.audit_log.as_ref())?;
+    let (queue, jobs) = crossbeam_channel::bounded(conf.queue_size);
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])?;
+    let signals_handle = signals.handle();
+    let stop_refreshing = AtomicBool::new(false);
+    let never_stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| refresh_forever(&state, &stop_refreshing));
+        if let Some(reload) = reload {
+            let state = &state;
+            scope.spawn(move || {
+                for _ in signals.forever() {
+                    reload_state(state, reload);
+                }
+            });
+        }
+        scope.spawn(|| report_drops(&limits, &stop_refreshing));
+        for _ in 0..conf.workers {
+            let (jobs, limits) = (jobs.clone(), &limits);
+            scope.spawn(move || work(jobs, limits));
+        }
+        let result = match conf.interfaces.is_empty() {
+            true => {
+                let (listeners, group, stop) =
+                    (&listeners, &conf.multicast_addr_v6, &stop_refreshing);
+                scope.spawn(move || join_multicast_forever(listeners, group, stop));
+                let handles: Vec<_> = listeners
+                    .iter()
+                    .map(|listener| {
+                        let queue = queue.clone();
+                        let (state, limits, never_stop) = (&state, &limits, &never_stop);
+                        scope.spawn(move || {
+                            serve_forever(listener, state, limits, queue, never_stop)
+                        })
+                    })
+                    .collect();
+                drop(queue); // workers stop when every receiving thread has
+                handles
+                    .into_iter()
+                    .try_for_each(|handle| handle.join().expect("Serving thread panicked"))
+            }
+            false => serve_interfaces(scope, conf, &state, &limits, queue),
+        };
+        stop_refreshing.store(true, Ordering::Relaxed);
+        signals_handle.close();
+        result
+    })
+}
+
+/// A socket bound to `interface` while it is present.
+struct InterfaceListeners<'scope> {
+    stop: Arc<AtomicBool>,
+    handles: Vec<thread::ScopedJoinHandle<'scope, Result<(), Report>>>,
+}
+
+/// Serve on the allowed interfaces only, binding sockets to them when they appear and stopping
+/// serving when they disappear. Errors on single interfaces are only logged, the interface is
+/// bound again at the next check.
+fn serve_interfaces<'scope>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    conf: &ServerConfig,
+    state: &'scope SharedState,
+    limits: &'scope Limits<'_>,
+    queue: Sender<Job>,
+) -> Result<(), Report> {
+    let mut serving: BTreeMap<String, InterfaceListeners<'scope>> = BTreeMap::new();
     loop {
-        rate_limiter.conditional_reset();
-        rate_limiter = serve_single(
-            &socket,
-            &conf.signatures,
-            &conf.inventory_files,
-            rate_limiter,
-        )?;
+        let present = match interface_indexes() {
+            Ok(present) => present,
+            Err(error) => {
+                warn!(?error, "Failed listing the network interfaces.");
+                thread::sleep(INTERFACE_POLL_PERIOD);
+                continue;
+            }
+        };
+        serving.retain(|name, listeners| {
+            let finished = listeners.handles.iter().all(|h| h.is_finished());
+            if !present.contains_key(name) {
+                info!(%name, "Interface disappeared, not serving on it.");
+                listeners.stop.store(true, Ordering::Relaxed);
+                return false;
+            }
+            if finished {
+                warn!(%name, "Serving on the interface stopped, binding it again.");
+            }
+            !finished
+        });
+        for name in &conf.interfaces {
+            let index = match present.get(name) {
+                Some(index) if !serving.contains_key(name) => *index,
+                _ => continue,
+            };
+            info!(%name, "Interface present, serving on it.");
+            let stop = Arc::new(AtomicBool::new(false));
+            let mut handles = Vec::new();
+            for addr in &conf.listening_addrs {
+                let addr = SocketAddr::new(*addr, conf.port);
+                let interface = Some((name.as_str(), index));
+                let listener = match socket_setup(addr, &conf.multicast_addr_v6, interface) {
+                    Ok(l) => l,
+                    Err(error) => {
+                        error!(%addr, %name, ?error, "Failed to listen on interface.");
+                        continue;
+                    }
+                };
+                let (stop, queue) = (stop.clone(), queue.clone());
+                handles.push(scope.spawn(move || {
+                    let result = serve_forever(&listener, state, limits, queue, &stop);
+                    if let Err(error) = &result {
+                        warn!(?listener.interface, ?error, "Stopped serving on interface.");
+                    }
+                    result
+                }));
+            }
+            serving.insert(name.clone(), InterfaceListeners { stop, handles });
+        }
+        thread::sleep(INTERFACE_POLL_PERIOD);
+    }
+}
+
+/// Join the multicast group on the interfaces appearing after binding, for the sockets listening
+/// on all the IPv6 addresses of every interface, until `stop` is set.
+fn join_multicast_forever(listeners: &[Arc<Listener>], group: &Ipv6Addr, stop: &AtomicBool) {
+    let mut wildcards: Vec<_> = listeners
+        .iter()
+        .filter(|l| {
+            l.socket
+                .local_addr()
+                .is_ok_and(|a| a.ip() == Ipv6Addr::UNSPECIFIED)
+        })
+        .map(|l| (&l.socket, BTreeSet::new()))
+        .collect();
+    while !stop.load(Ordering::Relaxed) && !wildcards.is_empty() {
+        for (socket, joined) in &mut wildcards {
+            if let Err(error) = join_multicast_v6(socket, group, joined) {
+                debug!(?error, "Failed listing the network interfaces.");
+            }
+        }
+        thread::sleep(INTERFACE_POLL_PERIOD);
+    }
+}
+
+/// Refresh the stale outputs of the cache in use until `stop` is set.
+fn refresh_forever(state: &SharedState, stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+        let wait = current(state).cache.refresh_wait(Instant::now());
+        thread::sleep(wait);
+    }
+}
+
+/// Replace the state with one built from the reloaded configuration. On errors the current
+/// configuration is kept. Listening sockets are kept too: changes to their settings are applied
+/// only on restart.
+fn reload_state(state: &SharedState, reload: &ReloadConfig<'_>) {
+    info!("Reloading configuration.");
+    let conf = match reload() {
+        Ok(c) => c,
+        Err(error) => {
+            error!(%error, "Failed reloading configuration, keeping the current one.");
+            return;
+        }
+    };
+    let previous = current(state);
+    if conf.port != previous.conf.port
+        || conf.listening_addrs != previous.conf.listening_addrs
+        || conf.multicast_addr_v6 != previous.conf.multicast_addr_v6
+        || conf.interfaces != previous.conf.interfaces
+        || conf.workers != previous.conf.workers
+        || conf.queue_size != previous.conf.queue_size
+    {
+        warn!("Listening port, addresses, interfaces, workers and queue size changes are applied only on restart.");
+    }
+    let reloaded = State::new(conf);
+    *state.write().expect("Poisoned state lock") = reloaded;
+    info!("Configuration reloaded.");
+}
+
+/// A listening socket, with the interface it is bound to.
+#[derive(Debug)]
+struct Listener {
+    socket: UdpSocket,
+    interface: Option<String>,
+}
+
+/// Bind the listening socket, to the interface (name and index) if given. Sockets listening on
+/// all the IPv6 addresses join the multicast group too, only on the interface if given.
+fn socket_setup(
+    addr: SocketAddr,
+    multicast_addr_v6: &Ipv6Addr,
+    interface: Option<(&str, Option<u32>)>,
+) -> Result<Arc<Listener>, Report> {
+    let socket = bind_udp_on(addr, interface.map(|(name, _)| name))?;
+    enable_packet_info(&socket)?;
+    if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        match interface {
+            None => join_multicast_v6(&socket, multicast_addr_v6, &mut BTreeSet::new())?,
+            Some((_, Some(index))) => socket.join_multicast_v6(multicast_addr_v6, index)?,
+            Some((name, None)) => {
+                warn!(%name, "Interface index unknown, multicast group not joined.")
+            }
+        }
+    }
+    if interface.is_some() {
+        // so that the serving thread notices when it must stop
+        socket.set_read_timeout(Some(INTERFACE_POLL_PERIOD))?;
+    }
+    info!(?socket, ?interface, "Listening for scanner requests.");
+    Ok(Arc::new(Listener {
+        socket,
+        interface: interface.map(|(name, _)| name.to_string()),
+    }))
+}
+
+/// Receive requests and queue them for the workers, until an error occurs or `stop` is set.
+fn serve_forever(
+    listener: &Arc<Listener>,
+    state: &SharedState,
+    limits: &Limits<'_>,
+    queue: Sender<Job>,
+    stop: &AtomicBool,
+) -> Result<(), Report> {
+    let mut in_use = current(state);
+    while !stop.load(Ordering::Relaxed) {
+        let datagram = match receive(&listener.socket) {
+            Ok(d) => d,
+            Err(error) if is_timeout(&error) => continue,
+            Err(error) => return Err(error.into()),
+        };
+        let latest = current(state);
+        if !Arc::ptr_eq(&latest, &in_use) {
+            limits.update(&latest.conf);
+            in_use = latest;
+        }
+        dispatch(listener, datagram, &in_use, limits, &queue)?;
     }
+    Ok(())
+}
+
+/// Answer the queued requests, until every receiving thread stops.
+fn work(jobs: Receiver<Job>, limits: &Limits<'_>) {
+    for job in jobs {
+        if let Err(error) = answer_request(&job, limits) {
+            warn!(source = %job.source, ?error, "Failed answering.");
+        }
+    }
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
 }
 
-#[derive(Debug, Clone)]
+/// Where the request was received: the interface, and the local address on it. Without packet
+/// informations, or for multicast requests, the address is the one the socket is bound to, or the
+/// address of the interface in the scanner subnet.
+fn received_on(listener: &Listener, peer: &SocketAddr, info: &PacketInfo) -> BeaconInfos {
+    let interface = listener.interface.clone().or_else(|| {
+        let index = info.interface_index?;
+        let indexes = interface_indexes().ok()?;
+        indexes
+            .into_iter()
+            .find(|(_, i)| *i == Some(index))
+            .map(|(name, _)| name)
+    });
+    let mut interfaces = interface_addrs().unwrap_or_else(|error| {
+        debug!(?error, "Failed listing the network interfaces.");
+        Vec::new()
+    });
+    if let Some(name) = &interface {
+        interfaces.retain(|i| &i.name == name);
+    }
+    let matching = interface_addr_of(peer, &interfaces);
+    let bound_ip = listener
+        .socket
+        .local_addr()
+        .ok()
+        .map(|addr| addr.ip())
+        .filter(|ip| !ip.is_unspecified());
+    let local_ip = info
+        .local_ip
+        .filter(|ip| !ip.is_multicast() && !ip.is_unspecified())
+        .or(bound_ip)
+        .or_else(|| matching.map(|i| i.ip));
+    let interface = interface.or_else(|| matching.map(|i| i.name.clone()));
+    let mut infos = BeaconInfos::new();
+    if let Some(interface) = interface {
+        infos.insert("interface".into(), interface.into());
+    }
+    if let Some(ip) = local_ip {
+        infos.insert("addr".into(), ip.to_string().into());
+    }
+    let mut res = BeaconInfos::new();
+    if !infos.is_empty() {
+        res.insert(RECEIVED_ON_KEY.into(), Value::Object(infos));
+    }
+    res
+}
+
+/// Limits shared by the receiving threads of every socket, and the workers.
+#[derive(Debug)]
+struct Limits<'a> {
+    rate_limiter: RateLimiter<'a>,
+    replay_guard: Mutex<ReplayGuard>,
+    drops: DropCounters,
+    denied_log: LogLimiter,
+    amplification_log: LogLimiter,
+    fragments_log: LogLimiter,
+    interfaces: LocalInterfaces,
+    audit: Audit,
+    alerts: SignatureAlerts,
+}
+
+impl<'a> Limits<'a> {
+    fn new(clock: &'a dyn WrappedSystemTime, conf: &ServerConfig) -> Self {
+        Self {
+            rate_limiter: RateLimiter::new(clock, RateLimitConfig::from(conf)),
+            replay_guard: Mutex::new(ReplayGuard::new(conf.replay_window)),
+            drops: DropCounters::default(),
+            denied_log: LogLimiter::default(),
+            amplification_log: LogLimiter::default(),
+            fragments_log: LogLimiter::default(),
+            interfaces: LocalInterfaces::default(),
+            audit: Audit::default(),
+            alerts: SignatureAlerts::new(AlertConfig::from(conf)),
+        }
+    }
+
+    /// Apply a reloaded configuration.
+    fn update(&self, conf: &ServerConfig) {
+        self.rate_limiter.update(RateLimitConfig::from(conf));
+        self.alerts.update(AlertConfig::from(conf));
+        if let Err(error) = self.audit.configure(conf.audit_log.as_ref()) {
+            error!(%error, "Failed opening the audit log, keeping the current one.");
+        }
+        let mut replay_guard = self
+            .replay_guard
+            .lock()
+            .expect("Poisoned replay guard lock");
+        replay_guard.set_window(conf.replay_window);
+    }
+
+    /// Count the request as dropped, and record it in the audit log.
+    fn drop_request(&self, datagram: &Datagram, reason: DropReason, check: SignatureCheck) {
+        self.drops.count(reason);
+        self.audit.record(|| {
+            let signature = claimed_signature(&datagram.payload);
+            let signature = signature.map(|s| Signature::from(s).to_string());
+            AuditRecord::new(datagram.source, signature, check, reason.name())
+        });
+    }
+
+    /// Count the queued request as dropped, and record it in the audit log.
+    fn drop_job(&self, job: &Job, reason: DropReason) {
+        self.drops.count(reason);
+        self.audit.record(|| job.record(reason.name()));
+    }
+}
+
+/// Addresses of the network interfaces, listed again at most every `INTERFACE_POLL_PERIOD`.
+#[derive(Debug, Default)]
+struct LocalInterfaces(Mutex<Option<(Instant, Arc<Vec<InterfaceAddr>>)>>);
+
+impl LocalInterfaces {
+    fn get(&self) -> Arc<Vec<InterfaceAddr>> {
+        let mut listed = self.0.lock().expect("Poisoned interfaces lock");
+        match &*listed {
+            Some((at, addrs)) if at.elapsed() < INTERFACE_POLL_PERIOD => addrs.clone(),
+            _ => {
+                let addrs = Arc::new(interface_addrs().unwrap_or_else(|error| {
+                    debug!(?error, "Failed listing the network interfaces.");
+                    Vec::new()
+                }));
+                *listed = Some((Instant::now(), addrs.clone()));
+                addrs
+            }
+        }
+    }
+}
+
+/// Logs an event at most once per period, counting the others.
+#[derive(Debug, Default)]
+struct LogLimiter(Mutex<LogLimiterState>);
+
+#[derive(Debug, Default)]
+struct LogLimiterState {
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+impl LogLimiter {
+    /// Return the events not logged since the last one if the event is to be logged now.
+    fn due(&self, period: Duration) -> Option<u64> {
+        let mut state = self.0.lock().expect("Poisoned log limiter lock");
+        match state.last {
+            Some(last) if last.elapsed() < period => {
+                state.suppressed += 1;
+                None
+            }
+            _ => {
+                state.last = Some(Instant::now());
+                Some(std::mem::take(&mut state.suppressed))
+            }
+        }
+    }
+}
+
+/// Why a request is not answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DropReason {
+    SourceDenied,
+    SourceNotAllowed,
+    NotBroadcast,
+    Invalid,
+    SourceRateLimited,
+    GlobalRateLimited,
+    QueueFull,
+    Amplification,
+}
+
+impl DropReason {
+    /// Name of the counter, and outcome in the audit log.
+    fn name(self) -> &'static str {
+        match self {
+            Self::SourceDenied => "source_denied",
+            Self::SourceNotAllowed => "source_not_allowed",
+            Self::NotBroadcast => "not_broadcast",
+            Self::Invalid => "invalid",
+            Self::SourceRateLimited => "source_rate_limited",
+            Self::GlobalRateLimited => "global_rate_limited",
+            Self::QueueFull => "queue_full",
+            Self::Amplification => "amplification",
+        }
+    }
+}
+
+/// Requests not answered since the start, by reason.
+#[derive(Debug, Default)]
+struct DropCounters {
+    source_denied: AtomicU64,
+    source_not_allowed: AtomicU64,
+    not_broadcast: AtomicU64,
+    invalid: AtomicU64,
+    source_rate_limited: AtomicU64,
+    global_rate_limited: AtomicU64,
+    queue_full: AtomicU64,
+    amplification: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct DropCounts {
+    source_denied: u64,
+    source_not_allowed: u64,
+    not_broadcast: u64,
+    invalid: u64,
+    source_rate_limited: u64,
+    global_rate_limited: u64,
+    queue_full: u64,
+    amplification: u64,
+}
+
+impl DropCounters {
+    fn count(&self, reason: DropReason) {
+        let counter = match reason {
+            DropReason::SourceDenied => &self.source_denied,
+            DropReason::SourceNotAllowed => &self.source_not_allowed,
+            DropReason::NotBroadcast => &self.not_broadcast,
+            DropReason::Invalid => &self.invalid,
+            DropReason::SourceRateLimited => &self.source_rate_limited,
+            DropReason::GlobalRateLimited => &self.global_rate_limited,
+            DropReason::QueueFull => &self.queue_full,
+            DropReason::Amplification => &self.amplification,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> DropCounts {
+        DropCounts {
+            source_denied: self.source_denied.load(Ordering::Relaxed),
+            source_not_allowed: self.source_not_allowed.load(Ordering::Relaxed),
+            not_broadcast: self.not_broadcast.load(Ordering::Relaxed),
+            invalid: self.invalid.load(Ordering::Relaxed),
+            source_rate_limited: self.source_rate_limited.load(Ordering::Relaxed),
+            global_rate_limited: self.global_rate_limited.load(Ordering::Relaxed),
+            queue_full: self.queue_full.load(Ordering::Relaxed),
+            amplification: self.amplification.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Log the dropped requests counters, and the dropped audit records, when they change, until
+/// `stop` is set.
+fn report_drops(limits: &Limits<'_>, stop: &AtomicBool) {
+    let mut reported = (DropCounts::default(), 0);
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(DROPS_REPORT_PERIOD);
+        let (counts, audit_dropped) = (limits.drops.load(), limits.audit.dropped());
+        if (counts, audit_dropped) != reported {
+            info!(
+                source_denied = counts.source_denied,
+                source_not_allowed = counts.source_not_allowed,
+                not_broadcast = counts.not_broadcast,
+                invalid = counts.invalid,
+                source_rate_limited = counts.source_rate_limited,
+                global_rate_limited = counts.global_rate_limited,
+                queue_full = counts.queue_full,
+                amplification = counts.amplification,
+                audit_dropped,
+                "Dropped requests since the start."
+            );
+            reported = (counts, audit_dropped);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RateLimitConfig {
+    /// A token is added to the bucket of each source IP this often.
+    period: Duration,
+    /// Tokens a source IP bucket can hold.
+    burst: u32,
+    /// Answers per second, to any source.
+    global_rate: f64,
+    /// Source IPs tracked, the least recently seen are forgotten.
+    max_sources: usize,
+}
+
+impl From<&ServerConfig> for RateLimitConfig {
+    fn from(conf: &ServerConfig) -> Self {
+        Self {
+            period: conf.rate_limit_period,
+            burst: conf.rate_limit_burst,
+            global_rate: conf.global_rate_limit,
+            max_sources: conf.rate_limited_sources,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    fn max_sources(&self) -> NonZeroUsize {
+        NonZeroUsize::new(self.max_sources).unwrap_or(NonZeroUsize::MIN)
+    }
+
+    fn global_interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.global_rate.max(GLOBAL_RATE_LIMIT_MIN))
+    }
+
+    /// At least one answer, a second worth of answers otherwise.
+    fn global_capacity(&self) -> f64 {
+        self.global_rate.max(1.0)
+    }
+}
+
+/// Token buckets: one for each source IP, tracked for a bounded number of sources, and a global
+/// one capping the answers per second. An answer takes a token from both.
+#[derive(Debug)]
 struct RateLimiter<'a> {
-    served_ips: HashSet<SocketAddr>,
     clock: &'a dyn WrappedSystemTime,
-    next_reset: SystemTime,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    conf: RateLimitConfig,
+    sources: LruCache<IpAddr, Bucket>,
+    global: Bucket,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: SystemTime,
+}
+
+impl Bucket {
+    fn full(capacity: f64, now: SystemTime) -> Self {
+        Self {
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    /// Add a token every `interval` since the last update, up to `capacity`.
+    fn refill(&mut self, now: SystemTime, interval: Duration, capacity: f64) {
+        let elapsed = now.duration_since(self.updated).unwrap_or_default();
+        self.tokens = match interval.is_zero() {
+            true => capacity,
+            false => (self.tokens + elapsed.as_secs_f64() / interval.as_secs_f64()).min(capacity),
+        };
+        self.updated = now;
+    }
 }
 
 impl<'a> RateLimiter<'a> {
-    fn new(clock: &'a dyn WrappedSystemTime) -> Self {
+    fn new(clock: &'a dyn WrappedSystemTime, conf: RateLimitConfig) -> Self {
         Self {
-            served_ips: HashSet::default(),
             clock,
-            next_reset: clock.now(),
+            buckets: Mutex::new(Buckets {
+                conf,
+                sources: LruCache::new(conf.max_sources()),
+                global: Bucket::full(conf.global_capacity(), clock.now()),
+            }),
         }
     }
 }
 
 impl RateLimiter<'_> {
-    /// Return true if the address is not in served_ips, add it.
-    fn check(&mut self, ip: &SocketAddr) -> bool {
-        self.conditional_reset();
-        let not_already_served = self.served_ips.insert(*ip);
-        trace!(%ip, %not_already_served, "IP checked.");
-        not_already_served
+    fn buckets(&self) -> MutexGuard<'_, Buckets> {
+        self.buckets.lock().expect("Poisoned rate limiter lock")
     }
 
-    /// Reset served_ips if timeout has elapsed, set new timeout and return true. Return false
-    /// otherwise.
-    fn conditional_reset(&mut self) -> bool {
+    /// Take a token for an answer to the IP, if both its bucket and the global one have any.
+    fn check(&self, ip: &IpAddr) -> Result<(), DropReason> {
         let now = self.clock.now();
-        if now >= self.next_reset {
-            self.next_reset = now + RATE_LIMIT_TIMEOUT;
-            self.served_ips = HashSet::default();
-            trace!(?self.next_reset, "Cleared served IPs.");
-            return true;
+        let mut buckets = self.buckets();
+        let Buckets {
+            conf,
+            sources,
+            global,
+        } = &mut *buckets;
+        let burst = f64::from(conf.burst);
+        let source = sources.get_or_insert_mut(*ip, || Bucket::full(burst, now));
+        source.refill(now, conf.period, burst);
+        if source.tokens < 1.0 {
+            trace!(%ip, "Source rate limited.");
+            return Err(DropReason::SourceRateLimited);
         }
-        false
+        global.refill(now, conf.global_interval(), conf.global_capacity());
+        if global.tokens < 1.0 {
+            trace!(%ip, "Global rate limit reached.");
+            return Err(DropReason::GlobalRateLimited);
+        }
+        source.tokens -= 1.0;
+        global.tokens -= 1.0;
+        trace!(%ip, "IP checked.");
+        Ok(())
+    }
+
+    fn update(&self, conf: RateLimitConfig) {
+        let mut buckets = self.buckets();
+        buckets.sources.resize(conf.max_sources());
+        buckets.conf = conf;
     }
 }
 
-trait WrappedSystemTime: std::fmt::Debug {
+trait WrappedSystemTime: std::fmt::Debug + Sync {
     // NB: supertrait
     fn now(&self) -> SystemTime;
 }
@@ -84,36 +754,381 @@ impl WrappedSystemTime for Clock {
     }
 }
 
+#[cfg(test)]
 #[derive(Debug)]
 struct DummyClock {
     time: SystemTime,
 }
 
+#[cfg(test)]
 impl WrappedSystemTime for DummyClock {
     fn now(&self) -> SystemTime {
         self.time
     }
 }
 
-#[instrument]
-fn serve_single<'a>(
-    socket: &UdpSocket,
-    expected_signatures: &[Signature],
-    inventory_files: &[&Path],
-    mut rate_limiter: RateLimiter<'a>,
-) -> Result<RateLimiter<'a>, Report> {
-    let (addr, received) = receive(socket)?;
-    if !is_signature_vaid(&received, expected_signatures) {
-        trace!(%received, %addr, "Bad signature received, not answering.");
-        return Ok(rate_limiter);
+#[derive(Error, Debug, PartialEq)]
+enum RequestError {
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error("unexpected message type {0:?}")]
+    NotRequest(MessageType),
+    #[error("bad signature")]
+    BadSignature,
+    #[error("authentication required")]
+    NotAuthenticated,
+}
+
+impl RequestError {
+    fn signature_check(&self) -> SignatureCheck {
+        match self {
+            Self::BadSignature | Self::NotAuthenticated | Self::Auth(AuthError::BadMac) => {
+                SignatureCheck::Invalid
+            }
+            Self::Auth(AuthError::Expired | AuthError::Replayed) => SignatureCheck::Valid,
+            Self::Protocol(_) | Self::NotRequest(_) | Self::Auth(AuthError::Malformed) => {
+                SignatureCheck::Malformed
+            }
+        }
+    }
+}
+
+/// A validated scanner request.
+#[derive(Debug, Clone, PartialEq)]
+struct Request {
+    /// None for legacy requests, made of a bare signature.
+    header: Option<Header>,
+    /// Nonce of authenticated requests.
+    nonce: Option<Nonce>,
+    /// Signature of requests not authenticated.
+    signature: Option<Signature>,
+}
+
+impl Request {
+    /// Capabilities announced by the scanner, legacy scanners have none.
+    fn capabilities(&self) -> Flags {
+        self.header.map_or(Flags::empty(), |h| h.flags)
+    }
+
+    fn request_id(&self) -> u32 {
+        self.header.map_or(0, |h| h.request_id)
+    }
+}
+
+/// A validated request, waiting for a worker to answer it.
+struct Job {
+    listener: Arc<Listener>,
+    source: SocketAddr,
+    info: PacketInfo,
+    request: Request,
+    /// Received bytes, padding included.
+    request_size: usize,
+    /// State in use when the request was received.
+    state: Arc<State>,
+}
+
+impl Job {
+    fn record(&self, outcome: &'static str) -> AuditRecord {
+        let signature = self.request.signature.as_ref().map(Signature::to_string);
+        AuditRecord::new(self.source, signature, SignatureCheck::Valid, outcome)
+    }
+}
+
+#[cfg(test)]
+fn serve_single(
+    listener: &Arc<Listener>,
+    state: &Arc<State>,
+    limits: &Limits<'_>,
+) -> Result<(), Report> {
+    let datagram = receive(&listener.socket)?;
+    match accept_request(listener, datagram, state, limits) {
+        Some(job) => answer_request(&job, limits),
+        None => Ok(()),
+    }
+}
+
+/// Queue the request for the workers if valid and not rate limited. When the queue is full
+/// requests are dropped, before marking the scanner as served.
+fn dispatch(
+    listener: &Arc<Listener>,
+    datagram: Datagram,
+    state: &Arc<State>,
+    limits: &Limits<'_>,
+    queue: &Sender<Job>,
+) -> Result<(), Report> {
+    if queue.is_full() {
+        debug!(source = %datagram.source, "Request queue full, request dropped.");
+        limits.drop_request(&datagram, DropReason::QueueFull, SignatureCheck::Unchecked);
+        return Ok(());
+    }
+    let job = match accept_request(listener, datagram, state, limits) {
+        Some(j) => j,
+        None => return Ok(()),
+    };
+    match queue.try_send(job) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(job)) => {
+            debug!(source = %job.source, "Request queue full, request dropped.");
+            limits.drop_job(&job, DropReason::QueueFull);
+            Ok(())
+        }
+        Err(TrySendError::Disconnected(_)) => Err(eyre!("No worker left")),
+    }
+}
+
+/// Validate the request and check the rate limit, return the job to answer it.
+#[instrument(skip(listener, state, limits))]
+fn accept_request(
+    listener: &Arc<Listener>,
+    datagram: Datagram,
+    state: &Arc<State>,
+    limits: &Limits<'_>,
+) -> Option<Job> {
+    let (addr, received, conf) = (datagram.source, &datagram.payload, &state.conf);
+    let needs_interfaces = conf.only_broadcast
+        || conf.allowed_sources.contains(&SourceRange::Local)
+        || conf
+            .source_rules
+            .iter()
+            .any(|r| r.range == SourceRange::Local);
+    let interfaces = match needs_interfaces {
+        true => limits.interfaces.get(),
+        false => Arc::default(),
     };
-    if !rate_limiter.check(&addr) {
-        return Ok(rate_limiter);
+    let signature = claimed_signature(received);
+    match first_match(&conf.source_rules, &addr.ip(), signature, &interfaces) {
+        Some(rule) if rule.action == RuleAction::Deny => {
+            if let Some(suppressed) = limits.denied_log.due(DENIED_LOG_PERIOD) {
+                info!(%addr, %rule, %suppressed, "Request denied by source rule.");
+            }
+            limits.drop_request(
+                &datagram,
+                DropReason::SourceDenied,
+                SignatureCheck::Unchecked,
+            );
+            return None;
+        }
+        Some(rule) => trace!(%addr, %rule, "Request allowed by source rule."),
+        None if !conf
+            .allowed_sources
+            .iter()
+            .any(|range| range.contains(&addr.ip(), &interfaces)) =>
+        {
+            trace!(%addr, "Source not allowed, not answering.");
+            limits.drop_request(
+                &datagram,
+                DropReason::SourceNotAllowed,
+                SignatureCheck::Unchecked,
+            );
+            return None;
+        }
+        None => (),
     }
-    let answer = get_answer(inventory_files)?;
-    respond(socket, &addr, &answer)?;
+    if conf.only_broadcast && !is_broadcast(&datagram.info, &interfaces) {
+        trace!(%addr, ?datagram.info, "Not a broadcast or multicast request, not answering.");
+        limits.drop_request(
+            &datagram,
+            DropReason::NotBroadcast,
+            SignatureCheck::Unchecked,
+        );
+        return None;
+    }
+    let validated = {
+        let mut replay_guard = limits
+            .replay_guard
+            .lock()
+            .expect("Poisoned replay guard lock");
+        validate_request(received, conf, &mut replay_guard)
+    };
+    let request = match validated {
+        Ok(r) => r,
+        Err(error) => {
+            trace!(received = %safe_format_bytes(received), %addr, %error, "Bad request received, not answering.");
+            let check = error.signature_check();
+            if check == SignatureCheck::Invalid {
+                limits.alerts.invalid_signature(addr.ip(), Instant::now());
+            }
+            limits.drops.count(DropReason::Invalid);
+            limits.audit.record(|| {
+                let signature = claimed_signature(received).map(|s| Signature::from(s).to_string());
+                AuditRecord {
+                    error: Some(error.to_string()),
+                    ..AuditRecord::new(addr, signature, check, DropReason::Invalid.name())
+                }
+            });
+            return None;
+        }
+    };
+    let job = Job {
+        listener: listener.clone(),
+        source: addr,
+        info: datagram.info,
+        request,
+        request_size: received.len(),
+        state: state.clone(),
+    };
+    if let Err(reason) = limits.rate_limiter.check(&addr.ip()) {
+        limits.drop_job(&job, reason);
+        return None;
+    }
+    Some(job)
+}
+
+/// Whether the datagram was sent to a broadcast or multicast address, `interfaces` are the
+/// addresses of the local network interfaces.
+fn is_broadcast(info: &PacketInfo, interfaces: &[InterfaceAddr]) -> bool {
+    match info.destination {
+        Some(IpAddr::V4(ip)) => {
+            ip.is_broadcast()
+                || ip.is_multicast()
+                || interfaces.iter().any(|i| i.broadcast == Some(ip))
+        }
+        Some(IpAddr::V6(ip)) => ip.is_multicast(),
+        None => false,
+    }
+}
+
+/// Build the answer, executing the inventories without cached output, and send it. Answers
+/// larger than `max_amplification` times the request are not sent: legacy requests, which
+/// cannot be padded, get answers only if the check is disabled.
+#[instrument(skip(job, limits), fields(source = %job.source))]
+fn answer_request(job: &Job, limits: &Limits<'_>) -> Result<(), Report> {
+    let (addr, request, conf) = (job.source, &job.request, &job.state.conf);
+    let answer = job
+        .state
+        .cache
+        .answer_with(received_on(&job.listener, &addr, &job.info))?;
+    let msg = match encode_answer(request, &answer, conf)? {
+        Some(m) => m,
+        None => {
+            debug!(%addr, ?request, "Scanner cannot decrypt answers, not answering.");
+            limits.audit.record(|| job.record("cannot_decrypt"));
+            return Ok(());
+        }
+    };
+    let max_size = conf.max_amplification * job.request_size as f64;
+    if conf.max_amplification > 0.0 && msg.len() as f64 > max_size {
+        if let Some(suppressed) = limits.amplification_log.due(DENIED_LOG_PERIOD) {
+            warn!(%addr, answer_size = msg.len(), %job.request_size, %suppressed, "Answer too large for the request, not answering.");
+        }
+        limits.drop_job(job, DropReason::Amplification);
+        return Ok(());
+    }
+    let max_datagram_size = match request.capabilities().contains(Flags::CAN_REASSEMBLE) {
+        true => conf.max_datagram_size,
+        false => usize::MAX,
+    };
+    let answer_id = protocol::new_request_id()?; // random, as request ids
+    let datagrams = match fragment(&msg, request.request_id(), answer_id, max_datagram_size) {
+        Ok(datagrams) => datagrams,
+        Err(error) => {
+            if let Some(suppressed) = limits.fragments_log.due(DENIED_LOG_PERIOD) {
+                warn!(%addr, %error, %suppressed, "Answer too large to be reassembled, not answering.");
+            }
+            limits.audit.record(|| job.record("too_many_fragments"));
+            return Ok(());
+        }
+    };
+    respond(&job.listener.socket, &addr, &datagrams)?;
     info!(%answer, %addr, "Answered.");
-    Ok(rate_limiter)
+    limits.audit.record(|| AuditRecord {
+        answer_size: Some(msg.len()),
+        ..job.record("answered")
+    });
+    Ok(())
+}
+
+/// Parse the request and check its signature, or its authentication if a shared key is
+/// configured. Legacy requests are accepted only without a shared key.
+fn validate_request(
+    datagram: &[u8],
+    conf: &ServerConfig,
+    replay_guard: &mut ReplayGuard,
+) -> Result<Request, RequestError> {
+    let (header, body) = split_request(datagram)?;
+    let (nonce, signature) = match (&conf.shared_key, header) {
+        (None, _) => {
+            let signature = Signature::from(body);
+            if !is_signature_vaid(&signature, &conf.signatures) {
+                return Err(RequestError::BadSignature);
+            }
+            (None, Some(signature))
+        }
+        (Some(key), Some(header)) if header.flags.contains(Flags::AUTHENTICATED) => {
+            let request = AuthenticatedRequest::from_bytes(&header, body, key)?;
+            replay_guard.check(&request, SystemTime::now())?;
+            (Some(request.nonce), None)
+        }
+        (Some(_), _) => return Err(RequestError::NotAuthenticated),
+    };
+    Ok(Request {
+        header,
+        nonce,
+        signature,
+    })
+}
+
+/// Split a request in header (none for legacy requests) and unpadded body.
+fn split_request(datagram: &[u8]) -> Result<(Option<Header>, &[u8]), RequestError> {
+    let (header, body) = match Header::parse(datagram) {
+        Ok((header, body)) => (header, body),
+        Err(ProtocolError::Legacy) => {
+            return Ok((None, &datagram[..datagram.len().min(SIGNATURE_MAX_LENGHT)]))
+        }
+        Err(error) => return Err(error.into()),
+    };
+    if header.message_type != MessageType::Request {
+        return Err(RequestError::NotRequest(header.message_type));
+    }
+    match header.flags.contains(Flags::PADDED) {
+        true => Ok((Some(header), protocol::unpad(body)?)),
+        false => Ok((Some(header), body)),
+    }
+}
+
+/// Signature of a request not validated yet, none for authenticated or malformed requests.
+fn claimed_signature(datagram: &[u8]) -> Option<&[u8]> {
+    match split_request(datagram) {
+        Ok((Some(header), _)) if header.flags.contains(Flags::AUTHENTICATED) => None,
+        Ok((_, body)) => Some(body),
+        Err(_) => None,
+    }
+}
+
+/// Build the answer message with the features configured and supported by the scanner. Return
+/// None if encryption is configured but the scanner does not support it.
+fn encode_answer(
+    request: &Request,
+    answer: &Answer,
+    conf: &ServerConfig,
+) -> Result<Option<Bytes>, Report> {
+    let encrypt = !conf.encryption_keys.is_empty();
+    let header = match request.header {
+        Some(h) => h,
+        None if encrypt => return Ok(None),
+        None => return Ok(Some(answer.0.clone())), // legacy answer, bare JSON
+    };
+    let mut flags = Flags::empty();
+    let payload = match encrypt {
+        true if !header.flags.contains(Flags::CAN_DECRYPT) => return Ok(None),
+        true => {
+            flags = flags | Flags::ENCRYPTED;
+            encrypt_answer(&answer.0, &conf.encryption_keys)?
+        }
+        false => answer.0.clone(),
+    };
+    let signing = conf.shared_key.as_ref().zip(request.nonce);
+    if signing.is_some() {
+        flags = flags | Flags::AUTHENTICATED;
+    }
+    let answer_header = Header::answer_to(&header, flags);
+    let body = match signing {
+        Some((key, nonce)) => sign_answer(key, &answer_header, &nonce, &payload),
+        None => payload,
+    };
+    Ok(Some(answer_header.encode(&body)))
 }
 
 fn is_signature_vaid(received: &Signature, expected: &[Signature]) -> bool {
@@ -127,49 +1142,77 @@ fn is_signature_vaid(received: &Signature, expected: &[Signature]) -> bool {
     false
 }
 
-fn receive(socket: &UdpSocket) -> Result<(SocketAddr, Signature), Report> {
+/// A received datagram, with its source and packet informations.
+#[derive(Debug)]
+struct Datagram {
+    source: SocketAddr,
+    payload: Bytes,
+    info: PacketInfo,
+}
+
+fn receive(socket: &UdpSocket) -> io::Result<Datagram> {
     // Receives a single datagram message on the socket. If `buf` is too small to hold
     // the message, it will be cut off.
     let mut buf = [0; RECV_BUFFER_LENGHT];
     trace!(?socket, "Listening.");
-    let (lenght, source) = socket.recv_from(&mut buf)?;
-    let received: Signature = (&buf[..lenght]).into();
-    trace!(%lenght, %source, "Datagram received.");
-    Ok((source, received))
+    let (lenght, source, info) = recv_with_info(socket, &mut buf)?;
+    trace!(%lenght, %source, ?info, "Datagram received.");
+    Ok(Datagram {
+        source,
+        payload: Bytes::copy_from_slice(&buf[..lenght]),
+        info,
+    })
 }
 
-fn respond(socket: &UdpSocket, addr: &SocketAddr, msg: &Answer) -> Result<(), Report> {
-    socket.send_to(&msg.0, addr)?;
+/// Send the datagrams of a message, see `fragment`.
+fn respond(socket: &UdpSocket, addr: &SocketAddr, datagrams: &[Bytes]) -> Result<(), Report> {
+    for datagram in datagrams {
+        socket.send_to(datagram, addr)?;
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::audit::AuditLog;
+    use crate::auth::{verify_answer, SharedKey};
+    use crate::crypto::{decrypt_answer, PrivateKey};
+    use crate::fragment::Reassembler;
     use std::net::Ipv4Addr;
     use std::thread;
-    use std::time::Duration;
+
+    fn listener(socket: UdpSocket) -> Arc<Listener> {
+        Arc::new(Listener {
+            socket,
+            interface: None,
+        })
+    }
+
+    /// A request padded to the maximum size, as sent by ipdisscan.
+    fn padded_request(flags: Flags, request_id: u32, body: &[u8]) -> Bytes {
+        let header = Header::new(MessageType::Request, flags | Flags::PADDED, request_id);
+        header.encode(&protocol::pad(body, protocol::REQUEST_MAX_SIZE))
+    }
 
     #[test]
     #[tracing_test::traced_test]
     fn test_serve_localhost() {
-        let conf = ServerConfig::default();
+        let conf = ServerConfig {
+            max_amplification: 0.0, // legacy requests cannot be padded
+            ..ServerConfig::default()
+        };
         let sending_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::UNSPECIFIED, 0)).unwrap();
         let receiving_socket = sending_socket
             .try_clone()
             .expect("couldn't clone the socket");
-        let beacon_socket = UdpSocket::bind(format!("{}:{}", conf.listening_addr, 0)).unwrap();
+        let beacon_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::UNSPECIFIED, 0)).unwrap();
         let server_port = beacon_socket.local_addr().unwrap().port();
         let conf_clone = conf.clone();
         let server_handle = thread::spawn(move || {
-            let clock = Clock::default();
-            serve_single(
-                &beacon_socket,
-                &conf_clone.signatures,
-                &conf_clone.inventory_files,
-                RateLimiter::new(&clock),
-            )
-            .unwrap();
+            let beacon = listener(beacon_socket);
+            let limits = Limits::new(&Clock, &conf_clone);
+            serve_single(&beacon, &State::new(conf_clone), &limits).unwrap();
         });
         let scanner_handle = thread::spawn(move || {
             thread::sleep(Duration::from_secs_f64(0.1));
@@ -180,24 +1223,383 @@ mod test {
             println!("[{}] <- {:?}", beacon_addr, &conf.signatures);
         });
         let response = receive(&receiving_socket).unwrap();
-        println!("[{}] -> {}", response.0, response.1);
+        println!(
+            "[{}] -> {}",
+            response.source,
+            safe_format_bytes(&response.payload)
+        );
+        assert!(!protocol::is_versioned(&response.payload)); // legacy request, legacy answer
         server_handle.join().unwrap();
         scanner_handle.join().unwrap();
     }
 
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_serve_authenticated() {
+        let key = SharedKey::from("secret".as_bytes());
+        let conf = ServerConfig {
+            shared_key: Some(key.clone()),
+            ..ServerConfig::default()
+        };
+        let scanner_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_addr = beacon_socket.local_addr().unwrap();
+        let beacon = listener(beacon_socket);
+        let request = AuthenticatedRequest::new().unwrap();
+        let header = Header::new(
+            MessageType::Request,
+            Flags::AUTHENTICATED | Flags::PADDED,
+            42,
+        );
+        // bare signatures are ignored
+        scanner_socket
+            .send_to(conf.signatures.first().unwrap().0.as_ref(), beacon_addr)
+            .unwrap();
+        scanner_socket
+            .send_to(
+                &header.encode(&protocol::pad(
+                    &request.to_bytes(&header, &key),
+                    protocol::REQUEST_MAX_SIZE,
+                )),
+                beacon_addr,
+            )
+            .unwrap();
+        let limits = Limits::new(&Clock, &conf);
+        let state = State::new(conf);
+        for _ in 0..2 {
+            serve_single(&beacon, &state, &limits).unwrap();
+        }
+        let mut buf = [0; 1024];
+        let (lenght, _) = scanner_socket.recv_from(&mut buf).unwrap();
+        let (answer_header, body) = Header::parse(&buf[..lenght]).unwrap();
+        assert_eq!(answer_header.request_id, 42);
+        assert!(answer_header.flags.contains(Flags::AUTHENTICATED));
+        let (nonce, _payload) = verify_answer(&key, &answer_header, body).unwrap();
+        assert_eq!(nonce, request.nonce);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_serve_encrypted() {
+        let scanner_key = PrivateKey::generate().unwrap();
+        let conf = ServerConfig {
+            encryption_keys: vec![scanner_key.public_key()],
+            rate_limit_burst: 2,
+            ..ServerConfig::default()
+        };
+        let scanner_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_addr = beacon_socket.local_addr().unwrap();
+        let beacon = listener(beacon_socket);
+        let signature = conf.signatures.first().unwrap().0.as_ref();
+        // legacy scanners cannot decrypt, they get no answer
+        scanner_socket.send_to(signature, beacon_addr).unwrap();
+        scanner_socket
+            .send_to(
+                &padded_request(Flags::CAN_DECRYPT, 7, signature),
+                beacon_addr,
+            )
+            .unwrap();
+        let limits = Limits::new(&Clock, &conf);
+        let state = State::new(conf);
+        for _ in 0..2 {
+            serve_single(&beacon, &state, &limits).unwrap();
+        }
+        let mut buf = [0; 1024];
+        let (lenght, _) = scanner_socket.recv_from(&mut buf).unwrap();
+        let (answer_header, body) = Header::parse(&buf[..lenght]).unwrap();
+        assert_eq!(answer_header.request_id, 7);
+        assert!(answer_header.flags.contains(Flags::ENCRYPTED));
+        let payload = decrypt_answer(body, &scanner_key).unwrap();
+        assert!(serde_json::from_slice::<serde_json::Value>(&payload).is_ok());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_dispatch() {
+        let conf = ServerConfig {
+            rate_limit_burst: 2,
+            ..ServerConfig::default()
+        };
+        let signature = conf.signatures.first().unwrap().0.clone();
+        let scanners: Vec<_> = (0..2)
+            .map(|_| UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap())
+            .collect();
+        let beacon_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_addr = beacon_socket.local_addr().unwrap();
+        let beacon = listener(beacon_socket);
+        let limits = Limits::new(&Clock, &conf);
+        let state = State::new(conf);
+        let (queue, jobs) = crossbeam_channel::bounded(1);
+        for scanner in &scanners {
+            let request = padded_request(Flags::empty(), 1, &signature);
+            scanner.send_to(&request, beacon_addr).unwrap();
+            let datagram = receive(&beacon.socket).unwrap();
+            dispatch(&beacon, datagram, &state, &limits, &queue).unwrap();
+        }
+        // the queue was full, the second request took no token
+        assert_eq!(limits.drops.load().queue_full, 1);
+        assert!(limits.rate_limiter.check(&beacon_addr.ip()).is_ok());
+        drop(queue);
+        work(jobs, &limits);
+        let mut buf = [0; 2048];
+        let (_, source) = scanners[0].recv_from(&mut buf).unwrap();
+        assert_eq!(source, beacon_addr);
+        scanners[1].set_nonblocking(true).unwrap();
+        assert!(scanners[1].recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_request_filters() {
+        let scanner_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_addr = beacon_socket.local_addr().unwrap();
+        let beacon = listener(beacon_socket);
+        enable_packet_info(&beacon.socket).unwrap();
+        let signature = ServerConfig::default().signatures[0].0.clone();
+        let unpadded = Header::new(MessageType::Request, Flags::empty(), 1).encode(&signature);
+        let padded = padded_request(Flags::empty(), 2, &signature);
+        let serve = |conf: ServerConfig, request: &[u8]| {
+            let limits = Limits::new(&Clock, &conf);
+            scanner_socket.send_to(request, beacon_addr).unwrap();
+            serve_single(&beacon, &State::new(conf), &limits).unwrap();
+            limits.drops.load()
+        };
+        let remote_only = ServerConfig {
+            allowed_sources: vec!["203.0.113.0/24".parse().unwrap()],
+            ..ServerConfig::default()
+        };
+        assert_eq!(serve(remote_only, &padded).source_not_allowed, 1);
+        if cfg!(target_os = "linux") {
+            let only_broadcast = ServerConfig {
+                only_broadcast: true,
+                ..ServerConfig::default()
+            };
+            assert_eq!(serve(only_broadcast, &padded).not_broadcast, 1);
+        }
+        let strict = ServerConfig {
+            max_amplification: 1.0,
+            ..ServerConfig::default()
+        };
+        assert_eq!(serve(strict, &unpadded).amplification, 1);
+        // legacy, cannot be padded
+        assert_eq!(serve(ServerConfig::default(), &signature).amplification, 1);
+        assert_eq!(
+            serve(ServerConfig::default(), &padded),
+            DropCounts::default()
+        );
+        let mut buf = [0; 2048];
+        let (lenght, _) = scanner_socket.recv_from(&mut buf).unwrap();
+        let (header, _) = Header::parse(&buf[..lenght]).unwrap();
+        assert_eq!(header.request_id, 2); // the only answer
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_source_rules() {
+        let scanner_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_addr = beacon_socket.local_addr().unwrap();
+        let beacon = listener(beacon_socket);
+        let signature = ServerConfig::default().signatures[0].0.clone();
+        let request = padded_request(Flags::empty(), 1, &signature);
+        let serve = |rules: &[&str]| {
+            let conf = ServerConfig {
+                source_rules: rules.iter().map(|rule| rule.parse().unwrap()).collect(),
+                allowed_sources: vec!["203.0.113.0/24".parse().unwrap()],
+                ..ServerConfig::default()
+            };
+            let limits = Limits::new(&Clock, &conf);
+            scanner_socket.send_to(&request, beacon_addr).unwrap();
+            serve_single(&beacon, &State::new(conf), &limits).unwrap();
+            limits.drops.load()
+        };
+        let denied = serve(&["deny 127.0.0.0/8", "allow any"]);
+        assert_eq!(denied.source_denied, 1);
+        assert!(logs_contain("Request denied by source rule."));
+        let other_signature = serve(&["allow 127.0.0.0/8@lab-beacon"]);
+        assert_eq!(other_signature.source_not_allowed, 1);
+        let allowed = serve(&["deny ::1@ipdisbeacon", "allow 127.0.0.1@ipdisbeacon"]);
+        assert_eq!(allowed, DropCounts::default());
+        let mut buf = [0; 2048];
+        scanner_socket.recv_from(&mut buf).unwrap();
+
+        let log = LogLimiter::default();
+        assert_eq!(log.due(Duration::from_secs(60)), Some(0));
+        assert_eq!(log.due(Duration::from_secs(60)), None);
+        assert_eq!(log.due(Duration::from_secs(60)), None);
+        assert_eq!(log.due(Duration::ZERO), Some(2));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_audit() {
+        let scanner_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_addr = beacon_socket.local_addr().unwrap();
+        let beacon = listener(beacon_socket);
+        let path = std::env::temp_dir().join("rust-ipdisserver-test-server-audit.log");
+        let _ = std::fs::remove_file(&path);
+        let conf = ServerConfig {
+            audit_log: Some(AuditLog::file(path.clone())),
+            alert_threshold: 1,
+            ..ServerConfig::default()
+        };
+        let limits = Limits::new(&Clock, &conf);
+        limits.audit.configure(conf.audit_log.as_ref()).unwrap();
+        let state = State::new(conf);
+        for request in [
+            padded_request(Flags::empty(), 1, b"ipdisbeacon"),
+            padded_request(Flags::empty(), 2, b"probe"),
+            padded_request(Flags::empty(), 3, b"ipdisbeacon"),
+        ] {
+            scanner_socket.send_to(&request, beacon_addr).unwrap();
+            serve_single(&beacon, &state, &limits).unwrap();
+        }
+        limits.audit.flush();
+        assert!(logs_contain("Source sending invalid signatures."));
+        let records: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        let scanner_addr = scanner_socket.local_addr().unwrap().to_string();
+        assert_eq!(records[0]["source"], scanner_addr.as_str());
+        assert_eq!(records[0]["outcome"], "answered");
+        assert_eq!(records[0]["signature"], "ipdisbeacon");
+        assert_eq!(records[0]["signature_check"], "valid");
+        assert!(records[0]["answer_size"].as_u64().unwrap() > 0);
+        assert_eq!(records[1]["outcome"], "invalid");
+        assert_eq!(records[1]["signature"], "probe");
+        assert_eq!(records[1]["signature_check"], "invalid");
+        assert_eq!(records[1]["error"], "bad signature");
+        assert_eq!(records[2]["outcome"], "source_rate_limited");
+        assert_eq!(records[2]["signature_check"], "valid");
+        assert!(records[2].get("answer_size").is_none());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_received_on() {
+        let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 1902));
+        let bound = Listener {
+            socket: UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap(),
+            interface: Some("test0".into()),
+        };
+        let no_info = PacketInfo::default();
+        assert_eq!(
+            Value::Object(received_on(&bound, &peer, &no_info)),
+            serde_json::json!({RECEIVED_ON_KEY: {"interface": "test0", "addr": "127.0.0.1"}})
+        );
+        // loopback is not among the interface addresses
+        let unbound =
+            listener(UdpSocket::bind(format!("{}:{}", Ipv4Addr::UNSPECIFIED, 0)).unwrap());
+        assert!(received_on(&unbound, &peer, &no_info).is_empty());
+        let info = PacketInfo {
+            local_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 1, 0, 2))),
+            ..PacketInfo::default()
+        };
+        assert_eq!(
+            Value::Object(received_on(&unbound, &peer, &info)),
+            serde_json::json!({RECEIVED_ON_KEY: {"addr": "10.1.0.2"}})
+        );
+        let multicast = PacketInfo {
+            local_ip: Some(IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x1901))),
+            ..PacketInfo::default()
+        };
+        assert!(received_on(&unbound, &peer, &multicast).is_empty());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_respond_fragmented() {
+        let scanner_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let beacon_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let msg = vec![b'x'; 3000];
+        let datagrams = fragment(&msg, 1, 1, 1200).unwrap();
+        respond(
+            &beacon_socket,
+            &scanner_socket.local_addr().unwrap(),
+            &datagrams,
+        )
+        .unwrap();
+        let mut reassembler = Reassembler::default();
+        let mut buf = [0; 2048];
+        let reassembled = loop {
+            let (lenght, source) = scanner_socket.recv_from(&mut buf).unwrap();
+            assert!(lenght <= 1200);
+            if let Some(m) = reassembler.push(source, &buf[..lenght], Instant::now()) {
+                break m;
+            }
+        };
+        assert_eq!(reassembled, msg);
+    }
+
     #[test]
     #[tracing_test::traced_test]
     fn test_rate_limiter() {
         let time = SystemTime::now();
         let clock = DummyClock { time };
-        let mut rate_limiter = RateLimiter::new(&clock);
-        let ip = SocketAddr::from(([10, 11, 12, 13], 1234));
-        assert!(rate_limiter.check(&ip));
-        assert!(!rate_limiter.check(&ip));
-        let time = SystemTime::now() + RATE_LIMIT_TIMEOUT + Duration::from_millis(1);
+        let conf = RateLimitConfig::from(&ServerConfig::default());
+        let mut rate_limiter = RateLimiter::new(&clock, conf);
+        let ip = IpAddr::from([10, 11, 12, 13]);
+        assert!(rate_limiter.check(&ip).is_ok());
+        assert_eq!(rate_limiter.check(&ip), Err(DropReason::SourceRateLimited));
+        let time = SystemTime::now() + RATE_LIMIT_PERIOD_DEFAULT + Duration::from_millis(1);
         let clock = DummyClock { time };
         rate_limiter.clock = &clock;
-        assert!(rate_limiter.check(&ip));
-        assert!(!rate_limiter.check(&ip));
+        assert!(rate_limiter.check(&ip).is_ok());
+        assert!(rate_limiter.check(&ip).is_err());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_rate_limiter_buckets() {
+        let clock = DummyClock {
+            time: SystemTime::now(),
+        };
+        let conf = RateLimitConfig {
+            period: Duration::from_secs(10),
+            burst: 2,
+            global_rate: 3.0,
+            max_sources: 2,
+        };
+        let rate_limiter = RateLimiter::new(&clock, conf);
+        let ips: Vec<IpAddr> = (1..=3).map(|i| IpAddr::from([10, 0, 0, i])).collect();
+        assert!(rate_limiter.check(&ips[0]).is_ok());
+        assert!(rate_limiter.check(&ips[0]).is_ok());
+        assert!(rate_limiter.check(&ips[0]).is_err());
+        assert!(rate_limiter.check(&ips[1]).is_ok());
+        // the global bucket is empty, the source bucket is not used
+        assert_eq!(
+            rate_limiter.check(&ips[2]),
+            Err(DropReason::GlobalRateLimited)
+        );
+        assert_eq!(rate_limiter.buckets().sources.len(), 2);
+        assert!(!rate_limiter.buckets().sources.contains(&ips[0])); // least recently seen
+        rate_limiter.buckets().global.tokens = 3.0;
+        assert!(rate_limiter.check(&ips[0]).is_ok()); // forgotten, full bucket again
+        assert!(rate_limiter.check(&ips[2]).is_ok());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_rate_limiter_concurrent() {
+        let conf = RateLimitConfig::from(&ServerConfig::default());
+        let rate_limiter = RateLimiter::new(&Clock, conf);
+        let ip = IpAddr::from([10, 11, 12, 13]);
+        let allowed = thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| rate_limiter.check(&ip)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .filter(Result::is_ok)
+                .count()
+        });
+        assert_eq!(allowed, 1);
     }
 }
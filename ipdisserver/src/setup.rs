@@ -4,12 +4,14 @@ use tracing_error::ErrorLayer;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
+/// Install tracing and error reporting. `log_level` replaces the default logging directives, the
+/// RUST_LOG environment variable takes precedence over both.
 #[instrument]
-pub fn setup(log_to_journald: bool) -> Result<(), Report> {
+pub fn setup(log_to_journald: bool, log_level: Option<&str>) -> Result<(), Report> {
     match log_to_journald {
-        false => install_stderr_tracing(),
-        true => install_journald_tracing().unwrap_or_else(|_| {
-            install_stderr_tracing();
+        false => install_stderr_tracing(log_level.unwrap_or("warn")),
+        true => install_journald_tracing(log_level.unwrap_or("info")).unwrap_or_else(|_| {
+            install_stderr_tracing(log_level.unwrap_or("warn"));
             error!("Failed to connect to journald, logging to stderr.")
         }),
     };
@@ -17,8 +19,8 @@ pub fn setup(log_to_journald: bool) -> Result<(), Report> {
     Ok(())
 }
 
-fn install_stderr_tracing() {
-    let filter_layer = get_envfilter("warn");
+fn install_stderr_tracing(default_level: &str) {
+    let filter_layer = get_envfilter(default_level);
     let fmt_layer = tracing_subscriber::fmt::layer()
         .with_target(false)
         .with_writer(std::io::stderr);
@@ -29,8 +31,8 @@ fn install_stderr_tracing() {
         .init();
 }
 
-fn install_journald_tracing() -> Result<(), Report> {
-    let filter_layer = get_envfilter("info");
+fn install_journald_tracing(default_level: &str) -> Result<(), Report> {
+    let filter_layer = get_envfilter(default_level);
     let fmt_layer = tracing_journald::layer()?;
     tracing_subscriber::registry()
         .with(filter_layer)
@@ -0,0 +1,299 @@
+//! Source addresses the server answers to, so that it cannot be used as a reflection amplifier
+//! towards arbitrary hosts.
+use crate::net::{in_network, InterfaceAddr};
+use crate::signature::Signature;
+use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+pub const ALLOWED_SOURCES_DEFAULT: [SourceRange; 2] = [SourceRange::Local, SourceRange::Private];
+
+/// A range of source addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum SourceRange {
+    Any,
+    /// Addresses in the subnets of the network interfaces, and loopback addresses.
+    Local,
+    /// Private (RFC 1918 and unique local), loopback and link-local addresses.
+    Private,
+    /// An IPv4 or IPv6 network, e.g. `203.0.113.0/24`, or a single address.
+    Network(IpAddr, u8),
+}
+
+impl fmt::Display for SourceRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "any"),
+            Self::Local => write!(f, "local"),
+            Self::Private => write!(f, "private"),
+            Self::Network(ip, prefix_len) => write!(f, "{}/{}", ip, prefix_len),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("invalid source range `{0}`, expected one of: any, local, private, an address or a network (e.g. 203.0.113.0/24)")]
+pub struct SourceRangeError(String);
+
+impl FromStr for SourceRange {
+    type Err = SourceRangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || SourceRangeError(s.to_string());
+        match s {
+            "any" => Ok(Self::Any),
+            "local" => Ok(Self::Local),
+            "private" => Ok(Self::Private),
+            network => {
+                let (ip, prefix_len) = match network.split_once('/') {
+                    Some((ip, prefix_len)) => (ip, Some(prefix_len)),
+                    None => (network, None),
+                };
+                let ip: IpAddr = ip.parse().map_err(|_| error())?;
+                let max_prefix_len = match ip {
+                    IpAddr::V4(_) => 32,
+                    IpAddr::V6(_) => 128,
+                };
+                let prefix_len = match prefix_len {
+                    Some(p) => p.parse().map_err(|_| error())?,
+                    None => max_prefix_len,
+                };
+                if prefix_len > max_prefix_len {
+                    return Err(error());
+                }
+                Ok(Self::Network(ip, prefix_len))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for SourceRange {
+    type Error = SourceRangeError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl SourceRange {
+    /// Whether the range contains the IP, `interfaces` are the addresses of the local network
+    /// interfaces.
+    pub fn contains(&self, ip: &IpAddr, interfaces: &[InterfaceAddr]) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Local => ip.is_loopback() || interfaces.iter().any(|i| i.contains(ip)),
+            Self::Private => is_private(ip),
+            Self::Network(network, prefix_len) => in_network(ip, network, *prefix_len),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    Allow,
+    Deny,
+}
+
+/// Allows or denies the requests from a source range, e.g. `deny 10.0.5.0/24`, optionally only
+/// those with a signature, e.g. `allow 203.0.113.0/24@lab-beacon`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SourceRule {
+    pub action: RuleAction,
+    pub range: SourceRange,
+    /// If set, the rule applies only to requests with this signature. Authenticated requests
+    /// carry no signature, so they never match.
+    pub signature: Option<Signature>,
+}
+
+impl fmt::Display for SourceRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            RuleAction::Allow => "allow",
+            RuleAction::Deny => "deny",
+        };
+        write!(f, "{} {}", action, self.range)?;
+        match &self.signature {
+            Some(signature) => write!(f, "@{}", signature),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SourceRuleError {
+    #[error("invalid source rule `{0}`, expected `allow RANGE` or `deny RANGE`, optionally followed by `@SIGNATURE`")]
+    Syntax(String),
+    #[error(transparent)]
+    Range(#[from] SourceRangeError),
+}
+
+impl FromStr for SourceRule {
+    type Err = SourceRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let syntax_error = || SourceRuleError::Syntax(s.to_string());
+        let (action, rest) = s.trim().split_once(' ').ok_or_else(syntax_error)?;
+        let action = match action {
+            "allow" => RuleAction::Allow,
+            "deny" => RuleAction::Deny,
+            _ => return Err(syntax_error()),
+        };
+        let (range, signature) = match rest.trim_start().split_once('@') {
+            Some((_, "")) => return Err(syntax_error()),
+            Some((range, signature)) => (range, Some(Signature::from(signature))),
+            None => (rest.trim_start(), None),
+        };
+        Ok(Self {
+            action,
+            range: range.parse()?,
+            signature,
+        })
+    }
+}
+
+impl TryFrom<String> for SourceRule {
+    type Error = SourceRuleError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl SourceRule {
+    /// Whether the rule applies to a request from the IP, with the signature if not
+    /// authenticated.
+    pub fn matches(
+        &self,
+        ip: &IpAddr,
+        signature: Option<&[u8]>,
+        interfaces: &[InterfaceAddr],
+    ) -> bool {
+        let signature_matches = match &self.signature {
+            Some(expected) => signature == Some(expected.0.as_ref()),
+            None => true,
+        };
+        signature_matches && self.range.contains(ip, interfaces)
+    }
+}
+
+/// First rule applying to a request, see `SourceRule::matches`.
+pub fn first_match<'a>(
+    rules: &'a [SourceRule],
+    ip: &IpAddr,
+    signature: Option<&[u8]>,
+    interfaces: &[InterfaceAddr],
+) -> Option<&'a SourceRule> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(ip, signature, interfaces))
+}
+
+/// Private (RFC 1918 and unique local), loopback and link-local addresses.
+pub fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_source_range() {
+        assert_eq!("any".parse(), Ok(SourceRange::Any));
+        assert_eq!(
+            "203.0.113.0/24".parse(),
+            Ok(SourceRange::Network(
+                IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)),
+                24
+            ))
+        );
+        assert_eq!(
+            "2001:db8::1".parse(),
+            Ok(SourceRange::Network("2001:db8::1".parse().unwrap(), 128))
+        );
+        assert!(SourceRange::from_str("10.0.0.0/33").is_err());
+        assert!(SourceRange::from_str("lan").is_err());
+        let public = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+        let interfaces = [InterfaceAddr {
+            name: "eth0".into(),
+            index: Some(2),
+            ip: IpAddr::V4(Ipv4Addr::new(203, 0, 113, 2)),
+            prefix_len: 24,
+            broadcast: Some(Ipv4Addr::new(203, 0, 113, 255)),
+        }];
+        assert!(SourceRange::Local.contains(&public, &interfaces));
+        assert!(!SourceRange::Local.contains(&public, &[]));
+        assert!(!SourceRange::Private.contains(&public, &interfaces));
+        assert!("203.0.113.0/25"
+            .parse::<SourceRange>()
+            .unwrap()
+            .contains(&public, &[]));
+        assert!(is_private(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2))));
+        assert!(is_private(&IpAddr::V6(Ipv6Addr::new(
+            0xfd00, 0, 0, 0, 0, 0, 0, 2
+        ))));
+        assert!(is_private(&IpAddr::V6(Ipv6Addr::new(
+            0xfe80, 0, 0, 0, 0, 0, 0, 2
+        ))));
+        assert!(!is_private(&IpAddr::V6(Ipv6Addr::new(
+            0x2001, 0xdb8, 0, 0, 0, 0, 0, 2
+        ))));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_source_rules() {
+        let rules: Vec<SourceRule> = [
+            "deny 10.0.5.0/24",
+            "allow 203.0.113.0/24@lab-beacon",
+            "deny 2001:db8::/32",
+            "allow any",
+        ]
+        .iter()
+        .map(|rule| rule.parse().unwrap())
+        .collect();
+        assert_eq!(rules[1].to_string(), "allow 203.0.113.0/24@lab-beacon");
+        let action = |ip: &str, signature: Option<&[u8]>| {
+            first_match(&rules, &ip.parse().unwrap(), signature, &[]).map(|rule| rule.action)
+        };
+        assert_eq!(
+            action("10.0.5.7", Some(b"ipdisbeacon")),
+            Some(RuleAction::Deny)
+        );
+        assert_eq!(action("10.0.6.7", None), Some(RuleAction::Allow));
+        assert_eq!(
+            first_match(
+                &rules,
+                &"203.0.113.9".parse().unwrap(),
+                Some(b"lab-beacon"),
+                &[]
+            ),
+            Some(&rules[1])
+        );
+        assert_eq!(
+            first_match(&rules, &"203.0.113.9".parse().unwrap(), None, &[]),
+            Some(&rules[3])
+        );
+        assert_eq!(action("2001:db8::5", None), Some(RuleAction::Deny));
+        assert!(first_match(&rules[..1], &"10.0.6.7".parse().unwrap(), None, &[]).is_none());
+        assert!(SourceRule::from_str("drop 10.0.0.0/8").is_err());
+        assert!(SourceRule::from_str("deny").is_err());
+        assert!(SourceRule::from_str("deny 10.0.0.0/8@").is_err());
+        assert!(matches!(
+            SourceRule::from_str("deny 10.0.0.0/40"),
+            Err(SourceRuleError::Range(_))
+        ));
+    }
+}